@@ -0,0 +1,239 @@
+use crate::dir::WasmcovDir;
+use crate::error::Result;
+use crate::report;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Environment variable read by the patched NEAR sandbox when it is spawned.
+// Every profraw file the sandbox writes is also recorded as produced by the
+// test with that name, see `sandbox_test_name_env`.
+pub const TEST_NAME_ENV: &str = "WASMCOV_TEST_NAME";
+
+// Subdirectory of `profraw/<crate>` with one directory per test, containing
+// an empty marker file for each profraw hash the test produced.
+pub const TESTS_DIR: &str = "tests";
+
+// Defines the function and a constant with its source, so the patched hosts
+// paste the same code instead of a copy.
+macro_rules! with_source {
+    ($source:ident, fn $name:ident $($item:tt)*) => {
+        pub fn $name $($item)*
+
+        pub const $source: &str = stringify!(fn $name $($item)*);
+    };
+}
+
+// Characters other than alphanumerics and `_-.:` are replaced, the name is
+// used as a directory name.
+with_source! {
+    SANITIZE_TEST_NAME_SOURCE,
+    fn sanitize_test_name(test_name: &str) -> String {
+        test_name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "_-.:".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+}
+
+// Records that the profraw file with the given hash was produced by the
+// named test. Used by the in-process hosts, the patched hosts run the code
+// of `record_test_source` instead.
+pub fn record_test(crate_profraw_dir: &Path, hash: &str, test_name: Option<&str>) -> Result<()> {
+    let Some(test_name) = test_name else {
+        return Ok(());
+    };
+    let test_dir = crate_profraw_dir
+        .join(TESTS_DIR)
+        .join(sanitize_test_name(test_name));
+    fs::create_dir_all(&test_dir)?;
+    fs::write(test_dir.join(hash), [])?;
+    Ok(())
+}
+
+// Code pasted into the patched hosts after they saved `coverage_hash`.profraw
+// to `profraw_directory`, the equivalent of `record_test`. `test_name` is an
+// expression of type `Option<String>` evaluated in the host.
+pub fn record_test_source(test_name: &str) -> String {
+    format!(
+        "
+    if let Some(test_name) = {test_name} {{
+        {SANITIZE_TEST_NAME_SOURCE}
+        let test_directory = profraw_directory.join(\"{TESTS_DIR}\").join(sanitize_test_name(&test_name));
+        let _ = std::fs::create_dir_all(&test_directory);
+        std::fs::write(test_directory.join(&coverage_hash), []).expect(\"Failed to write coverage test marker\");
+    }}"
+    )
+}
+
+// Environment variable and value to set on the command spawning a NEAR
+// sandbox, e.g. `command.env(key, value)`. The sandbox reads the name once,
+// so every test needs its own sandbox.
+pub fn sandbox_test_name_env(test_name: &str) -> (&'static str, String) {
+    (TEST_NAME_ENV, test_name.to_string())
+}
+
+// Profraw files of a crate grouped by the test which produced them.
+pub fn profraw_files_by_test(crate_profraw_dir: &Path) -> Result<BTreeMap<String, Vec<PathBuf>>> {
+    let mut tests = BTreeMap::new();
+    let tests_dir = crate_profraw_dir.join(TESTS_DIR);
+    if !tests_dir.is_dir() {
+        return Ok(tests);
+    }
+
+    for test_entry in fs::read_dir(tests_dir)? {
+        let test_dir = test_entry?.path();
+        if !test_dir.is_dir() {
+            continue;
+        }
+        let test_name = test_dir.file_name().unwrap().to_string_lossy().into_owned();
+        let mut profraw_files = Vec::new();
+        for marker in fs::read_dir(&test_dir)? {
+            let hash = marker?.file_name();
+            let profraw_file =
+                crate_profraw_dir.join(format!("{}.profraw", hash.to_string_lossy()));
+            if profraw_file.exists() {
+                profraw_files.push(profraw_file);
+            }
+        }
+        if !profraw_files.is_empty() {
+            tests.insert(test_name, profraw_files);
+        }
+    }
+    Ok(tests)
+}
+
+// Removes the test records of the profraw files with the given hashes, and
+// the test directories left without records.
+pub fn remove_test_records(crate_profraw_dir: &Path, hashes: &BTreeSet<String>) -> Result<()> {
+    let tests_dir = crate_profraw_dir.join(TESTS_DIR);
+    if !tests_dir.is_dir() {
+        return Ok(());
+    }
+
+    for test_entry in fs::read_dir(tests_dir)? {
+        let test_dir = test_entry?.path();
+        if !test_dir.is_dir() {
+            continue;
+        }
+        for hash in hashes {
+            let marker = test_dir.join(hash);
+            if marker.exists() {
+                fs::remove_file(marker)?;
+            }
+        }
+        if fs::read_dir(&test_dir)?.next().is_none() {
+            fs::remove_dir(&test_dir)?;
+        }
+    }
+    Ok(())
+}
+
+// Names of the functions executed at least once according to the profdata.
+pub fn covered_functions(
+    profdata_path: &Path,
+    object_file: &Path,
+    llvm_cov_args: &[String],
+) -> Result<BTreeSet<String>> {
+    let report = report::load_with_args(profdata_path, object_file, llvm_cov_args)?;
+    Ok(report
+        .functions
+        .into_iter()
+        .filter(|function| function.count > 0)
+        .map(|function| function.demangled_name)
+        .collect())
+}
+
+// Test -> covered functions matrix of a single contract.
+#[derive(Debug, Clone, Default)]
+pub struct TestMatrix {
+    pub tests: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl TestMatrix {
+    pub fn functions(&self) -> BTreeSet<&String> {
+        self.tests.values().flatten().collect()
+    }
+
+    // Functions covered by this test and no other test.
+    pub fn unique_functions(&self, test: &str) -> BTreeSet<&String> {
+        let Some(functions) = self.tests.get(test) else {
+            return BTreeSet::new();
+        };
+        functions
+            .iter()
+            .filter(|function| {
+                self.tests
+                    .iter()
+                    .all(|(other, functions)| other == test || !functions.contains(*function))
+            })
+            .collect()
+    }
+
+    // One row per function and one column per test, 1 when the test covers the function.
+    pub fn to_csv(&self) -> String {
+        let escape = |value: &str| format!("\"{}\"", value.replace('"', "\"\""));
+        let mut csv = String::from("function");
+        for test in self.tests.keys() {
+            csv.push(',');
+            csv.push_str(&escape(test));
+        }
+        csv.push('\n');
+        for function in self.functions() {
+            csv.push_str(&escape(function));
+            for functions in self.tests.values() {
+                let _ = write!(csv, ",{}", functions.contains(function) as u8);
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::Value::Object(
+            self.tests
+                .iter()
+                .map(|(test, functions)| (test.clone(), serde_json::json!(functions)))
+                .collect(),
+        )
+    }
+}
+
+// Merges the profraw files of every test separately and saves the test ->
+// covered functions matrix of the contract to `report/by-test/<contract>.{json,csv}`.
+pub fn by_test_report(
+    wasmcov_dir: &WasmcovDir,
+    contract: &str,
+    object_file: &Path,
+    llvm_cov_args: &[String],
+) -> Result<TestMatrix> {
+    let test_profdata_dir = wasmcov_dir.profdata()?.join("by-test").join(contract);
+    fs::create_dir_all(&test_profdata_dir)?;
+
+    let mut matrix = TestMatrix::default();
+    for (test, profraw_files) in profraw_files_by_test(&wasmcov_dir.profraw()?.join(contract))? {
+        let profdata_path = test_profdata_dir.join(format!("{}.profdata", test));
+        report::merge_profraw_files(&profraw_files, &profdata_path, Vec::new())?;
+        let functions = covered_functions(&profdata_path, object_file, llvm_cov_args)?;
+        matrix.tests.insert(test, functions);
+    }
+
+    let report_dir = wasmcov_dir.report()?.join("by-test");
+    fs::create_dir_all(&report_dir)?;
+    fs::write(
+        report_dir.join(format!("{}.json", contract)),
+        serde_json::to_string_pretty(&matrix.to_json())?,
+    )?;
+    fs::write(
+        report_dir.join(format!("{}.csv", contract)),
+        matrix.to_csv(),
+    )?;
+    Ok(matrix)
+}
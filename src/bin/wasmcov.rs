@@ -1,7 +1,20 @@
 use anyhow::{anyhow, Result};
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
 use std::{env, fs, path::PathBuf, process::Command};
-use wasmcov::{build, dir, llvm, near_sandbox, report, utils};
+use wasmcov::config::Config;
+#[cfg(feature = "cosmwasm")]
+use wasmcov::cosmwasm_vm;
+use wasmcov::dir::{self, WasmcovDir, VARIANT_ENV};
+use wasmcov::filter::SourceFilter;
+use wasmcov::report::{MergeOptions, ReportFormat};
+use wasmcov::threshold::{Threshold, Thresholds};
+use wasmcov::Error;
+use wasmcov::{attribution, build, diff, gc, llvm, near_sandbox, report};
 
 #[derive(Parser)]
 #[command(name = "cargo", bin_name = "cargo")]
@@ -25,44 +38,199 @@ struct WasmcovArgs {
     wasmcov_dir: Option<PathBuf>,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum TestRunner {
+    /// wasm-bindgen-test-runner under Node.js
+    WasmBindgenTest,
+}
+
+#[derive(Args)]
+struct FilterArgs {
+    /// Only report source files matching the glob, can be repeated
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// Hide source files matching the glob from the report, can be repeated
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// Show cargo registry and standard library sources, which are hidden by default
+    #[arg(long)]
+    no_default_excludes: bool,
+}
+
+impl FilterArgs {
+    // Patterns given on the command line replace the configured ones.
+    fn to_filter(&self, config: &Config) -> Result<SourceFilter> {
+        let or_configured = |cli: &Vec<String>, configured: &Vec<String>| {
+            if cli.is_empty() {
+                configured.clone()
+            } else {
+                cli.clone()
+            }
+        };
+        Ok(SourceFilter::new(
+            &or_configured(&self.include, &config.include),
+            &or_configured(&self.exclude, &config.exclude),
+            !(self.no_default_excludes || config.no_default_excludes),
+        )?)
+    }
+}
+
+#[derive(Args)]
+struct NearArgs {
+    /// Specify NEAR sandbox version (e.g. 1.35.0)
+    #[arg(long, value_name = "VERSION")]
+    near: Option<String>,
+    /// Use an already patched neard binary as the NEAR sandbox
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["near", "nearcore_src"])]
+    near_binary: Option<PathBuf>,
+    /// Patch and build the NEAR sandbox from a local nearcore checkout
+    #[arg(long, value_name = "PATH", conflicts_with = "near")]
+    nearcore_src: Option<PathBuf>,
+}
+
+impl NearArgs {
+    // The configured sandbox is used only when none is given on the command line.
+    fn or_config(self, config: &Config) -> NearArgs {
+        if self.near.is_some() || self.near_binary.is_some() || self.nearcore_src.is_some() {
+            return self;
+        }
+        NearArgs {
+            near: config.near.clone(),
+            near_binary: config.near_binary.clone(),
+            nearcore_src: config.nearcore_src.clone(),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum WasmcovCommands {
     /// Build the project with WASM coverage instrumentation
     Build {
+        /// Target triple to build for (e.g. wasm32-unknown-unknown)
+        #[arg(long, value_name = "TRIPLE")]
+        target: Option<String>,
+        /// Labels the build with a variant, for crates built more than once, e.g. with different features
+        #[arg(long, value_name = "LABEL")]
+        variant: Option<String>,
         /// Additional cargo arguments
         #[arg(last = true)]
         cargo_args: Vec<String>,
     },
     /// Run the project with WASM coverage
     Run {
-        /// Specify NEAR sandbox version (e.g. 1.35.0)
+        #[command(flatten)]
+        near: NearArgs,
+        /// Specify cosmwasm-vm version to collect coverage with (e.g. 2.2.2)
         #[arg(long, value_name = "VERSION")]
-        near: Option<String>,
+        cosmwasm: Option<String>,
+        /// Labels the build with a variant, for crates built more than once, e.g. with different features
+        #[arg(long, value_name = "LABEL")]
+        variant: Option<String>,
         /// Additional cargo arguments
         #[arg(last = true)]
         cargo_args: Vec<String>,
     },
     /// Run tests with WASM coverage
     Test {
-        /// Specify NEAR sandbox version (e.g. 1.35.0)
+        #[command(flatten)]
+        near: NearArgs,
+        /// Specify cosmwasm-vm version to collect coverage with (e.g. 2.2.2)
         #[arg(long, value_name = "VERSION")]
-        near: Option<String>,
+        cosmwasm: Option<String>,
+        /// Labels the build with a variant, for crates built more than once, e.g. with different features
+        #[arg(long, value_name = "LABEL")]
+        variant: Option<String>,
+        /// Runs the tests compiled to wasm with the given test runner
+        #[arg(long, value_name = "RUNNER", conflicts_with_all = ["near", "near_binary", "nearcore_src", "cosmwasm"])]
+        runner: Option<TestRunner>,
         /// Additional cargo arguments
         #[arg(last = true)]
         cargo_args: Vec<String>,
     },
-    /// Merge profraw into profdata by running llvm-profdata merge
+    /// Merge profraw into profdata (falls back to llvm-profdata merge when needed)
     Merge {
-        //. Additional llvm-profdata arguments
+        /// Merges all profraw files again instead of only the ones added since the last merge
+        #[arg(long)]
+        full: bool,
+        /// Number of merge threads, defaults to the number of CPUs
+        #[arg(long, short, value_name = "N")]
+        jobs: Option<usize>,
+        /// Moves profraw files written by a different build of the contract to profraw/<contract>/stale
+        #[arg(long)]
+        quarantine_stale: bool,
+        /// Additional llvm-profdata arguments
         #[arg(last = true)]
         llvm_profdata_args: Vec<String>,
     },
     /// Generate coverage report
     Report {
+        /// Report formats to generate: html, lcov, cobertura, json
+        #[arg(long, value_name = "FORMAT", value_delimiter = ',')]
+        format: Vec<ReportFormat>,
+        /// Fails when line coverage is below the threshold, can be set per contract
+        #[arg(long, value_name = "[CONTRACT=]PERCENT")]
+        fail_under_lines: Vec<Threshold>,
+        /// Fails when function coverage is below the threshold, can be set per contract
+        #[arg(long, value_name = "[CONTRACT=]PERCENT")]
+        fail_under_functions: Vec<Threshold>,
+        /// Fails when region coverage is below the threshold, can be set per contract
+        #[arg(long, value_name = "[CONTRACT=]PERCENT")]
+        fail_under_regions: Vec<Threshold>,
+        /// Reports which functions each test covers, requires WASMCOV_TEST_NAME to be set by the tests
+        #[arg(long)]
+        by_test: bool,
+        /// Only prints the coverage summary, no report files are generated
+        #[arg(long)]
+        summary_only: bool,
+        /// Generates a single report of all contracts with per-contract and overall totals
+        #[arg(long)]
+        combined: bool,
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Additional llvm-cov arguments
+        #[arg(last = true)]
+        llvm_cov_args: Vec<String>,
+    },
+    /// Compare coverage of two wasmcov directories, e.g. from the main branch and a PR
+    Diff {
+        /// Baseline wasmcov directory
+        baseline: PathBuf,
+        /// Current wasmcov directory
+        current: PathBuf,
+        /// Report uncovered lines changed since the git revision
+        #[arg(long, value_name = "REVISION")]
+        changed_since: Option<String>,
+        /// Saves the diff as Markdown, e.g. for a PR comment
+        #[arg(long, value_name = "FILE")]
+        markdown: Option<PathBuf>,
+        #[command(flatten)]
+        filter: FilterArgs,
         /// Additional llvm-cov arguments
         #[arg(last = true)]
         llvm_cov_args: Vec<String>,
     },
+    /// Print the effective configuration
+    Config,
+    /// Patch a local nearcore checkout to capture coverage, without building it
+    PatchNearcore {
+        /// nearcore checkout
+        nearcore_src: PathBuf,
+        /// nearcore version of the checkout, read from its git tags by default
+        #[arg(long, value_name = "VERSION")]
+        version: Option<String>,
+        /// Only report which patch patterns are found, without changing any file
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Merge old profraw files into a collected profdata per crate and remove them
+    Gc {
+        /// Only collects profraw files older than the age, e.g. 90m, 12h or 7d
+        #[arg(long, value_name = "AGE", value_parser = parse_age)]
+        older_than: Option<Duration>,
+        /// Number of merge threads, defaults to the number of CPUs
+        #[arg(long, short, value_name = "N")]
+        jobs: Option<usize>,
+    },
     /// Clean coverage data
     Clean {
         /// Removes entire wasmcov directory content when true
@@ -75,7 +243,7 @@ enum WasmcovCommands {
     },
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let args: Vec<String> = env::args().collect();
     let cli = if args.len() > 1 && args[1] == "wasmcov" {
         Cli::parse()
@@ -85,160 +253,710 @@ fn main() -> Result<()> {
         }
     };
 
-    match cli.command {
+    let result = match cli.command {
         Commands::Wasmcov(args) => handle_wasmcov(args),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:#}", err);
+            exit_code(&err)
+        }
     }
 }
 
-fn handle_wasmcov(args: WasmcovArgs) -> Result<()> {
-    let wasmcov_dir = env::var("WASMCOV_DIR").unwrap_or_default();
-    if args.wasmcov_dir.is_some() || wasmcov_dir.is_empty() {
-        dir::set_wasmcov_dir(args.wasmcov_dir.as_ref());
+// Failed cargo or test binary runs exit with the status of the command, so
+// wasmcov can be used as a drop-in for `cargo test` in CI.
+fn exit_code(err: &anyhow::Error) -> ExitCode {
+    match err.downcast_ref::<Error>() {
+        Some(Error::CommandFailed {
+            status: Some(status),
+            ..
+        }) => ExitCode::from(u8::try_from(*status).unwrap_or(1)),
+        _ => ExitCode::FAILURE,
     }
+}
+
+fn handle_wasmcov(args: WasmcovArgs) -> Result<()> {
+    let (config, config_path) = Config::load()?;
+
+    // --wasmcov-dir takes precedence over WASMCOV_DIR, which takes precedence over the config file
+    let env_wasmcov_dir = env::var("WASMCOV_DIR").unwrap_or_default();
+    let wasmcov_dir = if args.wasmcov_dir.is_some() || env_wasmcov_dir.is_empty() {
+        match args.wasmcov_dir.or(config.wasmcov_dir.clone()) {
+            Some(path) => WasmcovDir::new(path)?,
+            None => WasmcovDir::new(env::current_dir()?.join("wasmcov"))?,
+        }
+    } else {
+        WasmcovDir::from_env()?
+    };
+    let wasmcov_dir = &wasmcov_dir;
 
     match args.command {
-        WasmcovCommands::Build { cargo_args } => build_command(cargo_args),
-        WasmcovCommands::Run { near, cargo_args } => run_or_test_command("run", near, cargo_args),
-        WasmcovCommands::Test { near, cargo_args } => run_or_test_command("test", near, cargo_args),
-        WasmcovCommands::Merge { llvm_profdata_args } => merge_command(llvm_profdata_args),
-        WasmcovCommands::Report { llvm_cov_args } => report_command(llvm_cov_args),
-        WasmcovCommands::Clean { all } => clean_command(all),
+        WasmcovCommands::Build {
+            target,
+            variant,
+            cargo_args,
+        } => {
+            let target = target.or(config.target.clone());
+            build_command(wasmcov_dir, target, variant.as_deref(), cargo_args, &config)
+        }
+        WasmcovCommands::Run {
+            near,
+            cosmwasm,
+            variant,
+            cargo_args,
+        } => run_or_test_command(
+            wasmcov_dir,
+            "run",
+            near.or_config(&config),
+            cosmwasm.or(config.cosmwasm.clone()),
+            variant.as_deref(),
+            cargo_args,
+            &config,
+        ),
+        WasmcovCommands::Test {
+            runner: Some(TestRunner::WasmBindgenTest),
+            variant,
+            cargo_args,
+            ..
+        } => wasm_bindgen_test_command(wasmcov_dir, variant.as_deref(), cargo_args, &config),
+        WasmcovCommands::Test {
+            near,
+            cosmwasm,
+            variant,
+            cargo_args,
+            ..
+        } => run_or_test_command(
+            wasmcov_dir,
+            "test",
+            near.or_config(&config),
+            cosmwasm.or(config.cosmwasm.clone()),
+            variant.as_deref(),
+            cargo_args,
+            &config,
+        ),
+        WasmcovCommands::Merge {
+            full,
+            jobs,
+            quarantine_stale,
+            llvm_profdata_args,
+        } => {
+            let options = MergeOptions {
+                extra_args: llvm_profdata_args,
+                full,
+                jobs: jobs.unwrap_or(0),
+                progress: merge_progress(),
+                quarantine_stale,
+            };
+            merge_command(wasmcov_dir, &options)
+        }
+        WasmcovCommands::Report {
+            format,
+            fail_under_lines,
+            fail_under_functions,
+            fail_under_regions,
+            by_test,
+            summary_only,
+            combined,
+            filter,
+            llvm_cov_args,
+        } => {
+            let formats = report::report_formats(summary_only, format, &config.formats);
+            // Thresholds given on the command line replace the configured ones of the same metric
+            let configured = config.thresholds.to_thresholds()?;
+            let or_configured = |cli: Vec<Threshold>, configured: Vec<Threshold>| {
+                if cli.is_empty() {
+                    configured
+                } else {
+                    cli
+                }
+            };
+            let thresholds = Thresholds {
+                lines: or_configured(fail_under_lines, configured.lines),
+                functions: or_configured(fail_under_functions, configured.functions),
+                regions: or_configured(fail_under_regions, configured.regions),
+            };
+            let filter = filter.to_filter(&config)?;
+            report_command(
+                wasmcov_dir,
+                formats,
+                thresholds,
+                by_test,
+                combined,
+                &filter,
+                llvm_cov_args,
+            )
+        }
+        WasmcovCommands::Diff {
+            baseline,
+            current,
+            changed_since,
+            markdown,
+            filter,
+            llvm_cov_args,
+        } => {
+            let filter = filter.to_filter(&config)?;
+            diff_command(baseline, current, changed_since, markdown, &filter, llvm_cov_args)
+        }
+        WasmcovCommands::Config => config_command(wasmcov_dir, config, config_path),
+        WasmcovCommands::PatchNearcore {
+            nearcore_src,
+            version,
+            dry_run,
+        } => patch_nearcore_command(nearcore_src, version, dry_run),
+        WasmcovCommands::Gc { older_than, jobs } => {
+            gc_command(wasmcov_dir, older_than, jobs.unwrap_or(0))
+        }
+        WasmcovCommands::Clean { all } => clean_command(wasmcov_dir, all),
+    }
+}
+
+fn build_command(
+    wasmcov_dir: &WasmcovDir,
+    target: Option<String>,
+    variant: Option<&str>,
+    mut cargo_args: Vec<String>,
+    config: &Config,
+) -> Result<()> {
+    if let Some(target) = target {
+        cargo_args = build::with_default_target(cargo_args, &target);
     }
+    prepare_target_directory(wasmcov_dir)?;
+    set_env_vars(&config.rustflags, variant)?;
+    execute_command(wasmcov_dir, "cargo", "build", &cargo_args)?;
+    build::process_wasm_files(wasmcov_dir, variant)?;
+    Ok(())
 }
 
-fn build_command(cargo_args: Vec<String>) -> Result<()> {
-    let target_dir = prepare_target_directory()?;
-    set_env_vars();
-    execute_command("cargo", "build", &cargo_args);
-    process_wasm_files(&target_dir)
+// Cargo arguments patching cosmwasm-vm with the given version.
+#[cfg(feature = "cosmwasm")]
+fn cosmwasm_vm_patch_args(wasmcov_dir: &WasmcovDir, version: String) -> Result<Vec<String>> {
+    let cosmwasm_vm_dir = wasmcov_dir.path().join("cosmwasm_vm");
+    let cosmwasm_vm_path = cosmwasm_vm::setup_cosmwasm_vm(cosmwasm_vm_dir, version)?;
+    Ok(cosmwasm_vm::cargo_patch_args(&cosmwasm_vm_path))
 }
 
-fn run_or_test_command(command: &str, near: Option<String>, cargo_args: Vec<String>) -> Result<()> {
-    setup_near_sandbox_if_needed(&near)?;
+#[cfg(not(feature = "cosmwasm"))]
+fn cosmwasm_vm_patch_args(_wasmcov_dir: &WasmcovDir, _version: String) -> Result<Vec<String>> {
+    Err(anyhow!(
+        "--cosmwasm requires cargo-wasmcov to be built with the cosmwasm feature"
+    ))
+}
+
+fn run_or_test_command(
+    wasmcov_dir: &WasmcovDir,
+    command: &str,
+    near: NearArgs,
+    cosmwasm: Option<String>,
+    variant: Option<&str>,
+    mut cargo_args: Vec<String>,
+    config: &Config,
+) -> Result<()> {
+    setup_near_sandbox_if_needed(wasmcov_dir, &near)?;
+    if let Some(version) = cosmwasm {
+        cargo_args.splice(0..0, cosmwasm_vm_patch_args(wasmcov_dir, version)?);
+    }
     let (cargo_args, binary_args) = split_args(cargo_args);
     let executables = if command == "test" {
-        build_test_binaries(cargo_args)?
+        build_test_binaries(wasmcov_dir, cargo_args)?
     } else {
-        build_binaries(cargo_args)?
+        build_binaries(wasmcov_dir, cargo_args)?
     };
 
-    let target_dir = prepare_target_directory()?;
-    set_env_vars();
+    prepare_target_directory(wasmcov_dir)?;
+    set_env_vars(&config.rustflags, variant)?;
+    for binary in executables {
+        println!("Running binary: {}", binary);
+        if binary.ends_with(".wasm") {
+            run_wasi_binary(wasmcov_dir, &binary, &binary_args)?;
+        } else {
+            execute_command(wasmcov_dir, &binary, "", &binary_args)?;
+        }
+    }
+    build::process_wasm_files(wasmcov_dir, variant)?;
+    Ok(())
+}
+
+// Builds the tests for wasm32-unknown-unknown with coverage and runs them with
+// wasm-bindgen-test-runner, which captures the coverage itself when built with
+// `--cfg=wasm_bindgen_unstable_test_coverage` and writes it to LLVM_PROFILE_FILE.
+fn wasm_bindgen_test_command(
+    wasmcov_dir: &WasmcovDir,
+    variant: Option<&str>,
+    cargo_args: Vec<String>,
+    config: &Config,
+) -> Result<()> {
+    let (cargo_args, binary_args) = split_args(cargo_args);
+    let cargo_args = build::with_default_target(cargo_args, build::WASM_BINDGEN_TARGET);
+
+    prepare_target_directory(wasmcov_dir)?;
+    let mut rustflags = config.rustflags.clone();
+    rustflags.push(build::WASM_BINDGEN_COVERAGE_CFG.to_string());
+    set_env_vars(&rustflags, variant)?;
+    let executables = build_test_binaries(wasmcov_dir, cargo_args)?;
+
     for binary in executables {
         println!("Running binary: {}", binary);
-        execute_command(&binary, "", &binary_args);
+        let profile_file =
+            build::wasm_bindgen_profile_file(wasmcov_dir, Path::new(&binary), variant)?;
+        env::set_var("LLVM_PROFILE_FILE", profile_file);
+        execute_command(
+            wasmcov_dir,
+            "wasm-bindgen-test-runner",
+            &binary,
+            &binary_args,
+        )?;
+    }
+    build::process_wasm_files(wasmcov_dir, variant)?;
+    Ok(())
+}
+
+fn merge_command(wasmcov_dir: &WasmcovDir, options: &MergeOptions) -> Result<()> {
+    let merged = report::merge_all(wasmcov_dir, options)?;
+    warn_stale_profraw(&merged, options.quarantine_stale);
+    Ok(())
+}
+
+// Summarizes the profraw files skipped because they were written by a
+// different build than the contract's object file.
+fn warn_stale_profraw(merged: &[report::Merged], quarantined: bool) {
+    let stale: Vec<_> = merged
+        .iter()
+        .filter(|merged| !merged.stale_files.is_empty())
+        .collect();
+    if stale.is_empty() {
+        return;
+    }
+
+    eprintln!("Warning: profraw files written by a different build were not merged:");
+    for merged in stale {
+        let name = merged.profdata_path.file_stem().unwrap().to_string_lossy();
+        eprintln!("  {}: {} profraw files", name, merged.stale_files.len());
+    }
+    if quarantined {
+        eprintln!("They have been moved to profraw/<contract>/stale.");
+    } else {
+        eprintln!("Rerun the tests after rebuilding, or use `cargo wasmcov merge --quarantine-stale` to move them aside.");
     }
-    process_wasm_files(&target_dir)
 }
 
-fn merge_command(llvm_profdata_args: Vec<String>) -> Result<()> {
-    let profdata_dir = dir::get_profdata_dir();
-    for entry in fs::read_dir(dir::get_profraw_dir())? {
-        let dir_path = entry?.path();
-        if !dir_path.is_dir() || fs::read_dir(&dir_path)?.next().is_none() {
+// Progress of large merges is redrawn on a single line when stderr is a terminal.
+fn merge_progress() -> Option<&'static (dyn Fn(usize, usize) + Sync)> {
+    if !io::stderr().is_terminal() {
+        return None;
+    }
+    Some(&|merged, total| {
+        if merged == total || merged % (total / 100).max(1) == 0 {
+            eprint!("\r{}/{} profraw files merged", merged, total);
+            if merged == total {
+                eprintln!();
+            }
+        }
+    })
+}
+
+fn report_command(
+    wasmcov_dir: &WasmcovDir,
+    formats: Vec<ReportFormat>,
+    thresholds: Thresholds,
+    by_test: bool,
+    combined: bool,
+    filter: &SourceFilter,
+    llvm_cov_args: Vec<String>,
+) -> Result<()> {
+    let mut threshold_failures = Vec::new();
+    let mut summaries = BTreeMap::new();
+    let mut profdata_files = Vec::new();
+    let mut object_files = Vec::new();
+
+    let options = MergeOptions {
+        progress: merge_progress(),
+        ..Default::default()
+    };
+    warn_stale_profraw(&report::merge_all(wasmcov_dir, &options)?, false);
+
+    for (file_name, file_path) in report::profdata_files(wasmcov_dir)? {
+        let file_name = file_name.as_str();
+        println!("Generating coverage report for {}", file_name);
+
+        let Some(object_file) = find_object_file(wasmcov_dir, file_name)? else {
             continue;
+        };
+        let llvm_cov_args = filter.llvm_cov_args(&file_path, &object_file, &llvm_cov_args)?;
+
+        // The combined report replaces the reports of the single contracts.
+        for format in formats.iter().filter(|_| !combined) {
+            let output_path = format.output_path(&wasmcov_dir.report()?, file_name);
+            report::generate_report_in_format(
+                *format,
+                file_name,
+                &file_path,
+                &object_file,
+                &output_path,
+                &llvm_cov_args,
+            )?;
+            println!("Coverage report ({}) has been saved to {:?}", format, output_path);
         }
 
-        let dir_name = dir_path.file_name().unwrap().to_str().unwrap();
-        println!("Merging profraw files for {}", dir_name);
+        let summary = report::summary(&file_path, &object_file, &llvm_cov_args)?;
+        threshold_failures.extend(thresholds.check(file_name, &summary));
+        summaries.insert(file_name.to_string(), summary);
 
-        let profdata_path = profdata_dir.join(format!("{}.profdata", dir_name));
-        report::merge_profraw_to_profdata(&dir_path, &profdata_path, llvm_profdata_args.clone())?;
+        if by_test {
+            by_test_command(wasmcov_dir, file_name, &object_file, &llvm_cov_args)?;
+        }
+        profdata_files.push(file_path);
+        object_files.push(object_file);
+    }
+
+    let summaries: Vec<_> = summaries.into_iter().collect();
+    let mut total = None;
+    if combined && !object_files.is_empty() {
+        println!("Generating combined coverage report");
+        let merge_options = MergeOptions::default();
+        let profdata_path = report::merge_combined(wasmcov_dir, &profdata_files, &merge_options)?;
+        let object_file = &object_files[0];
+        let mut combined_args = report::object_args(&object_files)?;
+        combined_args.extend(llvm_cov_args);
+        let combined_args = filter.llvm_cov_args(&profdata_path, object_file, &combined_args)?;
+
+        let report_dir = wasmcov_dir.report()?;
+        for format in &formats {
+            let output_path = format.output_path(&report_dir, report::COMBINED);
+            report::generate_report_in_format(
+                *format,
+                report::COMBINED,
+                &profdata_path,
+                object_file,
+                &output_path,
+                &combined_args,
+            )?;
+            println!("Coverage report ({format}) has been saved to {output_path:?}");
+        }
 
-        println!("Profdata file has been saved to {:?}", profdata_path);
+        let summary = report::summary(&profdata_path, object_file, &combined_args)?;
+        threshold_failures.extend(thresholds.check(report::COMBINED, &summary));
+        if formats.contains(&ReportFormat::Html) {
+            let index_path = report_dir.join("html").join("index.html");
+            let index = report::combined_index_html(&summaries, &summary);
+            fs::write(&index_path, index)?;
+            println!("Combined report index has been saved to {index_path:?}");
+        }
+        total = Some(summary);
+    }
+
+    if !summaries.is_empty() {
+        println!();
+        let table = report::summary_table_with_total(&summaries, total, report::use_color());
+        print!("{}", table);
+    }
+
+    if !threshold_failures.is_empty() {
+        for failure in &threshold_failures {
+            eprintln!("{}", failure);
+        }
+        return Err(anyhow!("Coverage thresholds have not been met"));
     }
     Ok(())
 }
 
-fn report_command(llvm_cov_args: Vec<String>) -> Result<()> {
-    let target_dir = dir::get_target_dir();
+fn by_test_command(
+    wasmcov_dir: &WasmcovDir,
+    contract: &str,
+    object_file: &Path,
+    llvm_cov_args: &[String],
+) -> Result<()> {
+    let matrix = attribution::by_test_report(wasmcov_dir, contract, object_file, llvm_cov_args)?;
+    if matrix.tests.is_empty() {
+        eprintln!(
+            "Warning: no per-test coverage found for {:?}, the tests did not record their names",
+            contract
+        );
+        return Ok(());
+    }
+
+    let total = matrix.functions().len();
+    println!(
+        "Per-test coverage of {} ({} functions covered in total):",
+        contract, total
+    );
+    for (test, functions) in &matrix.tests {
+        let unique = matrix.unique_functions(test).len();
+        println!(
+            "  {}: {} functions, {} unique{}",
+            test,
+            functions.len(),
+            unique,
+            if unique == 0 { " (redundant)" } else { "" }
+        );
+    }
+    println!(
+        "Per-test report has been saved to {:?}",
+        wasmcov_dir.report()?.join("by-test")
+    );
+    Ok(())
+}
 
-    merge_command(Vec::new())?;
+fn find_object_file(wasmcov_dir: &WasmcovDir, name: &str) -> Result<Option<PathBuf>> {
+    let object_file = report::find_object_file(wasmcov_dir, name)?;
+    if object_file.is_none() {
+        eprintln!("Warning: object file not found for {:?}", name);
+        eprintln!("Object files should be placed in the wasmcov target directory");
+    }
+    Ok(object_file)
+}
 
-    for entry in fs::read_dir(dir::get_profdata_dir())? {
-        let file_path = entry?.path();
-        if file_path.is_dir() {
+// Loads a snapshot of every contract with a profdata and an object file in the wasmcov directory.
+fn load_snapshots(
+    wasmcov_dir: &WasmcovDir,
+    filter: &SourceFilter,
+    llvm_cov_args: &[String],
+) -> Result<BTreeMap<String, diff::Snapshot>> {
+    let mut snapshots = BTreeMap::new();
+    for (file_name, file_path) in report::profdata_files(wasmcov_dir)? {
+        let Some(object_file) = find_object_file(wasmcov_dir, &file_name)? else {
             continue;
+        };
+        let llvm_cov_args = filter.llvm_cov_args(&file_path, &object_file, llvm_cov_args)?;
+        let snapshot = diff::Snapshot::load(&file_path, &object_file, &llvm_cov_args)?;
+        snapshots.insert(file_name, snapshot);
+    }
+    Ok(snapshots)
+}
+
+fn diff_command(
+    baseline: PathBuf,
+    current: PathBuf,
+    changed_since: Option<String>,
+    markdown: Option<PathBuf>,
+    filter: &SourceFilter,
+    llvm_cov_args: Vec<String>,
+) -> Result<()> {
+    let baseline = load_snapshots(&WasmcovDir::open(baseline)?, filter, &llvm_cov_args)?;
+    let current = load_snapshots(&WasmcovDir::open(current)?, filter, &llvm_cov_args)?;
+    let changed_lines = changed_since
+        .map(|revision| diff::changed_lines_since(&revision))
+        .transpose()?;
+
+    let mut markdown_output = String::new();
+    let contracts: BTreeSet<&String> = baseline.keys().chain(current.keys()).collect();
+    for contract in contracts {
+        let coverage_diff = diff::diff(
+            contract,
+            baseline.get(contract),
+            current.get(contract),
+            changed_lines.as_ref(),
+        );
+        println!("{}", coverage_diff.to_table());
+        markdown_output.push_str(&coverage_diff.to_markdown());
+        markdown_output.push('\n');
+    }
+
+    if let Some(markdown) = markdown {
+        fs::write(&markdown, markdown_output)?;
+        println!("Markdown diff has been saved to {:?}", markdown);
+    }
+    Ok(())
+}
+
+fn patch_nearcore_command(
+    nearcore_dir: PathBuf,
+    version: Option<String>,
+    dry_run: bool,
+) -> Result<()> {
+    if !dry_run && near_sandbox::is_patched(&nearcore_dir)? {
+        println!("nearcore in {:?} is already patched", nearcore_dir);
+        return Ok(());
+    }
+
+    let version = version.or_else(|| near_sandbox::nearcore_version(&nearcore_dir));
+    println!(
+        "Checking patches for nearcore {}",
+        version.as_deref().unwrap_or("(unknown version)")
+    );
+    let checks = near_sandbox::check_patches(&nearcore_dir, version.as_deref())?;
+    for check in &checks {
+        let status = if check.applies() { "ok" } else { "missing" };
+        println!(
+            "[{}] {}: `{}` (matches: {})",
+            status, check.file, check.pattern, check.matches
+        );
+    }
+
+    if dry_run {
+        if checks.iter().any(|check| !check.applies()) {
+            return Err(anyhow!("Some patches do not apply"));
         }
+        return Ok(());
+    }
+    near_sandbox::add_wasmcov_to_nearcore(&nearcore_dir, version.as_deref())?;
+    println!("Patched nearcore in {:?}", nearcore_dir);
+    Ok(())
+}
 
-        let file_name = file_path.with_extension("");
-        let file_name = file_name.file_name().unwrap().to_str().unwrap();
-        println!("Generating coverage report for {}", file_name);
+fn config_command(
+    wasmcov_dir: &WasmcovDir,
+    mut config: Config,
+    config_path: Option<PathBuf>,
+) -> Result<()> {
+    match config_path {
+        Some(path) => println!("# Configuration loaded from {:?}", path),
+        None => println!("# No wasmcov.toml or [package.metadata.wasmcov] found, using defaults"),
+    }
+    config.wasmcov_dir = Some(wasmcov_dir.path().to_path_buf());
+    if config.formats.is_empty() {
+        config.formats = vec![ReportFormat::Html];
+    }
+    print!("{}", config.to_toml()?);
+    Ok(())
+}
 
-        let object_file = match utils::find_file(
-            &target_dir,
-            &[
-                &format!("{}.o", file_name.replace("-", "_")),
-                &format!("{}.o", file_name),
-            ],
-        ) {
-            Ok(file) => file,
-            Err(_) => {
-                eprintln!("Warning: object file not found for {:?}", file_name);
-                eprintln!("Object files should be placed in the wasmcov target directory");
-                continue;
-            }
-        };
+fn gc_command(wasmcov_dir: &WasmcovDir, older_than: Option<Duration>, jobs: usize) -> Result<()> {
+    let options = MergeOptions {
+        jobs,
+        progress: merge_progress(),
+        ..Default::default()
+    };
+    let collected = gc::collect(wasmcov_dir, older_than, &options)?;
+    if collected.is_empty() {
+        println!("No profraw files to collect");
+        return Ok(());
+    }
 
-        let report_dir = dir::get_report_dir().join(file_name);
-        report::generate_report(&file_path, &object_file, &report_dir, &llvm_cov_args)?;
-        println!("Coverage report has been saved to {:?}", report_dir);
+    for crate_collected in &collected {
+        println!(
+            "Collected {} profraw files of {} into {:?}, reclaimed {}",
+            crate_collected.profraw_files,
+            crate_collected.name,
+            crate_collected.profdata_path,
+            format_size(crate_collected.reclaimed_bytes)
+        );
     }
+    let reclaimed_bytes = collected.iter().map(|c| c.reclaimed_bytes).sum();
+    println!("Reclaimed {} in total", format_size(reclaimed_bytes));
     Ok(())
 }
 
-fn clean_command(all: bool) -> Result<()> {
-    dir::clean_wasmcov_directory(all)
+// Parses an age like 30s, 90m, 12h or 7d.
+fn parse_age(age: &str) -> Result<Duration, String> {
+    let (value, unit) = age.split_at(age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len()));
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid age {:?}, expected e.g. 90m, 12h or 7d", age))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        unit => return Err(format!("invalid age unit {unit:?}, expected s, m, h or d")),
+    };
+    Ok(Duration::from_secs(value * seconds))
 }
 
-fn set_env_vars() {
-    env::set_var(
-        "CARGO_ENCODED_RUSTFLAGS",
-        build::get_build_flags().join("\x1f"),
-    );
-    let (is_nightly, _version) = llvm::check_rustc_version().expect("Failed to run rustc command");
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn clean_command(wasmcov_dir: &WasmcovDir, all: bool) -> Result<()> {
+    Ok(wasmcov_dir.clean(all)?)
+}
+
+fn set_env_vars(rustflags: &[String], variant: Option<&str>) -> Result<()> {
+    let mut flags = build::get_build_flags();
+    flags.extend(rustflags.iter().map(String::as_str));
+    env::set_var("CARGO_ENCODED_RUSTFLAGS", flags.join("\x1f"));
+    // Read by the capture macros when they are not given a variant label
+    if let Some(variant) = variant {
+        dir::check_variant(variant)?;
+        env::set_var(VARIANT_ENV, variant);
+    }
+    let (is_nightly, _version) = llvm::check_rustc_version()?;
     if !is_nightly {
         println!("Setting RUSTUP_TOOLCHAIN to nightly");
         env::set_var("RUSTUP_TOOLCHAIN", "nightly");
     }
+    Ok(())
 }
 
-fn execute_command(command: &str, subcommand: &str, args: &[String]) -> String {
-    let output = Command::new(command)
+// Runs the command with inherited stdio. A non-zero status is returned as
+// `Error::CommandFailed`, whose status becomes the exit code of wasmcov.
+// WASMCOV_DIR is read by the tests, the patched NEAR sandbox and cosmwasm-vm
+// to save the profraw files.
+fn execute_command(
+    wasmcov_dir: &WasmcovDir,
+    command: &str,
+    subcommand: &str,
+    args: &[String],
+) -> Result<()> {
+    let status = Command::new(command)
         .arg(subcommand)
         .args(args)
-        .spawn()
-        .expect("Failed to execute command")
-        .wait_with_output()
-        .expect("Failed to wait on child");
-
-    if !output.status.success() {
-        eprintln!(
-            "Command `{}` failed with status: {}",
-            subcommand, output.status
-        );
-        std::process::exit(output.status.code().unwrap_or(1));
+        .env("WASMCOV_DIR", wasmcov_dir.path())
+        .status()
+        .map_err(|err| anyhow!("Failed to execute {}: {}", command, err))?;
+
+    if !status.success() {
+        return Err(Error::CommandFailed {
+            command: format!("{} {}", command, subcommand).trim_end().to_string(),
+            status: status.code(),
+            stderr: String::new(),
+        }
+        .into());
     }
-    String::from_utf8(output.stdout).expect("Failed to read command output")
+    Ok(())
 }
 
-fn setup_near_sandbox_if_needed(near: &Option<String>) -> Result<()> {
-    if let Some(version) = near {
-        let near_sandbox_dir = dir::get_wasmcov_dir().join("near_sandbox");
-        let neard_path = near_sandbox::setup_near_sandbox(near_sandbox_dir, version.clone())?;
-        env::set_var("NEAR_SANDBOX_BIN_PATH", neard_path);
-    }
+// WASI binaries (e.g. built for wasm32-wasip1) are run with wasmtime, with the
+// wasmcov directory preopened so `wasmcov::wasi` can write the profraw files.
+fn run_wasi_binary(wasmcov_dir: &WasmcovDir, binary: &str, binary_args: &[String]) -> Result<()> {
+    let path = wasmcov_dir.path();
+    let mut args = vec![
+        format!("--dir={}", path.display()),
+        format!("--env=WASMCOV_DIR={}", path.display()),
+        binary.to_string(),
+    ];
+    args.extend(binary_args.iter().cloned());
+    execute_command(wasmcov_dir, "wasmtime", "run", &args)
+}
+
+fn setup_near_sandbox_if_needed(wasmcov_dir: &WasmcovDir, near: &NearArgs) -> Result<()> {
+    let neard_path = if let Some(neard_path) = &near.near_binary {
+        near_sandbox::check_neard(neard_path)?
+    } else if let Some(nearcore_dir) = &near.nearcore_src {
+        near_sandbox::build_local_nearcore(nearcore_dir)?
+    } else if let Some(version) = &near.near {
+        let near_sandbox_dir = wasmcov_dir.cache()?.join("neard");
+        near_sandbox::setup_near_sandbox(near_sandbox_dir, version.clone())?
+    } else {
+        return Ok(());
+    };
+    env::set_var("NEAR_SANDBOX_BIN_PATH", neard_path);
     Ok(())
 }
 
-fn build_binaries(cargo_args: Vec<String>) -> Result<Vec<String>> {
-    execute_command("cargo", "build", &cargo_args);
+fn build_binaries(wasmcov_dir: &WasmcovDir, cargo_args: Vec<String>) -> Result<Vec<String>> {
+    execute_command(wasmcov_dir, "cargo", "build", &cargo_args)?;
     parse_cargo_output("build", &cargo_args)
 }
 
-fn build_test_binaries(mut cargo_args: Vec<String>) -> Result<Vec<String>> {
+fn build_test_binaries(
+    wasmcov_dir: &WasmcovDir,
+    mut cargo_args: Vec<String>,
+) -> Result<Vec<String>> {
     cargo_args.push("--no-run".to_string());
-    execute_command("cargo", "test", &cargo_args);
+    execute_command(wasmcov_dir, "cargo", "test", &cargo_args)?;
     parse_cargo_output("test", &cargo_args)
 }
 
@@ -267,38 +985,9 @@ fn parse_cargo_output(command: &str, extra_args: &[String]) -> Result<Vec<String
         .collect())
 }
 
-fn prepare_target_directory() -> Result<PathBuf> {
-    let target_dir = dir::get_target_dir();
-    for wasm_file in build::find_wasm_files_with_coverage(&target_dir)? {
-        fs::remove_file(&wasm_file)?;
-    }
+fn prepare_target_directory(wasmcov_dir: &WasmcovDir) -> Result<()> {
+    let target_dir = build::prepare_target_dir(wasmcov_dir)?;
     env::set_var("CARGO_TARGET_DIR", &target_dir);
-    Ok(target_dir)
-}
-
-fn process_wasm_files(target_dir: &PathBuf) -> Result<()> {
-    for wasm_file in build::find_wasm_files_with_coverage(target_dir)? {
-        println!("Processing WASM file: {:?}", wasm_file);
-        match build::find_ll_file(&wasm_file) {
-            Ok(ll_file) => {
-                let new_ll_file = target_dir.join(ll_file.file_name().unwrap());
-                build::correct_ll_file(&ll_file, &new_ll_file)?;
-
-                let obj_file = target_dir.join(format!(
-                    "{}.o",
-                    new_ll_file.file_stem().unwrap().to_str().unwrap()
-                ));
-                build::compile_ll_file(&new_ll_file, &obj_file)?;
-
-                let wasm_file_target = target_dir.join(wasm_file.file_name().unwrap());
-                fs::copy(&wasm_file, &wasm_file_target)?;
-            }
-            Err(_) => {
-                eprintln!("Warning: LL file not found for {:?}", wasm_file);
-            }
-        }
-    }
-    println!("Processed files have been saved to {:?}", target_dir);
     Ok(())
 }
 
@@ -0,0 +1,31 @@
+// Returns the coverage collected so far as profraw data and resets the
+// counters, so the next call only returns new coverage. JavaScript code saves
+// it to `$WASMCOV_DIR/profraw/<binary name>/<any name>.profraw`.
+pub fn capture_coverage() -> Vec<u8> {
+    let mut coverage = vec![];
+    unsafe {
+        minicov::capture_coverage(&mut coverage).unwrap();
+    }
+    minicov::reset_coverage();
+    coverage
+}
+
+// Exports `wasmcovCaptureCoverage()`, returning the profraw data as a
+// Uint8Array, and `wasmcovBinaryName()` to JavaScript. The crate using it must
+// depend on wasm-bindgen.
+#[macro_export]
+macro_rules! add_bindgen_coverage {
+    ($($variant:literal)?) => {
+        #[wasm_bindgen::prelude::wasm_bindgen(js_name = wasmcovCaptureCoverage)]
+        pub fn wasmcov_capture_coverage() -> Vec<u8> {
+            $crate::bindgen::capture_coverage()
+        }
+
+        #[wasm_bindgen::prelude::wasm_bindgen(js_name = wasmcovBinaryName)]
+        pub fn wasmcov_binary_name() -> String {
+            $crate::binary_name!($($variant)?)
+        }
+    };
+}
+
+pub use add_bindgen_coverage as add_coverage;
@@ -1,12 +1,15 @@
+use crate::dir::{BinaryKey, WasmcovDir};
+use crate::error::{error, Error, Result};
+use crate::fingerprint::Fingerprint;
 use crate::llvm;
-use crate::utils::run_command;
-use anyhow::anyhow;
-use anyhow::Result;
+use crate::object;
+use crate::utils::{path_to_str, run_command};
+use crate::wasm;
 use glob::glob;
 use regex::Regex;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub fn get_build_flags() -> Vec<&'static str> {
     vec![
@@ -18,9 +21,39 @@ pub fn get_build_flags() -> Vec<&'static str> {
     ]
 }
 
-pub fn find_wasm_files_with_coverage(dir: &PathBuf) -> Result<Vec<PathBuf>, anyhow::Error> {
+// Target and cfg of the tests run with wasm-bindgen-test-runner, which
+// captures the coverage itself when built with the cfg.
+pub const WASM_BINDGEN_TARGET: &str = "wasm32-unknown-unknown";
+pub const WASM_BINDGEN_COVERAGE_CFG: &str = "--cfg=wasm_bindgen_unstable_test_coverage";
+
+// Adds `--target` to the cargo arguments unless they already select a target.
+pub fn with_default_target(mut cargo_args: Vec<String>, target: &str) -> Vec<String> {
+    let has_target = cargo_args
+        .iter()
+        .any(|arg| arg == "--target" || arg.starts_with("--target="));
+    if !has_target {
+        cargo_args.extend(["--target".to_string(), target.to_string()]);
+    }
+    cargo_args
+}
+
+// LLVM_PROFILE_FILE for a test binary run with wasm-bindgen-test-runner. The
+// profraw directory is named like the object file generated from the binary
+// by `process_wasm_files`, and is created if needed.
+pub fn wasm_bindgen_profile_file(
+    wasmcov_dir: &WasmcovDir,
+    binary: &Path,
+    variant: Option<&str>,
+) -> Result<PathBuf> {
+    let name = binary.file_stem().unwrap_or_default().to_string_lossy();
+    let profraw_dir = wasmcov_dir.profraw_dir_of(&BinaryKey::new(&name, variant)?)?;
+    fs::create_dir_all(&profraw_dir)?;
+    Ok(profraw_dir.join("%m_%p.profraw"))
+}
+
+pub fn find_wasm_files_with_coverage(dir: &Path) -> Result<Vec<PathBuf>> {
     let mut matching_files = Vec::new();
-    let pattern = format!("{}/**/deps/*.wasm", dir.to_str().unwrap());
+    let pattern = format!("{}/**/deps/*.wasm", path_to_str(dir)?);
     let search_pattern = b"__llvm_profile_init";
 
     for entry in glob(&pattern)? {
@@ -39,24 +72,33 @@ pub fn find_wasm_files_with_coverage(dir: &PathBuf) -> Result<Vec<PathBuf>, anyh
     Ok(matching_files)
 }
 
-pub fn find_ll_file(wasm_file: &PathBuf) -> Result<PathBuf, anyhow::Error> {
+// Creates an object file for llvm-cov from the coverage mapping sections
+// embedded in the wasm file, without compiling the LLVM IR. The fingerprint
+// of the build is saved next to it.
+pub fn extract_coverage_object(wasm_file: &Path, obj_file: &Path) -> Result<()> {
+    let mut wasm_contents = Vec::new();
+    File::open(wasm_file)?.read_to_end(&mut wasm_contents)?;
+    let sections = wasm::read_coverage_sections(&wasm_contents)?;
+    object::write_coverage_object(&sections, obj_file)?;
+    Fingerprint::from_covfun(&sections.covfun)?.write(&Fingerprint::path(obj_file))
+}
+
+pub fn find_ll_file(wasm_file: &Path) -> Result<PathBuf> {
     // check if wasm_file_dir/deps/wasm_file_name.ll exists
-    let wasm_file_dir = wasm_file.parent().unwrap();
-    let wasm_file_name = wasm_file.file_stem().unwrap().to_str().unwrap();
-    let ll_file = wasm_file_dir.join(format!("{}.ll", wasm_file_name));
+    let ll_file = wasm_file.with_extension("ll");
 
     if !ll_file.exists() {
-        return Err(anyhow!("LL file {:?} does not exist.", ll_file));
+        return Err(Error::FileNotFound(ll_file));
     }
 
     Ok(ll_file)
 }
 
-pub fn correct_ll_file(ll_file: &PathBuf, new_ll_file: &PathBuf) -> Result<(), anyhow::Error> {
+pub fn correct_ll_file(ll_file: &Path, new_ll_file: &Path) -> Result<()> {
     let mut ll_contents = String::new();
 
-    File::open(&ll_file)
-        .unwrap_or_else(|_| panic!("Failed to open LL file {:?}.", ll_file))
+    File::open(ll_file)
+        .map_err(|err| error!("Failed to open LL file {:?}: {}", ll_file, err))?
         .read_to_string(&mut ll_contents)?;
 
     let modified_ll_contents = Regex::new(r"(?ms)^(define[^\n]*\n).*?^}\s*$")
@@ -64,14 +106,14 @@ pub fn correct_ll_file(ll_file: &PathBuf, new_ll_file: &PathBuf) -> Result<(), a
         .replace_all(&ll_contents, "${1}start:\n  unreachable\n}\n")
         .to_string();
 
-    File::create(&new_ll_file)
-        .unwrap_or_else(|_| panic!("Failed to open LL file {:?}", new_ll_file))
+    File::create(new_ll_file)
+        .map_err(|err| error!("Failed to create LL file {:?}: {}", new_ll_file, err))?
         .write_all(modified_ll_contents.as_bytes())?;
 
     Ok(())
 }
 
-pub fn compile_ll_file(ll_file: &PathBuf, obj_file: &PathBuf) -> Result<()> {
+pub fn compile_ll_file(ll_file: &Path, obj_file: &Path) -> Result<()> {
     run_command(
         &llvm::get_tooling()?.clang,
         &[
@@ -79,10 +121,60 @@ pub fn compile_ll_file(ll_file: &PathBuf, obj_file: &PathBuf) -> Result<()> {
             "-Wno-override-module",
             "-c",
             "-o",
-            &obj_file.to_str().unwrap(),
-            &ll_file.to_str().unwrap(),
+            path_to_str(obj_file)?,
+            path_to_str(ll_file)?,
         ],
         None,
     )?;
     Ok(())
 }
+
+// Removes the instrumented wasm files of previous builds from the target
+// directory, so only the binaries of the next build are processed. Returns
+// the target directory, to be used as CARGO_TARGET_DIR.
+pub fn prepare_target_dir(wasmcov_dir: &WasmcovDir) -> Result<PathBuf> {
+    let target_dir = wasmcov_dir.target()?;
+    for wasm_file in find_wasm_files_with_coverage(&target_dir)? {
+        fs::remove_file(&wasm_file)?;
+    }
+    Ok(target_dir)
+}
+
+// Creates an object file for every instrumented wasm file in the target
+// directory and copies the wasm files next to them. Both are named
+// `<crate_name>@<variant>` when the build is labelled with a variant.
+pub fn process_wasm_files(wasmcov_dir: &WasmcovDir, variant: Option<&str>) -> Result<()> {
+    let target_dir = wasmcov_dir.target()?;
+    for wasm_file in find_wasm_files_with_coverage(&target_dir)? {
+        println!("Processing WASM file: {:?}", wasm_file);
+        let crate_name = wasm_file.file_stem().unwrap().to_string_lossy();
+        let key = BinaryKey::new(&crate_name, variant)?;
+        let obj_file = wasmcov_dir.object_file_of(&key)?;
+        // A fingerprint of a previous build would mark the new profraw files stale.
+        let fingerprint_file = Fingerprint::path(&obj_file);
+        if fingerprint_file.exists() {
+            fs::remove_file(&fingerprint_file)?;
+        }
+        if let Err(err) = extract_coverage_object(&wasm_file, &obj_file) {
+            // Older toolchains do not keep the coverage mapping in the wasm
+            // file, so compile the object file from the LLVM IR instead.
+            eprintln!("Warning: {}, compiling LL file instead", err);
+            match find_ll_file(&wasm_file) {
+                Ok(ll_file) => {
+                    let new_ll_file = target_dir.join(format!("{}.ll", key.file_stem()));
+                    correct_ll_file(&ll_file, &new_ll_file)?;
+                    compile_ll_file(&new_ll_file, &obj_file)?;
+                }
+                Err(_) => {
+                    eprintln!("Warning: LL file not found for {:?}", wasm_file);
+                    continue;
+                }
+            }
+        }
+
+        let wasm_file_target = target_dir.join(format!("{}.wasm", key.file_stem()));
+        fs::copy(&wasm_file, &wasm_file_target)?;
+    }
+    println!("Processed files have been saved to {:?}", target_dir);
+    Ok(())
+}
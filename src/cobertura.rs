@@ -0,0 +1,207 @@
+use crate::error::{error, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Line, function and branch hits of a single source file, as exported by
+// `llvm-cov export -format=lcov`.
+#[derive(Debug, Default)]
+pub struct LcovFile {
+    pub path: String,
+    // line -> hits
+    pub lines: BTreeMap<u64, u64>,
+    // name -> (line, hits)
+    pub functions: BTreeMap<String, (u64, u64)>,
+    // line -> hits of each branch
+    pub branches: BTreeMap<u64, Vec<u64>>,
+}
+
+pub fn parse_lcov(lcov: &str) -> Result<Vec<LcovFile>> {
+    let mut files = Vec::new();
+    let mut current: Option<LcovFile> = None;
+    for line in lcov.lines() {
+        let (key, value) = line.split_once(':').unwrap_or((line, ""));
+        if key == "SF" {
+            current = Some(LcovFile {
+                path: value.to_string(),
+                ..Default::default()
+            });
+            continue;
+        }
+        if key == "end_of_record" {
+            files.extend(current.take());
+            continue;
+        }
+        let Some(file) = current.as_mut() else {
+            continue;
+        };
+        let fields: Vec<&str> = value.split(',').collect();
+        let parse = |index: usize| -> Result<u64> {
+            fields
+                .get(index)
+                .and_then(|field| field.parse().ok())
+                .ok_or_else(|| error!("Invalid lcov line: {}", line))
+        };
+        match key {
+            "DA" => {
+                *file.lines.entry(parse(0)?).or_default() += parse(1)?;
+            }
+            "FN" => {
+                let name = fields[1..].join(",");
+                file.functions.entry(name).or_default().0 = parse(0)?;
+            }
+            "FNDA" => {
+                let name = fields[1..].join(",");
+                file.functions.entry(name).or_default().1 += parse(0)?;
+            }
+            "BRDA" => {
+                // "-" means the block containing the branch was never executed
+                let hits = parse(3).unwrap_or(0);
+                file.branches.entry(parse(0)?).or_default().push(hits);
+            }
+            _ => {}
+        }
+    }
+    Ok(files)
+}
+
+struct Rates {
+    lines_covered: usize,
+    lines_valid: usize,
+    branches_covered: usize,
+    branches_valid: usize,
+}
+
+impl Rates {
+    fn of<'a>(files: impl IntoIterator<Item = &'a LcovFile>) -> Self {
+        let mut rates = Rates {
+            lines_covered: 0,
+            lines_valid: 0,
+            branches_covered: 0,
+            branches_valid: 0,
+        };
+        for file in files {
+            rates.lines_valid += file.lines.len();
+            rates.lines_covered += file.lines.values().filter(|hits| **hits > 0).count();
+            for branches in file.branches.values() {
+                rates.branches_valid += branches.len();
+                rates.branches_covered += branches.iter().filter(|hits| **hits > 0).count();
+            }
+        }
+        rates
+    }
+
+    fn attributes(&self) -> String {
+        format!(
+            "line-rate=\"{}\" branch-rate=\"{}\"",
+            rate(self.lines_covered, self.lines_valid),
+            rate(self.branches_covered, self.branches_valid)
+        )
+    }
+}
+
+fn rate(covered: usize, valid: usize) -> String {
+    if valid == 0 {
+        "1".to_string()
+    } else {
+        format!("{:.4}", covered as f64 / valid as f64)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Renders the files of one contract as a Cobertura XML report with a single package.
+pub fn to_cobertura(package: &str, files: &[LcovFile]) -> String {
+    let rates = Rates::of(files);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" ?>\n");
+    xml.push_str(
+        "<!DOCTYPE coverage SYSTEM \"http://cobertura.sourceforge.net/xml/coverage-04.dtd\">\n",
+    );
+    let _ = writeln!(
+        xml,
+        "<coverage {} lines-covered=\"{}\" lines-valid=\"{}\" branches-covered=\"{}\" branches-valid=\"{}\" complexity=\"0\" version=\"wasmcov {}\" timestamp=\"{}\">",
+        rates.attributes(),
+        rates.lines_covered,
+        rates.lines_valid,
+        rates.branches_covered,
+        rates.branches_valid,
+        env!("CARGO_PKG_VERSION"),
+        timestamp
+    );
+    xml.push_str("  <packages>\n");
+    let _ = writeln!(
+        xml,
+        "    <package name=\"{}\" {} complexity=\"0\">",
+        escape(package),
+        rates.attributes()
+    );
+    xml.push_str("      <classes>\n");
+    for file in files {
+        let path = escape(&file.path);
+        let _ = writeln!(
+            xml,
+            "        <class name=\"{}\" filename=\"{}\" {} complexity=\"0\">",
+            path,
+            path,
+            Rates::of([file]).attributes()
+        );
+        xml.push_str("          <methods>\n");
+        for (name, (line, hits)) in &file.functions {
+            let _ = writeln!(
+                xml,
+                "            <method name=\"{}\" signature=\"\" line-rate=\"{}\" branch-rate=\"1\" complexity=\"0\">",
+                escape(name),
+                if *hits > 0 { 1 } else { 0 }
+            );
+            let _ = writeln!(
+                xml,
+                "              <lines><line number=\"{}\" hits=\"{}\"/></lines>",
+                line, hits
+            );
+            xml.push_str("            </method>\n");
+        }
+        xml.push_str("          </methods>\n");
+        xml.push_str("          <lines>\n");
+        for (line, hits) in &file.lines {
+            match file.branches.get(line) {
+                Some(branches) => {
+                    let covered = branches.iter().filter(|hits| **hits > 0).count();
+                    let _ = writeln!(
+                        xml,
+                        "            <line number=\"{}\" hits=\"{}\" branch=\"true\" condition-coverage=\"{}% ({}/{})\"/>",
+                        line,
+                        hits,
+                        covered * 100 / branches.len(),
+                        covered,
+                        branches.len()
+                    );
+                }
+                None => {
+                    let _ = writeln!(
+                        xml,
+                        "            <line number=\"{}\" hits=\"{}\" branch=\"false\"/>",
+                        line, hits
+                    );
+                }
+            }
+        }
+        xml.push_str("          </lines>\n");
+        xml.push_str("        </class>\n");
+    }
+    xml.push_str("      </classes>\n");
+    xml.push_str("    </package>\n");
+    xml.push_str("  </packages>\n");
+    xml.push_str("</coverage>\n");
+    xml
+}
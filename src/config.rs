@@ -0,0 +1,172 @@
+use crate::error::{error, Result};
+use crate::report::ReportFormat;
+use crate::threshold::{Threshold, Thresholds};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE: &str = "wasmcov.toml";
+
+// Defaults read from `wasmcov.toml` or the `[package.metadata.wasmcov]` /
+// `[workspace.metadata.wasmcov]` table of Cargo.toml. Command line flags
+// take precedence over these values.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub wasmcov_dir: Option<PathBuf>,
+    // NEAR sandbox version used by `run` and `test`.
+    pub near: Option<String>,
+    // Already patched neard binary used instead of building the NEAR sandbox.
+    pub near_binary: Option<PathBuf>,
+    // Local nearcore checkout patched and built instead of cloning nearcore.
+    pub nearcore_src: Option<PathBuf>,
+    // cosmwasm-vm version patched for `run` and `test`.
+    pub cosmwasm: Option<String>,
+    // Target triple passed to cargo, e.g. wasm32-unknown-unknown.
+    pub target: Option<String>,
+    // Extra rustflags added to the coverage instrumentation flags.
+    pub rustflags: Vec<String>,
+    // Source path globs to include in or exclude from reports.
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // Shows cargo registry and standard library sources in reports.
+    pub no_default_excludes: bool,
+    pub formats: Vec<ReportFormat>,
+    pub thresholds: ThresholdsConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricThresholds {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub functions: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regions: Option<f64>,
+}
+
+// Global thresholds with per contract overrides:
+//
+// [thresholds]
+// lines = 80
+// [thresholds.contracts.helper_contract]
+// lines = 50
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThresholdsConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub functions: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regions: Option<f64>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub contracts: BTreeMap<String, MetricThresholds>,
+}
+
+impl ThresholdsConfig {
+    pub fn to_thresholds(&self) -> Result<Thresholds> {
+        let mut thresholds = Thresholds::default();
+        let global = MetricThresholds {
+            lines: self.lines,
+            functions: self.functions,
+            regions: self.regions,
+        };
+        let all = std::iter::once((None, &global)).chain(
+            self.contracts
+                .iter()
+                .map(|(contract, metrics)| (Some(contract.clone()), metrics)),
+        );
+        for (contract, metrics) in all {
+            let targets = [
+                (&mut thresholds.lines, metrics.lines),
+                (&mut thresholds.functions, metrics.functions),
+                (&mut thresholds.regions, metrics.regions),
+            ];
+            for (target, percent) in targets {
+                if let Some(percent) = percent {
+                    target.push(Threshold::new(contract.clone(), percent)?);
+                }
+            }
+        }
+        Ok(thresholds)
+    }
+}
+
+impl Config {
+    // Looks for the config in the current directory and its parents, see
+    // `find`. Returns the default config when there is none.
+    pub fn load() -> Result<(Config, Option<PathBuf>)> {
+        Config::find(&env::current_dir()?)
+    }
+
+    // Looks for `wasmcov.toml`, then for a Cargo.toml with wasmcov metadata,
+    // in the directory and each of its parents, so that the nearest config
+    // wins, e.g. the metadata of a package over the workspace `wasmcov.toml`.
+    pub fn find(dir: &Path) -> Result<(Config, Option<PathBuf>)> {
+        for dir in dir.ancestors() {
+            let path = dir.join(CONFIG_FILE);
+            if path.is_file() {
+                return Ok((Config::from_file(&path)?, Some(path)));
+            }
+            let path = dir.join("Cargo.toml");
+            if !path.is_file() {
+                continue;
+            }
+            if let Some(config) = Config::from_cargo_toml(&path)? {
+                return Ok((config, Some(path)));
+            }
+        }
+        Ok((Config::default(), None))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let content = fs::read_to_string(path)?;
+        let config: Config =
+            toml::from_str(&content).map_err(|err| error!("Invalid {:?}: {}", path, err))?;
+        Ok(config.relative_to(path))
+    }
+
+    pub fn from_cargo_toml(path: &Path) -> Result<Option<Config>> {
+        let content = fs::read_to_string(path)?;
+        let manifest: toml::Table =
+            toml::from_str(&content).map_err(|err| error!("Invalid {:?}: {}", path, err))?;
+        let metadata = ["package", "workspace"].into_iter().find_map(|table| {
+            manifest
+                .get(table)?
+                .get("metadata")?
+                .get("wasmcov")
+                .cloned()
+        });
+        let Some(metadata) = metadata else {
+            return Ok(None);
+        };
+        let config: Config = metadata
+            .try_into()
+            .map_err(|err| error!("Invalid wasmcov metadata in {:?}: {}", path, err))?;
+        Ok(Some(config.relative_to(path)))
+    }
+
+    // Relative paths are relative to the directory of the config file.
+    fn relative_to(mut self, config_path: &Path) -> Config {
+        if let Some(config_dir) = config_path.parent() {
+            for path in [
+                &mut self.wasmcov_dir,
+                &mut self.near_binary,
+                &mut self.nearcore_src,
+            ] {
+                if let Some(relative) = path.take() {
+                    *path = Some(config_dir.join(relative));
+                }
+            }
+        }
+        self
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).map_err(|err| error!("Failed to serialize the config: {}", err))
+    }
+}
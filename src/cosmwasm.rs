@@ -0,0 +1,40 @@
+// Memory region as used by cosmwasm-std to pass data between a contract and the VM.
+#[repr(C)]
+struct Region {
+    offset: u32,
+    capacity: u32,
+    length: u32,
+}
+
+// Captures the coverage and returns a pointer to a region holding
+// `[name length: u32 LE][name][profraw data]`. The patched cosmwasm-vm reads
+// it after each call and releases it with the contract's `deallocate` export.
+pub fn capture_coverage_region(binary_name: &str) -> u32 {
+    let mut data = Vec::new();
+    data.extend_from_slice(&(binary_name.len() as u32).to_le_bytes());
+    data.extend_from_slice(binary_name.as_bytes());
+    unsafe {
+        minicov::capture_coverage(&mut data).unwrap();
+    }
+    minicov::reset_coverage();
+
+    let data = std::mem::ManuallyDrop::new(data);
+    let region = Box::new(Region {
+        offset: data.as_ptr() as u32,
+        capacity: data.capacity() as u32,
+        length: data.len() as u32,
+    });
+    Box::into_raw(region) as u32
+}
+
+#[macro_export]
+macro_rules! add_cosmwasm_coverage {
+    ($($variant:literal)?) => {
+        #[no_mangle]
+        pub extern "C" fn capture_coverage() -> u32 {
+            $crate::cosmwasm::capture_coverage_region(&$crate::binary_name!($($variant)?))
+        }
+    };
+}
+
+pub use add_cosmwasm_coverage as add_coverage;
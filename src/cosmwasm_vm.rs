@@ -0,0 +1,144 @@
+use crate::attribution;
+use crate::error::{Error, Result};
+use crate::utils::{find_file, modify_file, path_to_str, run_command, FileOperation};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// libtest runs every test in a thread named after the test, so the coverage of
+// each call is attributed to the test making it, also when tests run in parallel.
+const TEST_NAME: &str =
+    "std::thread::current().name().filter(|name| *name != \"main\").map(String::from)";
+
+// Call added by the patch, used to recognize patched files.
+const CAPTURE_CALL: &str = "instance.call_function1(\"capture_coverage\", &[])";
+
+// Captures the coverage after every call of a contract export. Files which
+// are already patched are left unchanged.
+pub fn modify_calls(file_path: PathBuf) -> std::io::Result<()> {
+    if fs::read_to_string(&file_path)?.contains(CAPTURE_CALL) {
+        return Ok(());
+    }
+    let operation =
+        FileOperation::AddAfter {
+            pattern: String::from("instance.deallocate(res_region_ptr)?;"),
+            new_line: [
+                "
+    if let Some(wasmcov_dir) = std::env::var_os(\"WASMCOV_DIR\").filter(|_| name != \"capture_coverage\") {
+        if let Ok(result) = ",
+                CAPTURE_CALL,
+                " {
+            let coverage = ref_to_u32(&result).and_then(|region_ptr| {
+                let data = instance.read_memory(region_ptr, usize::MAX)?;
+                instance.deallocate(region_ptr)?;
+                Ok(data)
+            });
+            if let Ok(coverage) = coverage {
+                // [name length: u32 LE][name][profraw data], see wasmcov::cosmwasm
+                let invalid = |message: &str| crate::VmError::generic_err(format!(\"Invalid coverage data: {message}\"));
+                let name_len = match coverage.get(..4) {
+                    Some(&[a, b, c, d]) => u32::from_le_bytes([a, b, c, d]) as usize,
+                    _ => return Err(invalid(\"missing name length\")),
+                };
+                let name_end = 4usize.saturating_add(name_len);
+                let file = coverage.get(4..name_end).ok_or_else(|| invalid(\"name out of bounds\"))?;
+                let file = std::str::from_utf8(file).map_err(|_| invalid(\"name is not UTF-8\"))?;
+                if matches!(file, \"\" | \".\" | \"..\") || file.contains(['/', '\\\\']) {
+                    return Err(invalid(\"name is not a file name\"));
+                }
+                let coverage = &coverage[name_end..];
+
+                let wasmcov_dir = std::path::PathBuf::from(wasmcov_dir);
+                let profraw_directory = wasmcov_dir.join(\"profraw\").join(file);
+                let _ = std::fs::create_dir_all(&profraw_directory); // it may fail if multiple threads are trying to create the same directory
+                let coverage_hash = hex::encode(<sha2::Sha256 as sha2::Digest>::digest(coverage));
+                std::fs::write(profraw_directory.join(format!(\"{coverage_hash}.profraw\")), coverage).expect(\"Failed to write coverage file\");",
+                &attribution::record_test_source(TEST_NAME),
+                "
+            }
+        }
+    }",
+            ]
+            .concat(),
+        };
+    modify_file(file_path, operation)
+}
+
+pub fn add_wasmcov_to_cosmwasm_vm(cosmwasm_vm_dir: &Path) -> Result<()> {
+    let calls_path = find_file(cosmwasm_vm_dir, &["src/calls.rs"])?;
+    modify_calls(calls_path).map_err(|err| Error::PatchFailed {
+        file: "src/calls.rs".to_string(),
+        message: err.to_string(),
+    })?;
+    Ok(())
+}
+
+// Downloads the cosmwasm-vm crate and patches it to capture the coverage of
+// the contract after every call. Returns the path of the patched crate, to be
+// used as `[patch.crates-io]` of the tested project.
+pub fn setup_cosmwasm_vm(dir: PathBuf, version: String) -> Result<PathBuf> {
+    let major = version
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .parse::<u32>()
+        .unwrap_or_default();
+    if !(1..=2).contains(&major) {
+        return Err(Error::VersionMismatch {
+            tool: "cosmwasm-vm".to_string(),
+            expected: "1.x or 2.x".to_string(),
+            found: version.to_string(),
+        });
+    }
+
+    if !dir.exists() {
+        fs::create_dir_all(&dir)?;
+    }
+
+    let cosmwasm_vm_dir = dir.join(format!("cosmwasm-vm-{}", version));
+    // If the patched crate already exists, return its path
+    if cosmwasm_vm_dir.exists() {
+        return Ok(cosmwasm_vm_dir);
+    }
+
+    println!("Downloading cosmwasm-vm version {}", version);
+    let archive = dir.join(format!("cosmwasm-vm-{}.crate", version));
+    run_command(
+        "curl",
+        &[
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--location",
+            &format!(
+                "https://static.crates.io/crates/cosmwasm-vm/cosmwasm-vm-{}.crate",
+                version
+            ),
+            "--output",
+            path_to_str(&archive)?,
+        ],
+        None,
+    )?;
+    run_command(
+        "tar",
+        &["xzf", path_to_str(&archive)?, "-C", path_to_str(&dir)?],
+        None,
+    )?;
+    fs::remove_file(&archive)?;
+
+    if let Err(err) = add_wasmcov_to_cosmwasm_vm(&cosmwasm_vm_dir) {
+        fs::remove_dir_all(&cosmwasm_vm_dir)?;
+        return Err(err);
+    }
+    Ok(cosmwasm_vm_dir)
+}
+
+// Cargo arguments replacing cosmwasm-vm with the patched crate.
+pub fn cargo_patch_args(cosmwasm_vm_dir: &Path) -> Vec<String> {
+    vec![
+        "--config".to_string(),
+        format!(
+            "patch.crates-io.cosmwasm-vm.path={:?}",
+            cosmwasm_vm_dir.to_string_lossy()
+        ),
+    ]
+}
@@ -0,0 +1,464 @@
+use crate::error::Result;
+use crate::report::{self, CoverageReport, SummaryEntry};
+use crate::utils::run_command;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default)]
+pub struct FileSnapshot {
+    pub lines: SummaryEntry,
+    pub regions: SummaryEntry,
+    // line -> hits
+    pub line_hits: BTreeMap<u64, u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FunctionSnapshot {
+    pub file: String,
+    pub count: u64,
+    pub regions: SummaryEntry,
+}
+
+// Coverage of a single contract at one point in time, e.g. on the main branch.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub lines: SummaryEntry,
+    pub regions: SummaryEntry,
+    pub files: BTreeMap<String, FileSnapshot>,
+    pub functions: BTreeMap<String, FunctionSnapshot>,
+}
+
+impl Snapshot {
+    pub fn load(
+        profdata_path: &Path,
+        object_file: &Path,
+        llvm_cov_args: &[String],
+    ) -> Result<Self> {
+        let report = report::load_with_args(profdata_path, object_file, llvm_cov_args)?;
+        Ok(Self::from_report(&report))
+    }
+
+    pub fn from_report(report: &CoverageReport) -> Self {
+        let files = report
+            .files
+            .iter()
+            .map(|file| {
+                (
+                    file.filename.clone(),
+                    FileSnapshot {
+                        lines: file.summary.lines,
+                        regions: file.summary.regions,
+                        line_hits: file.lines.clone(),
+                    },
+                )
+            })
+            .collect();
+        let functions = report
+            .functions
+            .iter()
+            .map(|function| {
+                (
+                    function.name.clone(),
+                    FunctionSnapshot {
+                        file: function.filenames.first().cloned().unwrap_or_default(),
+                        count: function.count,
+                        regions: function.region_summary(),
+                    },
+                )
+            })
+            .collect();
+        Snapshot {
+            lines: report.totals.lines,
+            regions: report.totals.regions,
+            files,
+            functions,
+        }
+    }
+}
+
+// Changed lines per absolute file path.
+pub type ChangedLines = BTreeMap<PathBuf, BTreeSet<u64>>;
+
+// Lines added or modified in the working tree compared to the git revision.
+pub fn changed_lines_since(revision: &str) -> Result<ChangedLines> {
+    let root = PathBuf::from(run_command("git", &["rev-parse", "--show-toplevel"], None)?.trim());
+    let output = run_command(
+        "git",
+        &["diff", "-U0", "--no-color", "--no-ext-diff", revision],
+        Some(&root),
+    )?;
+    Ok(parse_changed_lines(&root, &output))
+}
+
+// Parses the hunks of a `git diff -U0` output. Lines of renamed files are
+// attributed to the new path, deleted files and hunks which only remove lines
+// have no changed lines.
+pub fn parse_changed_lines(root: &Path, diff: &str) -> ChangedLines {
+    let mut changed = ChangedLines::new();
+    let mut current_file = None;
+    for line in diff.lines() {
+        if let Some(path) = line.strip_prefix("+++ ") {
+            current_file = path.strip_prefix("b/").map(|path| root.join(path));
+        } else if let Some(hunk) = line.strip_prefix("@@ ") {
+            let Some(file) = &current_file else {
+                continue;
+            };
+            // @@ -old_start[,old_count] +new_start[,new_count] @@
+            let Some(added) = hunk.split(' ').find_map(|part| part.strip_prefix('+')) else {
+                continue;
+            };
+            let (start, count) = match added.split_once(',') {
+                Some((start, count)) => (start.parse().unwrap_or(0), count.parse().unwrap_or(0)),
+                None => (added.parse().unwrap_or(0), 1),
+            };
+            if count > 0 {
+                changed
+                    .entry(file.clone())
+                    .or_default()
+                    .extend(start..start + count);
+            }
+        }
+    }
+    changed
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Change {
+    pub baseline: Option<SummaryEntry>,
+    pub current: Option<SummaryEntry>,
+}
+
+impl Change {
+    pub fn is_changed(&self) -> bool {
+        match (self.baseline, self.current) {
+            (Some(baseline), Some(current)) => baseline.percent() != current.percent(),
+            (None, None) => false,
+            _ => true,
+        }
+    }
+
+    pub fn delta(&self) -> Option<f64> {
+        Some(self.current?.percent() - self.baseline?.percent())
+    }
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.baseline, self.current) {
+            (Some(baseline), Some(current)) => write!(
+                f,
+                "{:.2}% -> {:.2}% ({:+.2})",
+                baseline.percent(),
+                current.percent(),
+                current.percent() - baseline.percent()
+            ),
+            (None, Some(current)) => write!(f, "new, {:.2}%", current.percent()),
+            (Some(baseline), None) => write!(f, "removed, was {:.2}%", baseline.percent()),
+            (None, None) => write!(f, "-"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileDiff {
+    pub path: String,
+    pub lines: Change,
+    pub regions: Change,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionDiff {
+    pub name: String,
+    pub file: String,
+    pub regions: Change,
+}
+
+#[derive(Debug, Clone)]
+pub struct CoverageDiff {
+    pub contract: String,
+    pub lines: Change,
+    pub regions: Change,
+    pub files: Vec<FileDiff>,
+    pub functions: Vec<FunctionDiff>,
+    // path -> lines which are not covered in the current snapshot
+    pub uncovered_lines: BTreeMap<String, Vec<u64>>,
+}
+
+// Compares two snapshots of a contract, `None` when the contract does not
+// exist on that side. Newly uncovered lines are uncovered lines in the changed
+// code when `changed_lines` is given, otherwise uncovered lines that were
+// covered or did not exist in the baseline.
+pub fn diff(
+    contract: &str,
+    baseline: Option<&Snapshot>,
+    current: Option<&Snapshot>,
+    changed_lines: Option<&ChangedLines>,
+) -> CoverageDiff {
+    let empty = Snapshot::default();
+    let lines = Change {
+        baseline: baseline.map(|snapshot| snapshot.lines),
+        current: current.map(|snapshot| snapshot.lines),
+    };
+    let regions = Change {
+        baseline: baseline.map(|snapshot| snapshot.regions),
+        current: current.map(|snapshot| snapshot.regions),
+    };
+    let baseline = baseline.unwrap_or(&empty);
+    let current = current.unwrap_or(&empty);
+
+    let paths: BTreeSet<&String> = baseline.files.keys().chain(current.files.keys()).collect();
+    let files = paths
+        .into_iter()
+        .map(|path| FileDiff {
+            path: path.clone(),
+            lines: Change {
+                baseline: baseline.files.get(path).map(|file| file.lines),
+                current: current.files.get(path).map(|file| file.lines),
+            },
+            regions: Change {
+                baseline: baseline.files.get(path).map(|file| file.regions),
+                current: current.files.get(path).map(|file| file.regions),
+            },
+        })
+        .filter(|file| file.lines.is_changed() || file.regions.is_changed())
+        .collect();
+
+    let names: BTreeSet<&String> = baseline
+        .functions
+        .keys()
+        .chain(current.functions.keys())
+        .collect();
+    let functions = names
+        .into_iter()
+        .map(|name| FunctionDiff {
+            name: name.clone(),
+            file: current
+                .functions
+                .get(name)
+                .or_else(|| baseline.functions.get(name))
+                .map(|function| function.file.clone())
+                .unwrap_or_default(),
+            regions: Change {
+                baseline: baseline
+                    .functions
+                    .get(name)
+                    .map(|function| function.regions),
+                current: current.functions.get(name).map(|function| function.regions),
+            },
+        })
+        .filter(|function| function.regions.is_changed())
+        .collect();
+
+    let mut uncovered_lines = BTreeMap::new();
+    for (path, file) in &current.files {
+        let baseline_file = baseline.files.get(path);
+        let changed = changed_lines.map(|changed_lines| changed_lines.get(Path::new(path)));
+        let lines: Vec<u64> = file
+            .line_hits
+            .iter()
+            .filter(|(_, hits)| **hits == 0)
+            .map(|(line, _)| *line)
+            .filter(|line| match changed {
+                Some(changed) => changed.is_some_and(|changed| changed.contains(line)),
+                None => baseline_file
+                    .and_then(|file| file.line_hits.get(line))
+                    .is_none_or(|hits| *hits > 0),
+            })
+            .collect();
+        if !lines.is_empty() {
+            uncovered_lines.insert(path.clone(), lines);
+        }
+    }
+
+    CoverageDiff {
+        contract: contract.to_string(),
+        lines,
+        regions,
+        files,
+        functions,
+        uncovered_lines,
+    }
+}
+
+// Paths are shown relative to the current directory when possible.
+fn display_path(path: &str) -> String {
+    env::current_dir()
+        .ok()
+        .and_then(|dir| {
+            Path::new(path)
+                .strip_prefix(dir)
+                .ok()
+                .map(Path::to_path_buf)
+        })
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+fn display_function(name: &str) -> String {
+    format!("{:#}", rustc_demangle::demangle(name))
+}
+
+// Formats lines as ranges, e.g. "3-5, 9".
+fn display_lines(lines: &[u64]) -> String {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for &line in lines {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == line => *end = line,
+            _ => ranges.push((line, line)),
+        }
+    }
+    ranges
+        .into_iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{start}-{end}")
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn trend(change: &Change) -> &'static str {
+    match change.delta() {
+        Some(delta) if delta > 0.0 => "up",
+        Some(delta) if delta < 0.0 => "down",
+        Some(_) => "",
+        None if change.current.is_some() => "new",
+        None => "removed",
+    }
+}
+
+struct Table {
+    title: &'static str,
+    headers: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+impl CoverageDiff {
+    fn tables(&self) -> Vec<Table> {
+        let mut tables = vec![Table {
+            title: "Total",
+            headers: vec!["Lines", "Regions"],
+            rows: vec![vec![self.lines.to_string(), self.regions.to_string()]],
+        }];
+        if !self.files.is_empty() {
+            tables.push(Table {
+                title: "Files",
+                headers: vec!["File", "Lines", "Regions", ""],
+                rows: self
+                    .files
+                    .iter()
+                    .map(|file| {
+                        vec![
+                            display_path(&file.path),
+                            file.lines.to_string(),
+                            file.regions.to_string(),
+                            match trend(&file.lines) {
+                                "" => trend(&file.regions),
+                                trend => trend,
+                            }
+                            .to_string(),
+                        ]
+                    })
+                    .collect(),
+            });
+        }
+        if !self.functions.is_empty() {
+            tables.push(Table {
+                title: "Functions",
+                headers: vec!["Function", "File", "Regions", ""],
+                rows: self
+                    .functions
+                    .iter()
+                    .map(|function| {
+                        vec![
+                            display_function(&function.name),
+                            display_path(&function.file),
+                            function.regions.to_string(),
+                            trend(&function.regions).to_string(),
+                        ]
+                    })
+                    .collect(),
+            });
+        }
+        if !self.uncovered_lines.is_empty() {
+            tables.push(Table {
+                title: "Newly uncovered lines",
+                headers: vec!["File", "Lines"],
+                rows: self
+                    .uncovered_lines
+                    .iter()
+                    .map(|(path, lines)| vec![display_path(path), display_lines(lines)])
+                    .collect(),
+            });
+        }
+        tables
+    }
+
+    pub fn to_table(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "Coverage diff for {}", self.contract);
+        for Table {
+            title,
+            headers,
+            rows,
+        } in self.tables()
+        {
+            let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+            for row in &rows {
+                for (width, cell) in widths.iter_mut().zip(row) {
+                    *width = (*width).max(cell.chars().count());
+                }
+            }
+            let format_row = |cells: Vec<String>| {
+                cells
+                    .iter()
+                    .zip(&widths)
+                    .map(|(cell, width)| format!("{cell:<width$}"))
+                    .collect::<Vec<String>>()
+                    .join("  ")
+                    .trim_end()
+                    .to_string()
+            };
+            let _ = writeln!(out, "\n{title}");
+            let _ = writeln!(
+                out,
+                "{}",
+                format_row(headers.iter().map(|header| header.to_string()).collect())
+            );
+            let _ = writeln!(
+                out,
+                "{}",
+                "-".repeat(widths.iter().sum::<usize>() + 2 * (widths.len() - 1))
+            );
+            for row in rows {
+                let _ = writeln!(out, "{}", format_row(row));
+            }
+        }
+        out
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "## Coverage diff for `{}`", self.contract);
+        for Table {
+            title,
+            headers,
+            rows,
+        } in self.tables()
+        {
+            let _ = writeln!(out, "\n### {title}\n");
+            let _ = writeln!(out, "| {} |", headers.join(" | "));
+            let _ = writeln!(out, "|{}", "---|".repeat(headers.len()));
+            for row in rows {
+                let cells: Vec<String> = row.iter().map(|cell| cell.replace('|', "\\|")).collect();
+                let _ = writeln!(out, "| {} |", cells.join(" | "));
+            }
+        }
+        out
+    }
+}
@@ -1,93 +1,257 @@
-use anyhow::Ok;
-use anyhow::Result;
+use crate::error::{error, Error, Result};
 use std::env;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-// Sets the WASMCOV_DIR environment variable to the wasmcov directory.
-pub fn set_wasmcov_dir(wasmcov_dir: Option<&PathBuf>) -> PathBuf {
-    let default_directory = &env::current_dir().unwrap().join("wasmcov");
-    let coverage_directory = wasmcov_dir.unwrap_or(default_directory);
+// Separates the crate name from the variant label in binary names, e.g.
+// `my-contract@test-utils`.
+pub const VARIANT_SEPARATOR: char = '@';
 
-    env::set_var("WASMCOV_DIR", coverage_directory);
-    if !coverage_directory.exists() {
-        fs::create_dir_all(coverage_directory).expect("Failed to create coverage directory");
-    }
+// Set by `cargo wasmcov build --variant` and read by the capture macros at
+// compile time.
+pub const VARIANT_ENV: &str = "WASMCOV_VARIANT";
 
-    coverage_directory.to_owned()
+// A build of a crate, optionally labelled with a variant when the crate is
+// built more than once, e.g. with different features. Profraw directories,
+// object files, profdata files and reports are keyed by it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BinaryKey {
+    pub crate_name: String,
+    pub variant: Option<String>,
 }
 
-// Get the coverage directory from the WASMCOV_DIR environment variable.
-pub fn get_wasmcov_dir() -> PathBuf {
-    let coverage_directory = env::var("WASMCOV_DIR")
-        .map(PathBuf::from)
-        .expect("WASMCOV_DIR is not set.");
+impl BinaryKey {
+    pub fn new(crate_name: &str, variant: Option<&str>) -> Result<Self> {
+        if let Some(variant) = variant {
+            check_variant(variant)?;
+        }
+        Ok(Self {
+            crate_name: crate_name.to_string(),
+            variant: variant.map(str::to_string),
+        })
+    }
 
-    if !coverage_directory.exists() {
-        panic!("WASMCOV_DIR {:?} does not exist.", coverage_directory);
+    // Parses a binary name as written by the capture macros.
+    pub fn parse(name: &str) -> Self {
+        match name.split_once(VARIANT_SEPARATOR) {
+            Some((crate_name, variant)) => Self {
+                crate_name: crate_name.to_string(),
+                variant: Some(variant.to_string()),
+            },
+            None => Self {
+                crate_name: name.to_string(),
+                variant: None,
+            },
+        }
     }
 
-    coverage_directory
+    // Name of the object and wasm files, cargo replaces dashes in crate names
+    // with underscores.
+    pub fn file_stem(&self) -> String {
+        Self {
+            crate_name: self.crate_name.replace('-', "_"),
+            variant: self.variant.clone(),
+        }
+        .to_string()
+    }
 }
 
-// Directory with the profraw files.
-pub fn get_profraw_dir() -> PathBuf {
-    let profraw_dir = get_wasmcov_dir().join("profraw");
+impl fmt::Display for BinaryKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.variant {
+            Some(variant) => write!(f, "{}{}{}", self.crate_name, VARIANT_SEPARATOR, variant),
+            None => write!(f, "{}", self.crate_name),
+        }
+    }
+}
 
-    if !profraw_dir.exists() {
-        fs::create_dir_all(&profraw_dir).expect("Failed to create profraw directory");
+// Variant labels are used in file names.
+pub fn check_variant(variant: &str) -> Result<()> {
+    if variant.is_empty()
+        || !variant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(error!(
+            "Invalid variant {:?}, only letters, digits, '-' and '_' are allowed",
+            variant
+        ));
     }
+    Ok(())
+}
 
-    profraw_dir
+// A wasmcov directory with the profraw, profdata, target and report
+// subdirectories. Passed explicitly to the build and report functions, so a
+// process can work with several coverage workspaces at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WasmcovDir {
+    root: PathBuf,
 }
 
-// Directory with the profdata files.
-pub fn get_profdata_dir() -> PathBuf {
-    let profdata_dir = get_wasmcov_dir().join("profdata");
-    if !profdata_dir.exists() {
-        fs::create_dir_all(&profdata_dir).expect("Failed to create profdata directory");
+impl WasmcovDir {
+    // Creates the directory if it does not exist.
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        if !root.exists() {
+            fs::create_dir_all(&root)?;
+        }
+        Ok(Self { root })
     }
-    profdata_dir
-}
 
-// Directory with the output files.
-pub fn get_target_dir() -> PathBuf {
-    let target_dir = get_wasmcov_dir().join("target");
-    if !target_dir.exists() {
-        fs::create_dir_all(&target_dir).expect("Failed to create target directory");
+    // An existing directory, e.g. the baseline of a coverage diff.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        if !root.exists() {
+            return Err(Error::FileNotFound(root));
+        }
+        Ok(Self { root })
     }
-    target_dir
-}
 
-// Directory with the report files.
-pub fn get_report_dir() -> PathBuf {
-    let report_dir = get_wasmcov_dir().join("report");
-    if !report_dir.exists() {
-        fs::create_dir_all(&report_dir).expect("Failed to create report directory");
+    // The directory from the WASMCOV_DIR environment variable, which has to
+    // exist. Used by the processes wasmcov runs, e.g. hosts saving profraw files.
+    pub fn from_env() -> Result<Self> {
+        let root = env::var("WASMCOV_DIR").map_err(|_| error!("WASMCOV_DIR is not set."))?;
+        Self::open(root)
     }
-    report_dir
-}
 
-// Remove "profdata", "profraw", "report" directories from coverage directory
-// or clear all files and directories from coverage directory when full is true.
-pub fn clean_wasmcov_directory(full: bool) -> Result<()> {
-    let coverage_dir = get_wasmcov_dir();
-
-    if full {
-        // Remove all files and directories from coverage_directory
-        fs::remove_dir_all(&coverage_dir)?;
-        fs::create_dir_all(&coverage_dir)?;
-    } else {
-        // Remove only profdata, report, and profraw
-        let dirs_to_clear = vec![get_profdata_dir(), get_report_dir(), get_profraw_dir()];
-
-        for dir in dirs_to_clear {
-            if dir.exists() {
-                fs::remove_dir_all(&dir)?;
-                fs::create_dir_all(&dir)?;
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    fn subdir(&self, name: &str) -> Result<PathBuf> {
+        let dir = self.root.join(name);
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+        }
+        Ok(dir)
+    }
+
+    // Directory with the profraw files, one subdirectory per binary.
+    pub fn profraw(&self) -> Result<PathBuf> {
+        self.subdir("profraw")
+    }
+
+    // Directory with the profdata files.
+    pub fn profdata(&self) -> Result<PathBuf> {
+        self.subdir("profdata")
+    }
+
+    // Cargo target directory of the builds, also holding the object files.
+    pub fn target(&self) -> Result<PathBuf> {
+        self.subdir("target")
+    }
+
+    // Directory with the report files.
+    pub fn report(&self) -> Result<PathBuf> {
+        self.subdir("report")
+    }
+
+    // `profraw/<crate>[@<variant>]`, written by the hosts.
+    pub fn profraw_dir_of(&self, key: &BinaryKey) -> Result<PathBuf> {
+        Ok(self.profraw()?.join(key.to_string()))
+    }
+
+    // `target/<crate_name>[@<variant>].o`, with dashes in the crate name
+    // replaced by underscores.
+    pub fn object_file_of(&self, key: &BinaryKey) -> Result<PathBuf> {
+        Ok(self.target()?.join(format!("{}.o", key.file_stem())))
+    }
+
+    // `profdata/<crate>[@<variant>].profdata`
+    pub fn profdata_file_of(&self, key: &BinaryKey) -> Result<PathBuf> {
+        Ok(self.profdata()?.join(format!("{}.profdata", key)))
+    }
+
+    // Directory shared by all projects with artifacts which survive `clean --all`,
+    // e.g. patched neard binaries. WASMCOV_CACHE_DIR takes precedence over
+    // `$XDG_CACHE_HOME/wasmcov` and `~/.cache/wasmcov`, `cache` in this
+    // directory is used when none of them is set.
+    pub fn cache(&self) -> Result<PathBuf> {
+        create_cache_dir(shared_cache_dir().unwrap_or_else(|| self.root.join("cache")))
+    }
+
+    // Remove "profdata", "profraw", "report" directories from coverage directory
+    // or clear all files and directories from coverage directory when full is true.
+    pub fn clean(&self, full: bool) -> Result<()> {
+        if full {
+            // Remove all files and directories from coverage_directory
+            fs::remove_dir_all(&self.root)?;
+            fs::create_dir_all(&self.root)?;
+        } else {
+            // Remove only profdata, report, and profraw
+            let dirs_to_clear = vec![self.profdata()?, self.report()?, self.profraw()?];
+
+            for dir in dirs_to_clear {
+                if dir.exists() {
+                    fs::remove_dir_all(&dir)?;
+                    fs::create_dir_all(&dir)?;
+                }
             }
         }
+
+        Ok(())
     }
+}
 
-    Ok(())
+fn shared_cache_dir() -> Option<PathBuf> {
+    env::var_os("WASMCOV_CACHE_DIR")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("XDG_CACHE_HOME").map(|dir| PathBuf::from(dir).join("wasmcov")))
+        .or_else(|| {
+            env::var_os("HOME").map(|dir| PathBuf::from(dir).join(".cache").join("wasmcov"))
+        })
+}
+
+fn create_cache_dir(cache_dir: PathBuf) -> Result<PathBuf> {
+    if !cache_dir.exists() {
+        fs::create_dir_all(&cache_dir)?;
+    }
+    Ok(cache_dir)
+}
+
+// The functions below use the directory from the WASMCOV_DIR environment
+// variable, they are kept for compatibility.
+
+// Sets the WASMCOV_DIR environment variable to the wasmcov directory.
+pub fn set_wasmcov_dir(wasmcov_dir: Option<&PathBuf>) -> Result<PathBuf> {
+    let coverage_directory = match wasmcov_dir {
+        Some(dir) => dir.clone(),
+        None => env::current_dir()?.join("wasmcov"),
+    };
+
+    env::set_var("WASMCOV_DIR", &coverage_directory);
+    Ok(WasmcovDir::new(coverage_directory)?.root)
+}
+
+// Get the coverage directory from the WASMCOV_DIR environment variable.
+pub fn get_wasmcov_dir() -> Result<PathBuf> {
+    Ok(WasmcovDir::from_env()?.root)
+}
+
+pub fn get_cache_dir() -> Result<PathBuf> {
+    match shared_cache_dir() {
+        Some(cache_dir) => create_cache_dir(cache_dir),
+        None => WasmcovDir::from_env()?.cache(),
+    }
+}
+
+pub fn get_profraw_dir() -> Result<PathBuf> {
+    WasmcovDir::from_env()?.profraw()
+}
+
+pub fn get_profdata_dir() -> Result<PathBuf> {
+    WasmcovDir::from_env()?.profdata()
+}
+
+pub fn get_target_dir() -> Result<PathBuf> {
+    WasmcovDir::from_env()?.target()
+}
+
+pub fn get_report_dir() -> Result<PathBuf> {
+    WasmcovDir::from_env()?.report()
+}
+
+pub fn clean_wasmcov_directory(full: bool) -> Result<()> {
+    WasmcovDir::from_env()?.clean(full)
 }
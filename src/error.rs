@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+
+// Errors returned by the wasmcov library. Library functions never exit the
+// process or panic on failure, printing the error and choosing the exit code
+// is left to the caller.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    // A required tool, e.g. clang, llvm-cov or git, could not be found.
+    #[error("Failed to find {tool}: {message}")]
+    ToolingMissing { tool: String, message: String },
+
+    // A tool or source tree has a version wasmcov does not work with.
+    #[error("Found {tool} version {found}, but expected {expected}")]
+    VersionMismatch {
+        tool: String,
+        expected: String,
+        found: String,
+    },
+
+    #[error("{0:?} does not exist")]
+    FileNotFound(PathBuf),
+
+    // Patching nearcore or cosmwasm-vm failed.
+    #[error("Failed to patch {file}: {message}")]
+    PatchFailed { file: String, message: String },
+
+    // The command ran and exited with a non-zero status. `status` is None
+    // when the process was killed by a signal, `stderr` is empty when it was
+    // not captured.
+    #[error(
+        "Command \"{command}\" failed with status code {}{}",
+        status.unwrap_or(-1),
+        if stderr.is_empty() { String::new() } else { format!(": {stderr}") }
+    )]
+    CommandFailed {
+        command: String,
+        status: Option<i32>,
+        stderr: String,
+    },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Pattern(#[from] glob::PatternError),
+
+    #[error(transparent)]
+    Glob(#[from] glob::GlobError),
+
+    #[error(transparent)]
+    Utf8(#[from] std::string::FromUtf8Error),
+
+    #[cfg(feature = "wasmtime_host")]
+    #[error(transparent)]
+    Wasmtime(#[from] wasmtime::Error),
+
+    #[error("{0}")]
+    Other(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Builds an `Error::Other` from a format string, the library counterpart of
+// `anyhow!`.
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::error::Error::Other(format!($($arg)*))
+    };
+}
+
+pub(crate) use error;
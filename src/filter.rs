@@ -0,0 +1,115 @@
+use crate::error::{error, Result};
+use crate::report;
+use glob::{MatchOptions, Pattern};
+use std::env;
+use std::path::Path;
+
+// Sources hidden from reports unless default excludes are disabled: crates
+// from the cargo registry and git checkouts, and the standard library.
+pub const DEFAULT_EXCLUDES: [&str; 4] = [
+    "**/.cargo/registry/**",
+    "**/.cargo/git/**",
+    "**/.rustup/toolchains/**",
+    "/rustc/*/library/**",
+];
+
+// Include and exclude globs for source paths shown in reports. Patterns are
+// matched against the absolute path and the path relative to the current
+// directory, so both `src/**` and `/home/user/project/src/**` work.
+#[derive(Debug, Clone)]
+pub struct SourceFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl SourceFilter {
+    pub fn new(include: &[String], exclude: &[String], default_excludes: bool) -> Result<Self> {
+        let compile = |patterns: &mut dyn Iterator<Item = &str>| {
+            patterns
+                .map(|pattern| {
+                    Pattern::new(pattern)
+                        .map_err(|err| error!("Invalid source filter {:?}: {}", pattern, err))
+                })
+                .collect::<Result<Vec<Pattern>>>()
+        };
+        let defaults = if default_excludes {
+            &DEFAULT_EXCLUDES[..]
+        } else {
+            &[]
+        };
+        Ok(SourceFilter {
+            include: compile(&mut include.iter().map(String::as_str))?,
+            exclude: compile(
+                &mut exclude
+                    .iter()
+                    .map(String::as_str)
+                    .chain(defaults.iter().copied()),
+            )?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    // A file is kept when it matches any include pattern (or there are none)
+    // and no exclude pattern.
+    pub fn matches(&self, path: &str) -> bool {
+        let relative = env::current_dir().ok().and_then(|dir| {
+            Path::new(path)
+                .strip_prefix(dir)
+                .ok()
+                .map(Path::to_path_buf)
+        });
+        // `*` stays within a path component, `**` crosses them
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        let matches_any = |patterns: &[Pattern]| {
+            patterns.iter().any(|pattern| {
+                pattern.matches_with(path, options)
+                    || relative
+                        .as_deref()
+                        .is_some_and(|relative| pattern.matches_path_with(relative, options))
+            })
+        };
+        (self.include.is_empty() || matches_any(&self.include)) && !matches_any(&self.exclude)
+    }
+
+    // Appends an `-ignore-filename-regex` argument hiding the files of the
+    // binary which do not pass the filter, so every llvm-cov command
+    // (reports, summaries, thresholds) sees the same set of files.
+    pub fn llvm_cov_args(
+        &self,
+        profdata_path: &Path,
+        object_file: &Path,
+        llvm_cov_args: &[String],
+    ) -> Result<Vec<String>> {
+        let mut args = llvm_cov_args.to_vec();
+        if self.is_empty() {
+            return Ok(args);
+        }
+
+        let json = report::export(
+            profdata_path,
+            object_file,
+            "text",
+            &["-summary-only"],
+            llvm_cov_args,
+        )?;
+        let json: serde_json::Value = serde_json::from_str(&json)?;
+        let ignored: Vec<String> = json["data"][0]["files"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|file| file["filename"].as_str())
+            .filter(|filename| !self.matches(filename))
+            .map(regex::escape)
+            .collect();
+        if !ignored.is_empty() {
+            args.push(format!("-ignore-filename-regex=^({})$", ignored.join("|")));
+        }
+        Ok(args)
+    }
+}
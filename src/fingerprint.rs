@@ -0,0 +1,90 @@
+use crate::error::{error, Result};
+use crate::profraw::{self, Profraw, Reader};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Size of the header of a function record in the covfun section: name hash,
+// data size, function hash and filenames hash.
+const COVFUN_RECORD_HEADER_SIZE: usize = 28;
+
+// Function hashes of a build of a binary, read from the coverage mapping when
+// its object file is extracted. Profraw files written by an older build have
+// different hashes for the changed functions, llvm-cov would silently ignore
+// or misattribute their counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    // name hash -> function hashes
+    functions: BTreeMap<u64, BTreeSet<u64>>,
+}
+
+impl Fingerprint {
+    // Reads the function records of the `__llvm_covfun` section, each aligned
+    // to 8 bytes.
+    pub fn from_covfun(covfun: &[u8]) -> Result<Self> {
+        let mut functions: BTreeMap<u64, BTreeSet<u64>> = BTreeMap::new();
+        let mut offset = 0;
+        while offset + COVFUN_RECORD_HEADER_SIZE <= covfun.len() {
+            let mut reader = Reader::at(covfun, offset);
+            let name_hash = reader.u64()?;
+            let data_size = reader.u32()? as usize;
+            let function_hash = reader.u64()?;
+            functions
+                .entry(name_hash)
+                .or_default()
+                .insert(function_hash);
+            offset = align8(offset + COVFUN_RECORD_HEADER_SIZE + data_size);
+        }
+        if functions.is_empty() {
+            return Err(error!("No function records found in the coverage mapping"));
+        }
+        Ok(Self { functions })
+    }
+
+    // Saved next to the object file as `<binary>.fingerprint`.
+    pub fn path(object_file: &Path) -> PathBuf {
+        object_file.with_extension("fingerprint")
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    // Changes whenever the build changes, recorded with merged profdata files.
+    pub fn digest(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        format!("{:x}", md5::compute(json))
+    }
+
+    // A profraw file matches when it has a function of this build and no
+    // function with a hash this build does not have.
+    pub fn matches(&self, profraw: &Profraw) -> bool {
+        let mut known = profraw
+            .records
+            .iter()
+            .filter_map(|record| {
+                self.functions
+                    .get(&record.name_hash)
+                    .map(|hashes| (record, hashes))
+            })
+            .peekable();
+        known.peek().is_some()
+            && known.all(|(record, hashes)| hashes.contains(&record.function_hash))
+    }
+
+    // Profraw files which cannot be parsed are not considered stale, merging
+    // them reports the error or falls back to llvm-profdata.
+    pub fn is_stale(&self, profraw_file: &Path) -> bool {
+        profraw::read_profraw(profraw_file).is_ok_and(|profraw| !self.matches(&profraw))
+    }
+}
+
+fn align8(value: usize) -> usize {
+    (value + 7) & !7
+}
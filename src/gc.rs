@@ -0,0 +1,167 @@
+use crate::attribution;
+use crate::dir::WasmcovDir;
+use crate::error::Result;
+use crate::fingerprint::Fingerprint;
+use crate::report::{self, MergeOptions};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// Rolling profdata in `profraw/<crate>` holding the counters of the profraw
+// files removed by `gc`. It is merged together with the remaining profraw files.
+pub const COLLECTED_PROFDATA: &str = "collected.profdata";
+
+// Digest of the fingerprint of the build the collected profdata was merged
+// for, saved next to it as `collected.fingerprint`.
+fn digest_path(profdata_path: &Path) -> PathBuf {
+    profdata_path.with_extension("fingerprint")
+}
+
+// The collected profdata of the crate was merged for a different build than
+// the fingerprinted one. Collected profdata without a recorded build is kept.
+pub fn is_collected_stale(crate_profraw_dir: &Path, fingerprint: &Fingerprint) -> bool {
+    fs::read_to_string(digest_path(&crate_profraw_dir.join(COLLECTED_PROFDATA)))
+        .is_ok_and(|digest| digest != fingerprint.digest())
+}
+
+// Moves the collected profdata and its digest to `profraw/<crate>/stale`,
+// returning its new path.
+pub fn quarantine_collected(crate_profraw_dir: &Path) -> Result<PathBuf> {
+    let profdata_path = crate_profraw_dir.join(COLLECTED_PROFDATA);
+    let stale_dir = crate_profraw_dir.join("stale");
+    fs::create_dir_all(&stale_dir)?;
+    let quarantined = stale_dir.join(COLLECTED_PROFDATA);
+    fs::rename(&profdata_path, &quarantined)?;
+    if digest_path(&profdata_path).exists() {
+        fs::rename(digest_path(&profdata_path), digest_path(&quarantined))?;
+    }
+    Ok(quarantined)
+}
+
+// Profraw files of a crate folded into its collected profdata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Collected {
+    pub name: String,
+    pub profdata_path: PathBuf,
+    pub profraw_files: usize,
+    // Size of the removed profraw files minus the growth of the collected profdata.
+    pub reclaimed_bytes: u64,
+}
+
+// Merges the profraw files of every crate which are older than `older_than`,
+// or all of them when not given, into `profraw/<crate>/collected.profdata` and
+// removes them together with their test records. Profraw files written by a
+// different build than the last one are left in place, and a collected
+// profdata of a different build is moved to `profraw/<crate>/stale`.
+pub fn collect(
+    wasmcov_dir: &WasmcovDir,
+    older_than: Option<Duration>,
+    options: &MergeOptions,
+) -> Result<Vec<Collected>> {
+    let cutoff = older_than.and_then(|age| SystemTime::now().checked_sub(age));
+    let mut collected = Vec::new();
+    for entry in fs::read_dir(wasmcov_dir.profraw()?)? {
+        let dir_path = entry?.path();
+        if !dir_path.is_dir() {
+            continue;
+        }
+        let name = dir_path.file_name().unwrap_or_default().to_string_lossy();
+        let fingerprint = report::load_fingerprint(wasmcov_dir, &name)?;
+        if let Some(crate_collected) =
+            collect_crate(&dir_path, cutoff, fingerprint.as_ref(), options)?
+        {
+            collected.push(crate_collected);
+        }
+    }
+    collected.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(collected)
+}
+
+fn collect_crate(
+    crate_profraw_dir: &Path,
+    cutoff: Option<SystemTime>,
+    fingerprint: Option<&Fingerprint>,
+    options: &MergeOptions,
+) -> Result<Option<Collected>> {
+    let mut profraw_files = Vec::new();
+    for entry in fs::read_dir(crate_profraw_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("profraw") {
+            continue;
+        }
+        if cutoff.is_some_and(|cutoff| {
+            entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|time| time > cutoff)
+        }) {
+            continue;
+        }
+        profraw_files.push(path);
+    }
+    if let Some(fingerprint) = fingerprint {
+        let (fresh, stale) = report::partition_stale(profraw_files, fingerprint, options.jobs)?;
+        if !stale.is_empty() {
+            eprintln!(
+                "Warning: {} profraw files in {:?} were written by a different build and are not collected",
+                stale.len(),
+                crate_profraw_dir
+            );
+        }
+        profraw_files = fresh;
+    }
+    if profraw_files.is_empty() {
+        return Ok(None);
+    }
+    profraw_files.sort();
+
+    let mut removed_bytes = 0;
+    for path in &profraw_files {
+        removed_bytes += fs::metadata(path)?.len();
+    }
+
+    let profdata_path = crate_profraw_dir.join(COLLECTED_PROFDATA);
+    if profdata_path.exists()
+        && fingerprint.is_some_and(|fingerprint| is_collected_stale(crate_profraw_dir, fingerprint))
+    {
+        let quarantined = quarantine_collected(crate_profraw_dir)?;
+        eprintln!(
+            "Warning: {:?} was collected for a different build and has been moved to {:?}",
+            profdata_path, quarantined
+        );
+    }
+    let previous_size = fs::metadata(&profdata_path).map_or(0, |metadata| metadata.len());
+    let base = profdata_path.exists().then_some(profdata_path.as_path());
+    report::merge_profraw_files_into(base, &profraw_files, &profdata_path, options)?;
+    // Without a fingerprint the build of the merged files is unknown.
+    match fingerprint {
+        Some(fingerprint) => fs::write(digest_path(&profdata_path), fingerprint.digest())?,
+        None if digest_path(&profdata_path).exists() => {
+            fs::remove_file(digest_path(&profdata_path))?
+        }
+        None => {}
+    }
+
+    let mut hashes = BTreeSet::new();
+    for path in &profraw_files {
+        fs::remove_file(path)?;
+        if let Some(hash) = path.file_stem() {
+            hashes.insert(hash.to_string_lossy().into_owned());
+        }
+    }
+    attribution::remove_test_records(crate_profraw_dir, &hashes)?;
+
+    let size = fs::metadata(&profdata_path)?.len();
+    Ok(Some(Collected {
+        name: crate_profraw_dir
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        profdata_path,
+        profraw_files: profraw_files.len(),
+        reclaimed_bytes: (removed_bytes + previous_size).saturating_sub(size),
+    }))
+}
@@ -1,18 +1,78 @@
 #[cfg(target_family = "wasm")]
 pub use minicov;
 #[cfg(target_family = "wasm")]
+pub mod bindgen;
+#[cfg(target_family = "wasm")]
+pub mod cosmwasm;
+#[cfg(target_family = "wasm")]
 pub mod near;
+#[cfg(target_os = "wasi")]
+pub mod wasi;
+
+#[cfg(not(target_family = "wasm"))]
+pub use error::{Error, Result};
+
+// Name the coverage of the current package is saved under: the package name,
+// followed by `@<variant>` when a variant label is given or the package is
+// built with `cargo wasmcov build --variant`. Used by the capture macros.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! binary_name {
+    () => {
+        match option_env!("WASMCOV_VARIANT") {
+            Some(variant) if !variant.is_empty() => {
+                format!("{}@{}", env!("CARGO_PKG_NAME"), variant)
+            }
+            _ => env!("CARGO_PKG_NAME").to_string(),
+        }
+    };
+    ($variant:literal) => {
+        concat!(env!("CARGO_PKG_NAME"), "@", $variant).to_string()
+    };
+}
 
+#[cfg(not(target_family = "wasm"))]
+pub mod attribution;
 #[cfg(not(target_family = "wasm"))]
 pub mod build;
 #[cfg(not(target_family = "wasm"))]
+pub mod cobertura;
+#[cfg(not(target_family = "wasm"))]
+pub mod config;
+#[cfg(not(target_family = "wasm"))]
+pub mod diff;
+#[cfg(not(target_family = "wasm"))]
 pub mod dir;
 #[cfg(not(target_family = "wasm"))]
+pub mod error;
+#[cfg(not(target_family = "wasm"))]
+pub mod filter;
+#[cfg(not(target_family = "wasm"))]
+pub mod fingerprint;
+#[cfg(not(target_family = "wasm"))]
+pub mod gc;
+#[cfg(not(target_family = "wasm"))]
 pub mod llvm;
 #[cfg(not(target_family = "wasm"))]
+pub mod object;
+#[cfg(not(target_family = "wasm"))]
+pub mod profdata;
+#[cfg(not(target_family = "wasm"))]
+pub mod profraw;
+#[cfg(not(target_family = "wasm"))]
 pub mod report;
 #[cfg(not(target_family = "wasm"))]
+pub mod threshold;
+#[cfg(not(target_family = "wasm"))]
 pub mod utils;
+#[cfg(not(target_family = "wasm"))]
+pub mod wasm;
 
+#[cfg(all(not(target_family = "wasm"), feature = "cosmwasm"))]
+pub mod cosmwasm_vm;
+#[cfg(all(not(target_family = "wasm"), feature = "near_local"))]
+pub mod near_local;
 #[cfg(all(not(target_family = "wasm"), feature = "near_sandbox"))]
 pub mod near_sandbox;
+#[cfg(all(not(target_family = "wasm"), feature = "wasmtime_host"))]
+pub mod wasmtime_host;
@@ -1,15 +1,15 @@
+use crate::error::{error, Error, Result};
 use crate::utils::run_command;
-use anyhow::{anyhow, Result};
 use regex::Regex;
-use std::sync::Once;
+use std::sync::OnceLock;
 
-static LLVM_TOOLING: Once = Once::new();
-static mut LLVM_TOOLING_RESULT: Option<LlvmToolingResult> = None;
+static LLVM_TOOLING: OnceLock<LlvmToolingResult> = OnceLock::new();
 pub fn get_tooling() -> Result<&'static LlvmToolingResult> {
-    LLVM_TOOLING.call_once(|| unsafe {
-        LLVM_TOOLING_RESULT = Some(find_tooling().expect("Failed to initialize LLVM tooling"));
-    });
-    unsafe { Ok(LLVM_TOOLING_RESULT.as_ref().unwrap()) }
+    if let Some(tooling) = LLVM_TOOLING.get() {
+        return Ok(tooling);
+    }
+    let tooling = find_tooling()?;
+    Ok(LLVM_TOOLING.get_or_init(|| tooling))
 }
 
 pub fn check_rustc_version() -> Result<(bool, String)> {
@@ -20,39 +20,37 @@ pub fn check_rustc_version() -> Result<(bool, String)> {
         .captures(&output_str)
         .and_then(|cap| cap.get(1).map(|m| m.as_str()))
         .map(String::from)
-        .ok_or(anyhow!("Failed to parse rustc output: {}", output_str))?;
+        .ok_or(error!("Failed to parse rustc output: {}", output_str))?;
     Ok((is_nightly, llvm_major_version))
 }
 
 pub fn check_llvm_tool_version(command: &str) -> Result<String> {
-    let output = run_command(&command, &["--version"], None)?;
+    let output = run_command(command, &["--version"], None)?;
     let llvm_major_version = Regex::new(r"version (\d+)")
         .unwrap()
         .captures(&output)
         .and_then(|cap| cap.get(1).map(|m| m.as_str()))
         .map(String::from)
-        .ok_or(anyhow!("Failed to parse {command} output:\n{output}"))?;
+        .ok_or(error!("Failed to parse {command} output:\n{output}"))?;
     Ok(llvm_major_version)
 }
 
 pub fn find_llvm_tool(tool: &str, major_version: &str) -> Result<String> {
-    let version = check_llvm_tool_version(&format!("{tool}-{major_version}"));
-    if version.is_err() {
-        let version = check_llvm_tool_version(&tool);
-        if version.is_err() {
-            return Err(anyhow!("Failed to find {tool}-{major_version}"));
-        }
-        let version = version.unwrap();
-        if version != major_version {
-            Err(anyhow!(
-                "Found {tool} version {version}, but expected {major_version}"
-            ))
-        } else {
-            Ok(tool.to_string())
-        }
-    } else {
-        Ok(format!("{tool}-{major_version}"))
+    if check_llvm_tool_version(&format!("{tool}-{major_version}")).is_ok() {
+        return Ok(format!("{tool}-{major_version}"));
+    }
+    let version = check_llvm_tool_version(tool).map_err(|err| Error::ToolingMissing {
+        tool: format!("{tool}-{major_version}"),
+        message: err.to_string(),
+    })?;
+    if version != major_version {
+        return Err(Error::VersionMismatch {
+            tool: tool.to_string(),
+            expected: major_version.to_string(),
+            found: version,
+        });
     }
+    Ok(tool.to_string())
 }
 
 pub struct LlvmToolingResult {
@@ -108,7 +106,7 @@ mod tests {
             .captures(RUSTC_OUTPUT)
             .and_then(|cap| cap.get(1).map(|m| m.as_str()))
             .map(String::from)
-            .ok_or(anyhow!("Failed to parse rustc output: {}", RUSTC_OUTPUT))?;
+            .ok_or(error!("Failed to parse rustc output: {}", RUSTC_OUTPUT))?;
 
         assert_eq!(result, (is_nightly, llvm_major_version));
         Ok(())
@@ -1,6 +1,6 @@
 #[macro_export]
 macro_rules! add_coverage {
-    () => {
+    ($($variant:literal)?) => {
         extern "C" {
             fn sandbox_capture_coverage(
                 binary_len: u64,
@@ -12,12 +12,12 @@ macro_rules! add_coverage {
 
         #[no_mangle]
         pub unsafe extern "C" fn capture_coverage() {
-            const BINARY_NAME: &str = env!("CARGO_PKG_NAME");
+            let binary_name = wasmcov::binary_name!($($variant)?);
             let mut coverage = vec![];
             wasmcov::minicov::capture_coverage(&mut coverage).unwrap();
             sandbox_capture_coverage(
-                BINARY_NAME.len() as u64,
-                BINARY_NAME.as_ptr() as u64,
+                binary_name.len() as u64,
+                binary_name.as_ptr() as u64,
                 coverage.len() as u64,
                 coverage.as_ptr() as u64,
             );
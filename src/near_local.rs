@@ -0,0 +1,489 @@
+use crate::dir::WasmcovDir;
+use crate::error::{error, Result};
+use crate::wasmtime_host;
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use wasmtime::{Caller, Engine, Linker, Memory, Module, Store};
+
+// Storage bytes NEAR charges for every key-value record on top of its length.
+const STORAGE_RECORD_EXTRA_BYTES: u64 = 40;
+
+// Environment of a function call, returned by the `near_sdk::env` getters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallContext {
+    pub current_account_id: String,
+    pub signer_account_id: String,
+    pub signer_account_pk: Vec<u8>,
+    pub predecessor_account_id: String,
+    pub attached_deposit: u128,
+    pub account_balance: u128,
+    pub account_locked_balance: u128,
+    pub block_height: u64,
+    pub block_timestamp: u64,
+    pub epoch_height: u64,
+    pub prepaid_gas: u64,
+    pub random_seed: Vec<u8>,
+}
+
+impl Default for CallContext {
+    fn default() -> Self {
+        Self {
+            current_account_id: "contract.near".to_string(),
+            signer_account_id: "alice.near".to_string(),
+            // ed25519 key type followed by a zeroed key
+            signer_account_pk: vec![0; 33],
+            predecessor_account_id: "alice.near".to_string(),
+            attached_deposit: 0,
+            account_balance: 100 * 10u128.pow(24),
+            account_locked_balance: 0,
+            block_height: 1,
+            block_timestamp: 0,
+            epoch_height: 1,
+            prepaid_gas: 300 * 10u64.pow(12),
+            random_seed: vec![0; 32],
+        }
+    }
+}
+
+// Result of a successful function call.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallOutcome {
+    pub return_data: Option<Vec<u8>>,
+    pub logs: Vec<String>,
+}
+
+impl CallOutcome {
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        let data = self
+            .return_data
+            .as_ref()
+            .ok_or_else(|| error!("Function did not return a value"))?;
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+struct HostState {
+    context: CallContext,
+    input: Vec<u8>,
+    storage: BTreeMap<Vec<u8>, Vec<u8>>,
+    registers: HashMap<u64, Vec<u8>>,
+    return_data: Option<Vec<u8>>,
+    logs: Vec<String>,
+    panic_message: Option<String>,
+    test_name: Option<String>,
+}
+
+// NEAR contract executed in process with wasmtime instead of a NEAR sandbox,
+// for unit-style tests of a single contract. Every call runs in a new
+// instance, like on chain, and the coverage is captured after each call
+// through `near::add_coverage!` and saved to the given wasmcov directory.
+// The coverage of a call is attributed to `test_name` when it is set.
+// Cross-contract calls (promises) and host functions not implemented here trap.
+pub struct Contract {
+    module: Module,
+    linker: Linker<HostState>,
+    pub storage: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub context: CallContext,
+    pub test_name: Option<String>,
+}
+
+impl Contract {
+    pub fn new(wasmcov_dir: &WasmcovDir, wasm: &[u8]) -> Result<Self> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm)?;
+        let mut linker = Linker::new(&engine);
+        wasmtime_host::add_to_linker_with_test_name(
+            &mut linker,
+            wasmcov_dir,
+            |state: &HostState| state.test_name.as_deref(),
+        )?;
+        add_near_imports(&mut linker)?;
+        linker.define_unknown_imports_as_traps(&module)?;
+        Ok(Self {
+            module,
+            linker,
+            storage: BTreeMap::new(),
+            context: CallContext::default(),
+            test_name: None,
+        })
+    }
+
+    pub fn from_file(wasmcov_dir: &WasmcovDir, path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(wasmcov_dir, &fs::read(path)?)
+    }
+
+    // Calls the exported function with the given input. When the contract
+    // panics the storage changes are reverted and the panic message is
+    // returned as the error.
+    pub fn call(&mut self, method: &str, args: &[u8]) -> Result<CallOutcome> {
+        let mut store = Store::new(
+            self.module.engine(),
+            HostState {
+                context: self.context.clone(),
+                input: args.to_vec(),
+                storage: self.storage.clone(),
+                registers: HashMap::new(),
+                return_data: None,
+                logs: Vec::new(),
+                panic_message: None,
+                test_name: self.test_name.clone(),
+            },
+        );
+        let instance = self.linker.instantiate(&mut store, &self.module)?;
+        let result = instance
+            .get_typed_func::<(), ()>(&mut store, method)
+            .and_then(|function| function.call(&mut store, ()));
+        wasmtime_host::capture_coverage(&mut store, &instance)?;
+
+        let state = store.into_data();
+        if let Err(err) = result {
+            return Err(match state.panic_message {
+                Some(message) => error!("Smart contract panicked: {}", message),
+                None => err.into(),
+            });
+        }
+        self.storage = state.storage;
+        Ok(CallOutcome {
+            return_data: state.return_data,
+            logs: state.logs,
+        })
+    }
+
+    pub fn call_json(&mut self, method: &str, args: &serde_json::Value) -> Result<CallOutcome> {
+        self.call(method, &serde_json::to_vec(args)?)
+    }
+}
+
+fn memory(caller: &mut Caller<'_, HostState>) -> wasmtime::Result<Memory> {
+    caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or_else(|| anyhow!("Contract does not export its memory"))
+}
+
+fn read_memory(
+    caller: &mut Caller<'_, HostState>,
+    len: u64,
+    ptr: u64,
+) -> wasmtime::Result<Vec<u8>> {
+    let memory = memory(caller)?;
+    let data = memory.data(&caller);
+    usize::try_from(ptr)
+        .ok()
+        .zip(usize::try_from(len).ok())
+        .and_then(|(ptr, len)| data.get(ptr..ptr.checked_add(len)?))
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| anyhow!("Memory access out of bounds"))
+}
+
+// Strings with length u64::MAX are null terminated.
+fn read_string(caller: &mut Caller<'_, HostState>, len: u64, ptr: u64) -> wasmtime::Result<String> {
+    let bytes = if len == u64::MAX {
+        let memory = memory(caller)?;
+        let data = memory.data(&caller);
+        let start = usize::try_from(ptr)
+            .ok()
+            .filter(|ptr| *ptr <= data.len())
+            .ok_or_else(|| anyhow!("Memory access out of bounds"))?;
+        let end = data[start..]
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or_else(|| anyhow!("String is not null terminated"))?;
+        data[start..start + end].to_vec()
+    } else {
+        read_memory(caller, len, ptr)?
+    };
+    String::from_utf8(bytes).map_err(|_| anyhow!("Invalid UTF-8 string"))
+}
+
+fn write_memory(caller: &mut Caller<'_, HostState>, ptr: u64, data: &[u8]) -> wasmtime::Result<()> {
+    let memory = memory(caller)?;
+    let ptr = usize::try_from(ptr).map_err(|_| anyhow!("Memory access out of bounds"))?;
+    memory
+        .write(caller, ptr, data)
+        .map_err(|_| anyhow!("Memory access out of bounds"))
+}
+
+fn contract_panic(caller: &mut Caller<'_, HostState>, message: String) -> wasmtime::Error {
+    caller.data_mut().panic_message = Some(message.clone());
+    anyhow!("Smart contract panicked: {}", message)
+}
+
+// Reads a value of the call environment.
+type Getter<T> = fn(&HostState) -> T;
+
+fn add_near_imports(linker: &mut Linker<HostState>) -> wasmtime::Result<()> {
+    const ENV: &str = "env";
+
+    // Registers
+    linker.func_wrap(
+        ENV,
+        "read_register",
+        |mut caller: Caller<'_, HostState>, register_id: u64, ptr: u64| -> wasmtime::Result<()> {
+            let data = caller
+                .data()
+                .registers
+                .get(&register_id)
+                .cloned()
+                .ok_or_else(|| anyhow!("Register {} is not set", register_id))?;
+            write_memory(&mut caller, ptr, &data)
+        },
+    )?;
+    linker.func_wrap(
+        ENV,
+        "register_len",
+        |caller: Caller<'_, HostState>, register_id: u64| -> u64 {
+            caller
+                .data()
+                .registers
+                .get(&register_id)
+                .map_or(u64::MAX, |data| data.len() as u64)
+        },
+    )?;
+    linker.func_wrap(
+        ENV,
+        "write_register",
+        |mut caller: Caller<'_, HostState>,
+         register_id: u64,
+         len: u64,
+         ptr: u64|
+         -> wasmtime::Result<()> {
+            let data = read_memory(&mut caller, len, ptr)?;
+            caller.data_mut().registers.insert(register_id, data);
+            Ok(())
+        },
+    )?;
+
+    // Context
+    let register_getters: [(&str, Getter<Vec<u8>>); 6] = [
+        ("current_account_id", |state| {
+            state.context.current_account_id.clone().into_bytes()
+        }),
+        ("signer_account_id", |state| {
+            state.context.signer_account_id.clone().into_bytes()
+        }),
+        ("signer_account_pk", |state| {
+            state.context.signer_account_pk.clone()
+        }),
+        ("predecessor_account_id", |state| {
+            state.context.predecessor_account_id.clone().into_bytes()
+        }),
+        ("input", |state| state.input.clone()),
+        ("random_seed", |state| state.context.random_seed.clone()),
+    ];
+    for (name, getter) in register_getters {
+        linker.func_wrap(
+            ENV,
+            name,
+            move |mut caller: Caller<'_, HostState>, register_id: u64| {
+                let data = getter(caller.data());
+                caller.data_mut().registers.insert(register_id, data);
+            },
+        )?;
+    }
+
+    let u64_getters: [(&str, Getter<u64>); 6] = [
+        ("block_index", |state| state.context.block_height),
+        ("block_timestamp", |state| state.context.block_timestamp),
+        ("epoch_height", |state| state.context.epoch_height),
+        ("prepaid_gas", |state| state.context.prepaid_gas),
+        ("used_gas", |_| 0),
+        ("storage_usage", |state| {
+            state
+                .storage
+                .iter()
+                .map(|(key, value)| {
+                    key.len() as u64 + value.len() as u64 + STORAGE_RECORD_EXTRA_BYTES
+                })
+                .sum()
+        }),
+    ];
+    for (name, getter) in u64_getters {
+        linker.func_wrap(ENV, name, move |caller: Caller<'_, HostState>| {
+            getter(caller.data())
+        })?;
+    }
+
+    let u128_getters: [(&str, Getter<u128>); 3] = [
+        ("account_balance", |state| state.context.account_balance),
+        ("account_locked_balance", |state| {
+            state.context.account_locked_balance
+        }),
+        ("attached_deposit", |state| state.context.attached_deposit),
+    ];
+    for (name, getter) in u128_getters {
+        linker.func_wrap(
+            ENV,
+            name,
+            move |mut caller: Caller<'_, HostState>, ptr: u64| -> wasmtime::Result<()> {
+                let value = getter(caller.data());
+                write_memory(&mut caller, ptr, &value.to_le_bytes())
+            },
+        )?;
+    }
+
+    // Results, logs and panics
+    linker.func_wrap(
+        ENV,
+        "value_return",
+        |mut caller: Caller<'_, HostState>, len: u64, ptr: u64| -> wasmtime::Result<()> {
+            let data = read_memory(&mut caller, len, ptr)?;
+            caller.data_mut().return_data = Some(data);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        ENV,
+        "log_utf8",
+        |mut caller: Caller<'_, HostState>, len: u64, ptr: u64| -> wasmtime::Result<()> {
+            let message = read_string(&mut caller, len, ptr)?;
+            caller.data_mut().logs.push(message);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        ENV,
+        "log_utf16",
+        |mut caller: Caller<'_, HostState>, len: u64, ptr: u64| -> wasmtime::Result<()> {
+            let bytes = read_memory(&mut caller, len, ptr)?;
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .collect();
+            let message =
+                String::from_utf16(&units).map_err(|_| anyhow!("Invalid UTF-16 string"))?;
+            caller.data_mut().logs.push(message);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        ENV,
+        "panic",
+        |mut caller: Caller<'_, HostState>| -> wasmtime::Result<()> {
+            Err(contract_panic(
+                &mut caller,
+                "explicit guest panic".to_string(),
+            ))
+        },
+    )?;
+    linker.func_wrap(
+        ENV,
+        "panic_utf8",
+        |mut caller: Caller<'_, HostState>, len: u64, ptr: u64| -> wasmtime::Result<()> {
+            let message = read_string(&mut caller, len, ptr)?;
+            Err(contract_panic(&mut caller, message))
+        },
+    )?;
+    linker.func_wrap(
+        ENV,
+        "abort",
+        |mut caller: Caller<'_, HostState>,
+         msg_ptr: u32,
+         filename_ptr: u32,
+         line: u32,
+         col: u32|
+         -> wasmtime::Result<()> {
+            let message = read_string(&mut caller, u64::MAX, msg_ptr as u64)?;
+            let filename = read_string(&mut caller, u64::MAX, filename_ptr as u64)?;
+            Err(contract_panic(
+                &mut caller,
+                format!(
+                    "{}, filename: \"{}\" line: {} col: {}",
+                    message, filename, line, col
+                ),
+            ))
+        },
+    )?;
+
+    // Storage
+    linker.func_wrap(
+        ENV,
+        "storage_write",
+        |mut caller: Caller<'_, HostState>,
+         key_len: u64,
+         key_ptr: u64,
+         value_len: u64,
+         value_ptr: u64,
+         register_id: u64|
+         -> wasmtime::Result<u64> {
+            let key = read_memory(&mut caller, key_len, key_ptr)?;
+            let value = read_memory(&mut caller, value_len, value_ptr)?;
+            let state = caller.data_mut();
+            match state.storage.insert(key, value) {
+                Some(evicted) => {
+                    state.registers.insert(register_id, evicted);
+                    Ok(1)
+                }
+                None => Ok(0),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        ENV,
+        "storage_read",
+        |mut caller: Caller<'_, HostState>,
+         key_len: u64,
+         key_ptr: u64,
+         register_id: u64|
+         -> wasmtime::Result<u64> {
+            let key = read_memory(&mut caller, key_len, key_ptr)?;
+            let state = caller.data_mut();
+            match state.storage.get(&key).cloned() {
+                Some(value) => {
+                    state.registers.insert(register_id, value);
+                    Ok(1)
+                }
+                None => Ok(0),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        ENV,
+        "storage_remove",
+        |mut caller: Caller<'_, HostState>,
+         key_len: u64,
+         key_ptr: u64,
+         register_id: u64|
+         -> wasmtime::Result<u64> {
+            let key = read_memory(&mut caller, key_len, key_ptr)?;
+            let state = caller.data_mut();
+            match state.storage.remove(&key) {
+                Some(evicted) => {
+                    state.registers.insert(register_id, evicted);
+                    Ok(1)
+                }
+                None => Ok(0),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        ENV,
+        "storage_has_key",
+        |mut caller: Caller<'_, HostState>, key_len: u64, key_ptr: u64| -> wasmtime::Result<u64> {
+            let key = read_memory(&mut caller, key_len, key_ptr)?;
+            Ok(caller.data().storage.contains_key(&key) as u64)
+        },
+    )?;
+
+    // Math
+    linker.func_wrap(
+        ENV,
+        "sha256",
+        |mut caller: Caller<'_, HostState>,
+         len: u64,
+         ptr: u64,
+         register_id: u64|
+         -> wasmtime::Result<()> {
+            let value = read_memory(&mut caller, len, ptr)?;
+            let hash = Sha256::digest(value).to_vec();
+            caller.data_mut().registers.insert(register_id, hash);
+            Ok(())
+        },
+    )?;
+
+    Ok(())
+}
@@ -1,21 +1,92 @@
-use crate::utils::{find_file, modify_file, run_command, FileOperation};
-use anyhow::anyhow;
-use anyhow::Result;
+use crate::attribution;
+use crate::error::{error, Error, Result};
+use crate::utils::{find_file, modify_file, path_to_str, run_command, FileOperation};
+use semver::{Version, VersionReq};
 use std::fs;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub fn modify_cargo_toml(file_path: PathBuf) -> std::io::Result<()> {
+// A change to a nearcore source file. The operation is applied to the first
+// of the alternative files that exists.
+pub struct Patch {
+    pub files: Vec<&'static str>,
+    pub operation: FileOperation,
+    // String the patched code compiles into neard, checked by `verify_neard`.
+    pub marker: Option<&'static str>,
+}
+
+// Markers of the patches, each patch applied to a version needs its own so
+// that `verify_neard` notices when one of them is missing.
+const NEAR_VM_RUNNER_MARKER: &str = "capture_coverage function should not fail in near_vm";
+const WASMTIME_RUNNER_MARKER: &str = "capture_coverage function should not fail in wasmtime";
+const LOGIC_MARKER: &str = "Failed to write coverage file from VMLogic";
+const WASMTIME_LOGIC_MARKER: &str = "Failed to write coverage file from the wasmtime host";
+
+// Body of the `sandbox_capture_coverage` host functions, saves `coverage` to
+// the wasmcov profraw directory of the binary named `file`.
+const SAVE_COVERAGE: &str = "
+                use near_primitives_core::hash::CryptoHash;
+                use std::path::PathBuf;
+
+                let file = String::from_utf8(file.to_vec()).expect(\"Invalid coverage file name\");
+                let wasmcov_dir = std::env::var(\"WASMCOV_DIR\").map(|s| PathBuf::from(s)).expect(\"WASMCOV_DIR is not set.\");
+                if !wasmcov_dir.exists() {
+                    panic!(\"WASMCOV_DIR {wasmcov_dir:?} does not exist.\");
+                }
+                let profraw_directory = wasmcov_dir.join(\"profraw\").join(file);
+                let _ = std::fs::create_dir_all(&profraw_directory); // it may fail if multiple threads are trying to create the same directory
+                
+                const HEX_DIGITS: &[u8; 16] = b\"0123456789abcdef\";
+                let mut coverage_hash = String::with_capacity(64);
+                for &byte in CryptoHash::hash_bytes(&coverage).as_bytes() {
+                    coverage_hash.push(HEX_DIGITS[(byte >> 4) as usize] as char);
+                    coverage_hash.push(HEX_DIGITS[(byte & 0x0F) as usize] as char);
+                }
+            
+                let file_path = profraw_directory.join(format!(\"{coverage_hash}.profraw\"));";
+
+// Saves the coverage and records the test named in WASMCOV_TEST_NAME of the
+// sandbox environment, see wasmcov::attribution. `marker` is the message of
+// a failed write.
+fn save_coverage(marker: &str) -> String {
+    [
+        SAVE_COVERAGE,
+        &format!(
+            "
+                std::fs::write(&file_path, &coverage).expect(\"{marker}\");"
+        ),
+        &attribution::record_test_source(&format!(
+            "std::env::var(\"{}\").ok()",
+            attribution::TEST_NAME_ENV
+        )),
+        "
+                Ok(())",
+    ]
+    .concat()
+}
+
+fn cargo_toml_patch() -> Patch {
     let operation = FileOperation::ReplaceText {
         pattern: String::from("profile.quick-release"),
         replacement: String::from("profile.dev-release"),
     };
-    modify_file(file_path, operation)
+    Patch {
+        files: vec!["Cargo.toml"],
+        operation,
+        marker: None,
+    }
 }
 
-pub fn modify_near_vm_runner(file_path: PathBuf) -> std::io::Result<()> {
+// `anchor` is the line before which the instance is still alive after the
+// entrypoint call.
+fn near_vm_runner_patch(anchor: &str) -> Patch {
     let operation =
         FileOperation::AddBefore {
-            pattern: String::from("if let Err(trap) = res {"),
+            pattern: String::from(anchor),
             new_line: String::from(
                 "if std::env::var(\"WASMCOV_DIR\").is_ok() {
                 if let Some(near_vm_types::ExportIndex::Function(index)) = artifact.export_field(\"capture_coverage\") {
@@ -25,15 +96,22 @@ pub fn modify_near_vm_runner(file_path: PathBuf) -> std::io::Result<()> {
             function.call_trampoline.unwrap(),
             function.address,
             [].as_mut_ptr() as *mut _,
-        ).expect(\"capture_coverage function should not fail\");
+        ).expect(\"capture_coverage function should not fail in near_vm\");
     }
 }
 }"),
         };
-    modify_file(file_path, operation)
+    Patch {
+        files: vec![
+            "runtime/near-vm-runner/src/near_vm_runner.rs",
+            "runtime/near-vm-runner/src/near_vm_runner/runner.rs",
+        ],
+        operation,
+        marker: Some(NEAR_VM_RUNNER_MARKER),
+    }
 }
 
-pub fn modify_wasmtime_runner(file_path: PathBuf) -> std::io::Result<()> {
+fn wasmtime_runner_patch() -> Patch {
     let operation =
         FileOperation::ReplaceText {
             pattern: String::from("Ok(run) => match run.call(&mut store, ()) {"),
@@ -43,131 +121,576 @@ pub fn modify_wasmtime_runner(file_path: PathBuf) -> std::io::Result<()> {
                     if std::env::var(\"WASMCOV_DIR\").is_ok() {
                         if let Some(func) = instance.get_func(&mut store, \"capture_coverage\") {
                             if let Some(run) = func.typed::<(), ()>(&mut store).ok() {
-                                run.call(&mut store, ()).expect(\"capture_coverage function should not fail\");
+                                run.call(&mut store, ()).expect(\"capture_coverage function should not fail in wasmtime\");
                             }
                         }
                     }
                     result
                 })() {"),
         };
-    modify_file(file_path, operation)
+    Patch {
+        files: vec!["runtime/near-vm-runner/src/wasmtime_runner.rs"],
+        operation,
+        marker: Some(WASMTIME_RUNNER_MARKER),
+    }
+}
+
+// Since nearcore 2.10 the wasmtime runner is a module calling the method in
+// a separate function.
+fn wasmtime_call_patch() -> Patch {
+    let operation =
+        FileOperation::ReplaceText {
+            pattern: String::from("Ok(run) => match run.call(store, ()) {"),
+            replacement: String::from(
+                "Ok(run) => match (|| {
+            let result = run.call(&mut *store, ());
+            if std::env::var(\"WASMCOV_DIR\").is_ok() {
+                if let Some(func) = instance.get_func(&mut *store, \"capture_coverage\") {
+                    if let Ok(run) = func.typed::<(), ()>(&mut *store) {
+                        run.call(&mut *store, ()).expect(\"capture_coverage function should not fail in wasmtime\");
+                    }
+                }
+            }
+            result
+        })() {"),
+        };
+    Patch {
+        files: vec!["runtime/near-vm-runner/src/wasmtime_runner/mod.rs"],
+        operation,
+        marker: Some(WASMTIME_RUNNER_MARKER),
+    }
 }
 
-pub fn modify_imports(file_path: PathBuf) -> std::io::Result<()> {
+// Since nearcore 2.10 the wasmtime runner has its own host functions. The new
+// function is added before `sandbox_debug_log`, taking over its cfg attribute.
+// `signature` is the signature of `sandbox_debug_log` and `read_memory` binds
+// the guest memory to `memory`, both differ between versions.
+fn wasmtime_logic_patch(signature: &str, parameters: &str, read_memory: &str) -> Patch {
+    let operation = FileOperation::ReplaceText {
+        pattern: signature.to_string(),
+        replacement: [
+            "pub fn sandbox_capture_coverage(",
+            parameters,
+            " file_len: u64, file_ptr: u64, coverage_len: u64, coverage_ptr: u64) -> Result<()> {
+    ",
+            read_memory,
+            "
+    let file = read_memory_for_free(memory, file_ptr, file_len)?.to_vec();
+    let coverage = read_memory_for_free(memory, coverage_ptr, coverage_len)?.to_vec();",
+            &save_coverage(WASMTIME_LOGIC_MARKER),
+            "
+}
+
+#[cfg(feature = \"sandbox\")]
+",
+            signature,
+        ]
+        .concat(),
+    };
+    Patch {
+        files: vec!["runtime/near-vm-runner/src/wasmtime_runner/logic.rs"],
+        operation,
+        marker: Some(WASMTIME_LOGIC_MARKER),
+    }
+}
+
+// `attribute` marks imports available only with the sandbox feature, its
+// syntax changed in nearcore 2.6.
+fn imports_patch(attribute: &str) -> Patch {
     let operation = FileOperation::AddAfter {
-            pattern: String::from("sandbox_debug_log<[len: u64, ptr: u64]"),
-            new_line: String::from("##[\"sandbox\"] sandbox_capture_coverage<[file_len: u64, file_ptr: u64, coverage_len: u64, coverage_ptr: u64] -> []>,"),
+            pattern: format!("{attribute} sandbox_debug_log<[len: u64, ptr: u64]"),
+            new_line: format!("{attribute} sandbox_capture_coverage<[file_len: u64, file_ptr: u64, coverage_len: u64, coverage_ptr: u64] -> []>,"),
         };
-    modify_file(file_path, operation)
+    Patch {
+        files: vec!["runtime/near-vm-runner/src/imports.rs"],
+        operation,
+        marker: Some("sandbox_capture_coverage"),
+    }
 }
 
-pub fn modify_logic(file_path: PathBuf) -> std::io::Result<()> {
+fn logic_patch() -> Patch {
     let operation =
         FileOperation::AddBefore {
             pattern: String::from("pub fn panic(&mut self)"),
-            new_line: String::from("
+            new_line: [
+                "
             #[cfg(feature = \"sandbox\")]
             pub fn sandbox_capture_coverage(&mut self, file_len: u64, file_ptr: u64, coverage_len: u64, coverage_ptr: u64) -> Result<()> {
-                use near_primitives_core::hash::CryptoHash;
-                use std::path::PathBuf;
-            
                 let file = self.memory.view_for_free(MemSlice { ptr: file_ptr, len: file_len })?.into_owned();
-                let file = String::from_utf8(file).expect(\"Invalid coverage file name\");
-                let coverage = self.memory.view_for_free(MemSlice { ptr: coverage_ptr, len: coverage_len })?.into_owned();
-        
-                let wasmcov_dir = std::env::var(\"WASMCOV_DIR\").map(|s| PathBuf::from(s)).expect(\"WASMCOV_DIR is not set.\");
-                if !wasmcov_dir.exists() {
-                    panic!(\"WASMCOV_DIR {wasmcov_dir:?} does not exist.\");
-                }
-                let profraw_directory = wasmcov_dir.join(\"profraw\").join(file);
-                let _ = std::fs::create_dir_all(&profraw_directory); // it may fail if multiple threads are trying to create the same directory
-                
-                const HEX_DIGITS: &[u8; 16] = b\"0123456789abcdef\";
-                let mut coverage_hash = String::with_capacity(64);
-                for &byte in CryptoHash::hash_bytes(&coverage).as_bytes() {
-                    coverage_hash.push(HEX_DIGITS[(byte >> 4) as usize] as char);
-                    coverage_hash.push(HEX_DIGITS[(byte & 0x0F) as usize] as char);
-                }
-            
-                let file_path = profraw_directory.join(format!(\"{coverage_hash}.profraw\"));
-                std::fs::write(&file_path, coverage).expect(\"Failed to write coverage file\");
-                Ok(())
-            }"),
+                let coverage = self.memory.view_for_free(MemSlice { ptr: coverage_ptr, len: coverage_len })?.into_owned();",
+                &save_coverage(LOGIC_MARKER),
+                "
+            }",
+            ]
+            .concat(),
         };
-    modify_file(file_path, operation)
+    Patch {
+        files: vec![
+            "runtime/near-vm-runner/src/logic/logic.rs",
+            "runtime/near-vm-logic/src/logic.rs",
+        ],
+        operation,
+        marker: Some(LOGIC_MARKER),
+    }
 }
 
-pub fn add_wasmcov_to_nearcore(nearcore_dir: &PathBuf) -> Result<()> {
-    let near_vm_runner_path = find_file(
-        &nearcore_dir,
-        &[
-            "runtime/near-vm-runner/src/near_vm_runner.rs",
-            "runtime/near-vm-runner/src/near_vm_runner/runner.rs",
-        ],
-    )
-    .map_err(|_| anyhow!("Could not find near_vm_runner.rs"))?;
+// Layouts of the nearcore code changed by the patches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NearcoreLayout {
+    V1_34,
+    // near_vm instance dropped before the trap check
+    V2_3,
+    // `#[["sandbox"]]` import attributes
+    V2_6,
+    // wasmtime runner module with host functions taking the `Caller`
+    V2_10,
+    // wasmtime host functions taking the guest memory
+    V2_12,
+}
+
+// Supported nearcore versions, checked against the near-vm-runner crates
+// published with each release.
+const COMPATIBILITY: &[(&str, NearcoreLayout)] = &[
+    (">=1.34.0, <2.3.0", NearcoreLayout::V1_34),
+    (">=2.3.0, <2.6.0", NearcoreLayout::V2_3),
+    (">=2.6.0, <2.10.0", NearcoreLayout::V2_6),
+    (">=2.10.0, <2.12.0", NearcoreLayout::V2_10),
+    (">=2.12.0, <3.0.0", NearcoreLayout::V2_12),
+];
+
+// Version used when an empty version is given, kept from before 2.x releases
+// were supported so the cached binary does not change. Newer releases have to
+// be asked for explicitly.
+pub const DEFAULT_NEARCORE_VERSION: &str = "1.35.0";
+
+// Parses versions like `2.6.0`, `v2.6.0` or `2.6.0-rc.1`.
+pub fn parse_version(version: &str) -> Result<Version> {
+    Version::parse(version.trim_start_matches('v'))
+        .map_err(|err| error!("Invalid nearcore version {}: {}", version, err))
+}
+
+pub fn nearcore_layout(version: &str) -> Result<NearcoreLayout> {
+    let parsed = parse_version(version)?;
+    // Release candidates use the layout of their release
+    let release = Version::new(parsed.major, parsed.minor, parsed.patch);
+    COMPATIBILITY
+        .iter()
+        .find(|(range, _)| {
+            VersionReq::parse(range)
+                .expect("COMPATIBILITY has valid version ranges")
+                .matches(&release)
+        })
+        .map(|(_, layout)| *layout)
+        .ok_or_else(|| Error::VersionMismatch {
+            tool: "nearcore".to_string(),
+            expected: COMPATIBILITY
+                .iter()
+                .map(|(range, _)| *range)
+                .collect::<Vec<_>>()
+                .join("; "),
+            found: version.to_string(),
+        })
+}
+
+// Patch set for the given nearcore version, or for the latest supported
+// version when it is unknown.
+pub fn patches(version: Option<&str>) -> Result<Vec<Patch>> {
+    let layout = match version {
+        Some(version) => nearcore_layout(version)?,
+        None => COMPATIBILITY.last().expect("COMPATIBILITY is not empty").1,
+    };
+
+    let near_vm_anchor = match layout {
+        NearcoreLayout::V1_34 => "if let Err(trap) = res {",
+        _ => "lazy_drop(Box::new(instance));",
+    };
+    let mut patches = vec![
+        cargo_toml_patch(),
+        near_vm_runner_patch(near_vm_anchor),
+        logic_patch(),
+    ];
+    match layout {
+        NearcoreLayout::V1_34 | NearcoreLayout::V2_3 => {
+            patches.push(imports_patch("##[\"sandbox\"]"));
+            patches.push(wasmtime_runner_patch());
+        }
+        NearcoreLayout::V2_6 => {
+            patches.push(imports_patch("#[[\"sandbox\"]]"));
+            patches.push(wasmtime_runner_patch());
+        }
+        NearcoreLayout::V2_10 => {
+            patches.push(imports_patch("#[[\"sandbox\"]]"));
+            patches.push(wasmtime_call_patch());
+            patches.push(wasmtime_logic_patch(
+                "pub fn sandbox_debug_log(caller: &mut Caller<'_, Ctx>, len: u64, ptr: u64)",
+                "caller: &mut Caller<'_, Ctx>,",
+                "let memory = get_memory(caller)?;
+    let memory = memory.data(&caller);",
+            ));
+        }
+        NearcoreLayout::V2_12 => {
+            patches.push(imports_patch("#[[\"sandbox\"]]"));
+            patches.push(wasmtime_call_patch());
+            patches.push(wasmtime_logic_patch(
+                "pub fn sandbox_debug_log(_ctx: &mut Ctx, memory: &mut [u8], len: u64, ptr: u64)",
+                "_ctx: &mut Ctx, memory: &mut [u8],",
+                "",
+            ));
+        }
+    }
+    Ok(patches)
+}
+
+// Result of looking for the pattern of a patch in a nearcore checkout.
+#[derive(Debug, Clone)]
+pub struct PatchCheck {
+    // The patched file, or all the alternatives when none of them exists.
+    pub file: String,
+    pub pattern: String,
+    // Number of lines containing the pattern.
+    pub matches: usize,
+}
+
+impl PatchCheck {
+    pub fn applies(&self) -> bool {
+        self.matches > 0
+    }
+}
+
+// Looks for the pattern of every patch without changing any file.
+pub fn check_patches(nearcore_dir: &Path, version: Option<&str>) -> Result<Vec<PatchCheck>> {
+    let mut checks = Vec::new();
+    for patch in patches(version)? {
+        let (file, matches) = match find_file(nearcore_dir, &patch.files) {
+            Ok(path) => {
+                let content = fs::read_to_string(&path)?;
+                let matches = content
+                    .lines()
+                    .filter(|line| line.contains(patch.operation.pattern()))
+                    .count();
+                (
+                    path.strip_prefix(nearcore_dir)
+                        .unwrap_or(&path)
+                        .display()
+                        .to_string(),
+                    matches,
+                )
+            }
+            Err(_) => (patch.files.join(" or "), 0),
+        };
+        checks.push(PatchCheck {
+            file,
+            pattern: patch.operation.pattern().to_string(),
+            matches,
+        });
+    }
+    Ok(checks)
+}
 
-    let imports_path = find_file(&nearcore_dir, &["runtime/near-vm-runner/src/imports.rs"])
-        .map_err(|_| anyhow!("Could not find imports.rs"))?;
-    let wasmtime_runner_path = find_file(
-        &nearcore_dir,
-        &["runtime/near-vm-runner/src/wasmtime_runner.rs"],
+// Version of a nearcore checkout from its git tags.
+pub fn nearcore_version(nearcore_dir: &Path) -> Option<String> {
+    run_command(
+        "git",
+        &["describe", "--tags", "--abbrev=0"],
+        Some(nearcore_dir),
     )
-    .map_err(|_| anyhow!("Could not find wasmtime_runner.rs"))?;
+    .ok()
+    .map(|version| version.trim().to_string())
+    .filter(|version| !version.is_empty())
+}
+
+// Applies all the patches, after making sure every required pattern is found
+// so a failure does not leave the checkout half patched.
+pub fn add_wasmcov_to_nearcore(nearcore_dir: &Path, version: Option<&str>) -> Result<()> {
+    let version_name = version.unwrap_or("(unknown version)");
+    for check in check_patches(nearcore_dir, version)? {
+        if !check.applies() {
+            return Err(Error::PatchFailed {
+                file: check.file,
+                message: format!("`{}` not found in nearcore {}", check.pattern, version_name),
+            });
+        }
+    }
+
+    for patch in patches(version)? {
+        let path = find_file(nearcore_dir, &patch.files)?;
+        let file = path
+            .strip_prefix(nearcore_dir)
+            .unwrap_or(&path)
+            .display()
+            .to_string();
+        modify_file(path, patch.operation).map_err(|err| Error::PatchFailed {
+            file,
+            message: format!("{} (nearcore {})", err, version_name),
+        })?;
+    }
 
-    let logic_path = find_file(
-        &nearcore_dir,
+    Ok(())
+}
+
+// Checks that the strings compiled into neard by the patches of the nearcore
+// version are present in the binary, i.e. that it was built from a patched
+// nearcore. Without a version the patches of the latest version are used.
+pub fn verify_neard(neard_path: &Path, version: Option<&str>) -> Result<()> {
+    let binary = fs::read(neard_path)?;
+    for marker in patches(version)?
+        .into_iter()
+        .filter_map(|patch| patch.marker)
+    {
+        if !binary
+            .windows(marker.len())
+            .any(|window| window == marker.as_bytes())
+        {
+            return Err(error!(
+                "{:?} is not patched by wasmcov: `{}` not found in the binary",
+                neard_path, marker
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Identifies the patches applied to the nearcore version, so cached neard
+// binaries are rebuilt when the patterns or the patched code change, but not
+// when the rest of this file does.
+pub fn patch_hash(version: &str) -> Result<String> {
+    let mut context = md5::Context::new();
+    context.consume(version);
+    for patch in patches(Some(version))? {
+        for text in [patch.operation.pattern(), patch.operation.text()] {
+            context.consume([0]);
+            context.consume(text);
+        }
+    }
+    Ok(format!("{:x}", context.compute())[..8].to_string())
+}
+
+pub fn is_patched(nearcore_dir: &Path) -> Result<bool> {
+    let imports_path = find_file(nearcore_dir, &["runtime/near-vm-runner/src/imports.rs"])?;
+    Ok(fs::read_to_string(imports_path)?.contains("sandbox_capture_coverage"))
+}
+
+// Builds neard with the sandbox feature and returns the path of the binary.
+fn build_neard(nearcore_dir: &Path) -> Result<PathBuf> {
+    println!("Building neard, it may take a while");
+    run_command(
+        "cargo",
         &[
-            "runtime/near-vm-runner/src/logic/logic.rs",
-            "runtime/near-vm-logic/src/logic.rs",
+            "build",
+            "-p",
+            "neard",
+            "--locked",
+            "--features",
+            "sandbox",
+            "--profile",
+            "dev-release",
+            "--target-dir",
+            path_to_str(&nearcore_dir.join("target"))?,
         ],
-    )
-    .map_err(|_| anyhow!("Could not find logic.rs"))?;
+        Some(nearcore_dir),
+    )?;
+    Ok(nearcore_dir.join("target").join("dev-release").join("neard"))
+}
+
+// Release in the output of `neard --version`, e.g. `neard (release 2.6.0) ...`.
+// Builds from a branch report names like `trunk`, which are not versions.
+pub fn neard_release(version_output: &str) -> Option<String> {
+    let (_, release) = version_output.split_once("(release ")?;
+    let release = release.split(')').next()?.trim();
+    parse_version(release).ok().map(|_| release.to_string())
+}
 
-    let _ = modify_cargo_toml(nearcore_dir.join("Cargo.toml")); // it can fail
-    modify_near_vm_runner(near_vm_runner_path)
-        .map_err(|_| anyhow!("Failed to modify near_vm_runner.rs"))?;
-    modify_imports(imports_path).map_err(|_| anyhow!("Failed to modify imports.rs"))?;
-    modify_logic(logic_path).map_err(|_| anyhow!("Failed to modify logic.rs"))?;
-    modify_wasmtime_runner(wasmtime_runner_path)
-        .map_err(|_| anyhow!("Failed to modify wasmtime_runner.rs"))?;
+// Makes sure the neard binary works and is patched for its version, returns
+// its absolute path.
+pub fn check_neard(neard_path: &Path) -> Result<PathBuf> {
+    let neard_path =
+        fs::canonicalize(neard_path).map_err(|_| Error::FileNotFound(neard_path.to_path_buf()))?;
+    let version_output = run_command(path_to_str(&neard_path)?, &["--version"], None)
+        .map_err(|_| error!("Failed to run {}", neard_path.display()))?;
+    let version = neard_release(&version_output).filter(|version| nearcore_layout(version).is_ok());
+    verify_neard(&neard_path, version.as_deref())?;
+    Ok(neard_path)
+}
 
+// Contract of the smoke test run with a freshly built neard. It does nothing,
+// but hands over coverage from its `capture_coverage` export like a contract
+// using `near::add_coverage!`.
+pub const SMOKE_CONTRACT_WAT: &str = r#"(module
+  (import "env" "sandbox_capture_coverage" (func $capture (param i64 i64 i64 i64)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "wasmcov-smoke")
+  (data (i32.const 16) "smoke")
+  (func (export "wasmcov_smoke"))
+  (func (export "capture_coverage")
+    (call $capture (i64.const 13) (i64.const 0) (i64.const 5) (i64.const 16))))
+"#;
+
+// `SMOKE_CONTRACT_WAT` compiled to wasm and base64 encoded, and the base58
+// encoded sha256 hash of the wasm, as the genesis records need them.
+pub const SMOKE_CONTRACT_BASE64: &str = "AGFzbQEAAAABCwJgBH5+fn4AYAAAAiABA2VudhhzYW5kYm94X2NhcHR1cmVfY292ZXJhZ2UAAAMDAgEBBQMBAAEHLQMGbWVtb3J5AgANd2FzbWNvdl9zbW9rZQABEGNhcHR1cmVfY292ZXJhZ2UAAgoRAgIACwwAQg1CAEIFQhAQAAsLHQIAQQALDXdhc21jb3Ytc21va2UAQRALBXNtb2tlABEEbmFtZQEKAQAHY2FwdHVyZQ==";
+pub const SMOKE_CONTRACT_HASH: &str = "AWMJ1Kg95yC2N1xsUMAEE4gV6DrfKm9TTbLJ6h5kUPGC";
+
+// Binary name under which the smoke contract saves its coverage.
+pub const SMOKE_BINARY_NAME: &str = "wasmcov-smoke";
+
+// Account of the genesis created by `neard init`, which the smoke contract
+// is deployed to.
+const SMOKE_ACCOUNT: &str = "test.near";
+
+// Kills the sandbox and removes its home directory when the smoke test ends.
+struct SmokeSandbox {
+    process: Child,
+    home_dir: PathBuf,
+}
+
+impl Drop for SmokeSandbox {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.home_dir);
+    }
+}
+
+fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+// How long the smoke test waits for the sandbox to start and save coverage.
+const SMOKE_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Posts a JSON-RPC request to the sandbox and waits for the response. Fails
+// while the RPC server is not up yet.
+fn post_rpc(rpc_addr: SocketAddr, request: &str) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect_timeout(&rpc_addr, Duration::from_secs(1))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nHost: {rpc_addr}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{request}",
+        request.len()
+    )?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(())
+}
+
+// Adds the smoke contract to the `test.near` account of the genesis.
+fn deploy_smoke_contract(genesis_path: &Path) -> Result<()> {
+    let mut genesis: serde_json::Value = serde_json::from_str(&fs::read_to_string(genesis_path)?)?;
+    let records = genesis
+        .get_mut("records")
+        .and_then(|records| records.as_array_mut())
+        .ok_or_else(|| error!("No records found in {:?}", genesis_path))?;
+    let account = records
+        .iter_mut()
+        .filter_map(|record| record.get_mut("Account"))
+        .find(|record| record["account_id"] == SMOKE_ACCOUNT)
+        .ok_or_else(|| error!("No {} account found in {:?}", SMOKE_ACCOUNT, genesis_path))?;
+    account["account"]["code_hash"] = SMOKE_CONTRACT_HASH.into();
+    records.push(serde_json::json!({
+        "Contract": { "account_id": SMOKE_ACCOUNT, "code": SMOKE_CONTRACT_BASE64 }
+    }));
+    fs::write(genesis_path, serde_json::to_string_pretty(&genesis)?)?;
     Ok(())
 }
 
+// Starts a sandbox with the neard binary, calls the smoke contract and checks
+// that its coverage was saved, i.e. that the patched code not only compiled
+// into neard but captures coverage. Takes several seconds, so only freshly
+// built binaries are checked.
+pub fn smoke_test_neard(neard_path: &Path) -> Result<()> {
+    let rpc_addr = SocketAddr::from(([127, 0, 0, 1], free_port()?));
+    let home_dir = std::env::temp_dir().join(format!(
+        "wasmcov-smoke-{}-{}",
+        std::process::id(),
+        rpc_addr.port()
+    ));
+    let _ = fs::remove_dir_all(&home_dir);
+    let wasmcov_dir = home_dir.join("wasmcov");
+    fs::create_dir_all(&wasmcov_dir)?;
+    let neard = path_to_str(neard_path)?;
+    run_command(neard, &["--home", path_to_str(&home_dir)?, "init"], None)?;
+    deploy_smoke_contract(&home_dir.join("genesis.json"))?;
+
+    let process = Command::new(neard_path)
+        .args(["--home", path_to_str(&home_dir)?, "run"])
+        .args(["--rpc-addr", &rpc_addr.to_string()])
+        .args(["--network-addr", &format!("127.0.0.1:{}", free_port()?)])
+        .env("WASMCOV_DIR", &wasmcov_dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut sandbox = SmokeSandbox {
+        process,
+        home_dir: home_dir.clone(),
+    };
+
+    let request = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": "wasmcov",
+        "method": "query",
+        "params": {
+            "request_type": "call_function",
+            "finality": "optimistic",
+            "account_id": SMOKE_ACCOUNT,
+            "method_name": "wasmcov_smoke",
+            "args_base64": "",
+        },
+    })
+    .to_string();
+    let profraw_dir = wasmcov_dir.join("profraw").join(SMOKE_BINARY_NAME);
+    let started = Instant::now();
+    while started.elapsed() < SMOKE_TEST_TIMEOUT {
+        if let Some(status) = sandbox.process.try_wait()? {
+            return Err(error!(
+                "neard sandbox exited with {} during the smoke test",
+                status
+            ));
+        }
+        // fails until the RPC server is up
+        let _ = post_rpc(rpc_addr, &request);
+        if fs::read_dir(&profraw_dir).is_ok_and(|mut entries| entries.next().is_some()) {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    Err(error!(
+        "{:?} did not save the coverage of a contract call, it is not patched correctly",
+        neard_path
+    ))
+}
+
+// Patches a local nearcore checkout, unless it is already patched, and
+// builds neard in its target directory without cloning nearcore.
+pub fn build_local_nearcore(nearcore_dir: &Path) -> Result<PathBuf> {
+    if !is_patched(nearcore_dir)? {
+        println!("Patching nearcore in {:?}", nearcore_dir);
+        add_wasmcov_to_nearcore(nearcore_dir, nearcore_version(nearcore_dir).as_deref())?;
+    }
+    let neard_path = check_neard(&build_neard(nearcore_dir)?)?;
+    smoke_test_neard(&neard_path)?;
+    Ok(neard_path)
+}
+
+// Clones, patches and builds the given nearcore version. The binary is saved
+// to `dir` as `neard-<version>-<patch hash>` and reused by later calls.
 pub fn setup_near_sandbox(dir: PathBuf, version: String) -> Result<PathBuf> {
     let version = if version.is_empty() {
-        "1.35.0".to_string()
+        DEFAULT_NEARCORE_VERSION.to_string()
     } else {
         version
     };
 
-    let mut version_split = version.split(".");
-    let version_major = version_split
-        .next()
-        .unwrap_or_default()
-        .parse::<u32>()
-        .unwrap_or_default();
-    let version_minor = version_split
-        .next()
-        .unwrap_or_default()
-        .parse::<u32>()
-        .unwrap_or_default();
-    if version_major != 1 || version_minor < 34 {
-        eprintln!(
-            "Version {} is not supported. Please use version 1.34.0 or higher.",
-            version
-        );
-        std::process::exit(1);
-    }
+    // fails for unsupported versions
+    nearcore_layout(&version)?;
 
     if !dir.exists() {
-        fs::create_dir_all(&dir).expect("Failed to create location directory");
+        fs::create_dir_all(&dir)?;
     }
 
-    let neard_path = dir.join(format!("neard-{}", version));
+    let neard_path = dir.join(format!("neard-{}-{}", version, patch_hash(&version)?));
 
-    // If neard-version already exists, return its path
+    // If neard-version-hash already exists, return its path
     if neard_path.exists() {
         return Ok(neard_path);
     }
@@ -184,11 +707,11 @@ pub fn setup_near_sandbox(dir: PathBuf, version: String) -> Result<PathBuf> {
                 "--branch",
                 &version,
                 "https://github.com/near/nearcore",
-                near_repository_dir.to_str().unwrap(),
+                path_to_str(&near_repository_dir)?,
             ],
             None,
         )?;
-        add_wasmcov_to_nearcore(&near_repository_dir)?;
+        add_wasmcov_to_nearcore(&near_repository_dir, Some(&version))?;
     }
 
     // librocksdb-sys requires update because there are issues with the version in the lock file in older versions
@@ -197,37 +720,20 @@ pub fn setup_near_sandbox(dir: PathBuf, version: String) -> Result<PathBuf> {
         &["update", "-p", "librocksdb-sys"],
         Some(&near_repository_dir),
     )?;
-    println!("Building neard, it may take a while");
-    run_command(
-        "cargo",
-        &[
-            "build",
-            "-p",
-            "neard",
-            "--locked",
-            "--features",
-            "sandbox",
-            "--profile",
-            "dev-release",
-            "--target-dir",
-            near_repository_dir.join("target").to_str().unwrap(),
-        ],
-        Some(&near_repository_dir),
-    )?;
+    let source_path = build_neard(&near_repository_dir)?;
 
-    // Copy the built neard to neard-version
-    let source_path = near_repository_dir
-        .join("target")
-        .join("dev-release")
-        .join("neard");
-    fs::copy(&source_path, &neard_path).expect("Failed to copy neard binary");
+    // Copy the built neard to neard-version-hash
+    fs::copy(&source_path, &neard_path)?;
 
     // make sure neard works
-    run_command(neard_path.to_str().unwrap(), &["--version"], None)
-        .expect(format!("Failed to run {}", neard_path.to_str().unwrap()).as_str());
+    let neard_path = check_neard(&neard_path)?;
+    if let Err(err) = smoke_test_neard(&neard_path) {
+        fs::remove_file(&neard_path)?;
+        return Err(err);
+    }
 
     // remove the repo
-    fs::remove_dir_all(&near_repository_dir).expect("Failed to remove near repository dir");
+    fs::remove_dir_all(&near_repository_dir)?;
 
     Ok(neard_path)
 }
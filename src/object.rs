@@ -0,0 +1,137 @@
+use crate::error::Result;
+use crate::wasm::{CoverageSections, COVFUN_SECTION, COVMAP_SECTION, PRF_NAMES_SECTION};
+use std::fs;
+use std::path::Path;
+
+const ELF_HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const ET_REL: u16 = 1;
+const EM_X86_64: u16 = 62;
+const SHT_PROGBITS: u32 = 1;
+const SHT_STRTAB: u32 = 3;
+const SECTION_ALIGNMENT: usize = 8;
+
+struct Section<'a> {
+    name: &'a str,
+    kind: u32,
+    data: &'a [u8],
+}
+
+// Writes a relocatable x86_64 ELF object that contains only the coverage
+// mapping sections. This is all llvm-cov needs to map profile counters back
+// to source code, so the wasm code itself does not have to be compiled.
+pub fn write_coverage_object(sections: &CoverageSections, path: &Path) -> Result<()> {
+    fs::write(path, coverage_object_bytes(sections))?;
+    Ok(())
+}
+
+pub fn coverage_object_bytes(sections: &CoverageSections) -> Vec<u8> {
+    let mut section_names = vec![0u8];
+    let mut sections = vec![
+        Section {
+            name: PRF_NAMES_SECTION,
+            kind: SHT_PROGBITS,
+            data: &sections.prf_names,
+        },
+        Section {
+            name: COVMAP_SECTION,
+            kind: SHT_PROGBITS,
+            data: &sections.covmap,
+        },
+        Section {
+            name: COVFUN_SECTION,
+            kind: SHT_PROGBITS,
+            data: &sections.covfun,
+        },
+    ];
+    for section in &sections {
+        section_names.extend_from_slice(section.name.as_bytes());
+        section_names.push(0);
+    }
+    let shstrtab_name_offset = section_names.len();
+    section_names.extend_from_slice(b".shstrtab\0");
+    sections.push(Section {
+        name: ".shstrtab",
+        kind: SHT_STRTAB,
+        data: &section_names,
+    });
+
+    // Section contents follow the ELF header, each aligned to 8 bytes because
+    // llvm-cov reads the coverage records in place.
+    let mut out = vec![0u8; ELF_HEADER_SIZE];
+    let mut placements = Vec::with_capacity(sections.len());
+    for section in &sections {
+        out.resize(align(out.len()), 0);
+        placements.push(out.len());
+        out.extend_from_slice(section.data);
+    }
+    out.resize(align(out.len()), 0);
+    let section_headers_offset = out.len();
+
+    // Null section header, followed by one header per section.
+    out.extend_from_slice(&[0u8; SECTION_HEADER_SIZE]);
+    let mut name_offset = 1;
+    for (section, offset) in sections.iter().zip(placements) {
+        let name = if section.kind == SHT_STRTAB {
+            shstrtab_name_offset
+        } else {
+            let name = name_offset;
+            name_offset += section.name.len() + 1;
+            name
+        };
+        put_u32(&mut out, name as u32);
+        put_u32(&mut out, section.kind);
+        put_u64(&mut out, 0); // flags
+        put_u64(&mut out, 0); // address
+        put_u64(&mut out, offset as u64);
+        put_u64(&mut out, section.data.len() as u64);
+        put_u32(&mut out, 0); // link
+        put_u32(&mut out, 0); // info
+        put_u64(
+            &mut out,
+            if section.kind == SHT_STRTAB {
+                1
+            } else {
+                SECTION_ALIGNMENT as u64
+            },
+        );
+        put_u64(&mut out, 0); // entry size
+    }
+
+    let mut header = Vec::with_capacity(ELF_HEADER_SIZE);
+    header.extend_from_slice(b"\x7fELF");
+    header.extend_from_slice(&[2, 1, 1, 0]); // 64-bit, little endian, version 1, System V
+    header.extend_from_slice(&[0u8; 8]);
+    put_u16(&mut header, ET_REL);
+    put_u16(&mut header, EM_X86_64);
+    put_u32(&mut header, 1); // version
+    put_u64(&mut header, 0); // entry point
+    put_u64(&mut header, 0); // program headers offset
+    put_u64(&mut header, section_headers_offset as u64);
+    put_u32(&mut header, 0); // flags
+    put_u16(&mut header, ELF_HEADER_SIZE as u16);
+    put_u16(&mut header, 0); // program header size
+    put_u16(&mut header, 0); // number of program headers
+    put_u16(&mut header, SECTION_HEADER_SIZE as u16);
+    put_u16(&mut header, sections.len() as u16 + 1);
+    put_u16(&mut header, sections.len() as u16); // index of .shstrtab
+    out[..ELF_HEADER_SIZE].copy_from_slice(&header);
+
+    out
+}
+
+fn align(value: usize) -> usize {
+    value.div_ceil(SECTION_ALIGNMENT) * SECTION_ALIGNMENT
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
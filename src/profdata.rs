@@ -0,0 +1,352 @@
+use crate::error::{error, Result};
+use crate::profraw::{Profraw, ProfrawRecord, Reader};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+pub const MAGIC: u64 = 0x8169666f72706cff;
+
+// Version 9 of the indexed format is readable by llvm-cov 16 and newer.
+pub const VERSION: u64 = 9;
+
+const VERSION_MASK: u64 = 0x00ff_ffff_ffff_ffff;
+const HASH_TYPE_MD5: u64 = 0;
+const NUM_SUMMARY_FIELDS: u64 = 6;
+const SUMMARY_CUTOFFS: [u64; 16] = [
+    10000, 100000, 200000, 300000, 400000, 500000, 600000, 700000, 800000, 900000, 950000, 990000,
+    999000, 999900, 999990, 999999,
+];
+const SUMMARY_SCALE: u128 = 1000000;
+
+// function hash -> counters
+type FunctionRecords = BTreeMap<u64, Vec<u64>>;
+
+// Merged counters of all profraw files of a single binary, written in the
+// indexed profdata format the same way `llvm-profdata merge -sparse` does.
+#[derive(Debug, Default)]
+pub struct Profdata {
+    variant: Option<u64>,
+    functions: BTreeMap<String, FunctionRecords>,
+    binary_ids: BTreeSet<Vec<u8>>,
+}
+
+impl Profdata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn merge(&mut self, profraw: Profraw) -> Result<()> {
+        match self.variant {
+            Some(variant) if variant != profraw.variant => {
+                return Err(error!(
+                    "Cannot merge profiles with different instrumentation variants"
+                ))
+            }
+            _ => self.variant = Some(profraw.variant),
+        }
+
+        self.binary_ids.extend(profraw.binary_ids);
+        for ProfrawRecord {
+            name,
+            function_hash,
+            counters,
+            ..
+        } in profraw.records
+        {
+            self.add_counters(name, function_hash, &counters)?;
+        }
+        Ok(())
+    }
+
+    // Merges another profile, e.g. one merged from a batch of profraw files on
+    // another thread or read from an existing profdata file.
+    pub fn combine(&mut self, other: Profdata) -> Result<()> {
+        match (self.variant, other.variant) {
+            (Some(variant), Some(other_variant)) if variant != other_variant => {
+                return Err(error!(
+                    "Cannot merge profiles with different instrumentation variants"
+                ))
+            }
+            (None, variant) => self.variant = variant,
+            _ => {}
+        }
+
+        self.binary_ids.extend(other.binary_ids);
+        for (name, records) in other.functions {
+            for (function_hash, counters) in records {
+                self.add_counters(name.clone(), function_hash, &counters)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_counters(&mut self, name: String, function_hash: u64, counters: &[u64]) -> Result<()> {
+        let merged = self
+            .functions
+            .entry(name)
+            .or_default()
+            .entry(function_hash)
+            .or_insert_with(|| vec![0; counters.len()]);
+        if merged.len() != counters.len() {
+            return Err(error!(
+                "Function hash {:#x} has mismatched number of counters",
+                function_hash
+            ));
+        }
+        for (merged, counter) in merged.iter_mut().zip(counters) {
+            *merged = merged.saturating_add(*counter);
+        }
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let data = fs::read(path)?;
+        parse_profdata(&data).map_err(|err| error!("Failed to parse {:?}: {}", path, err))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Sparse profiles skip functions that were never executed.
+        let functions: Vec<(&String, &FunctionRecords)> = self
+            .functions
+            .iter()
+            .filter(|(_, records)| {
+                records
+                    .values()
+                    .any(|counters| counters.iter().any(|counter| *counter != 0))
+            })
+            .collect();
+
+        let mut out = Vec::new();
+        put_u64(&mut out, MAGIC);
+        put_u64(&mut out, VERSION | self.variant.unwrap_or_default());
+        put_u64(&mut out, 0);
+        put_u64(&mut out, HASH_TYPE_MD5);
+        let hash_offset_position = out.len();
+        put_u64(&mut out, 0);
+        // MemProf profiles are not supported.
+        put_u64(&mut out, 0);
+        let binary_id_offset_position = out.len();
+        put_u64(&mut out, 0);
+
+        self.write_summary(&mut out, &functions);
+
+        let hash_offset = write_hash_table(&mut out, &functions);
+        patch_u64(&mut out, hash_offset_position, hash_offset);
+
+        let binary_id_offset = out.len() as u64;
+        let binary_ids_size: usize = self
+            .binary_ids
+            .iter()
+            .map(|binary_id| 8 + align8(binary_id.len()))
+            .sum();
+        put_u64(&mut out, binary_ids_size as u64);
+        for binary_id in &self.binary_ids {
+            put_u64(&mut out, binary_id.len() as u64);
+            out.extend_from_slice(binary_id);
+            out.resize(align8(out.len()), 0);
+        }
+        patch_u64(&mut out, binary_id_offset_position, binary_id_offset);
+
+        out
+    }
+
+    fn write_summary(&self, out: &mut Vec<u8>, functions: &[(&String, &FunctionRecords)]) {
+        let mut num_functions = 0u64;
+        let mut num_blocks = 0u64;
+        let mut max_function_count = 0u64;
+        let mut max_block_count = 0u64;
+        let mut max_internal_block_count = 0u64;
+        let mut total_count = 0u64;
+        // count -> number of blocks with that count, highest first
+        let mut frequencies: BTreeMap<std::cmp::Reverse<u64>, u64> = BTreeMap::new();
+
+        for counters in functions.iter().flat_map(|(_, records)| records.values()) {
+            for (index, &count) in counters.iter().enumerate() {
+                if index == 0 {
+                    num_functions += 1;
+                    max_function_count = max_function_count.max(count);
+                } else {
+                    max_internal_block_count = max_internal_block_count.max(count);
+                }
+                num_blocks += 1;
+                max_block_count = max_block_count.max(count);
+                total_count = total_count.saturating_add(count);
+                *frequencies.entry(std::cmp::Reverse(count)).or_default() += 1;
+            }
+        }
+
+        put_u64(out, NUM_SUMMARY_FIELDS);
+        put_u64(out, SUMMARY_CUTOFFS.len() as u64);
+        put_u64(out, num_functions);
+        put_u64(out, num_blocks);
+        put_u64(out, max_function_count);
+        put_u64(out, max_block_count);
+        put_u64(out, max_internal_block_count);
+        put_u64(out, total_count);
+
+        // For each cutoff, the smallest count of the hottest blocks that add up
+        // to at least cutoff / 1000000 of the total count.
+        let mut frequencies = frequencies.into_iter();
+        let mut current_sum = 0u128;
+        let mut blocks_seen = 0u64;
+        let mut count = 0u64;
+        for cutoff in SUMMARY_CUTOFFS {
+            let desired = total_count as u128 * cutoff as u128 / SUMMARY_SCALE;
+            while current_sum < desired {
+                match frequencies.next() {
+                    Some((std::cmp::Reverse(next_count), frequency)) => {
+                        count = next_count;
+                        current_sum += next_count as u128 * frequency as u128;
+                        blocks_seen += frequency;
+                    }
+                    None => break,
+                }
+            }
+            put_u64(out, cutoff);
+            put_u64(out, count);
+            put_u64(out, blocks_seen);
+        }
+    }
+}
+
+// Emits an OnDiskChainedHashTable keyed by the MD5 of the function name and
+// returns the offset of its bucket array.
+fn write_hash_table(out: &mut Vec<u8>, functions: &[(&String, &FunctionRecords)]) -> u64 {
+    let num_buckets = if functions.len() <= 2 {
+        1
+    } else {
+        (functions.len() * 4 / 3 + 1).next_power_of_two()
+    };
+    let mut buckets: Vec<Vec<(u64, &String, &FunctionRecords)>> = vec![Vec::new(); num_buckets];
+    for (name, records) in functions {
+        let hash = crate::profraw::name_hash(name);
+        buckets[(hash as usize) & (num_buckets - 1)].push((hash, name, records));
+    }
+
+    let mut bucket_offsets = vec![0u64; num_buckets];
+    for (bucket, offset) in buckets.iter().zip(bucket_offsets.iter_mut()) {
+        if bucket.is_empty() {
+            continue;
+        }
+        *offset = out.len() as u64;
+        out.extend_from_slice(&(bucket.len() as u16).to_le_bytes());
+        for (hash, name, records) in bucket {
+            let data_len: usize = records
+                .values()
+                .map(|counters| 8 + 8 + 8 * counters.len() + 8)
+                .sum();
+            put_u64(out, *hash);
+            put_u64(out, name.len() as u64);
+            put_u64(out, data_len as u64);
+            out.extend_from_slice(name.as_bytes());
+            for (function_hash, counters) in records.iter() {
+                put_u64(out, *function_hash);
+                put_u64(out, counters.len() as u64);
+                for counter in counters {
+                    put_u64(out, *counter);
+                }
+                // Empty value profile data: total size and number of value kinds.
+                out.extend_from_slice(&8u32.to_le_bytes());
+                out.extend_from_slice(&0u32.to_le_bytes());
+            }
+        }
+    }
+
+    out.resize(align8(out.len()), 0);
+    let table_offset = out.len() as u64;
+    put_u64(out, num_buckets as u64);
+    put_u64(out, functions.len() as u64);
+    for offset in bucket_offsets {
+        put_u64(out, offset);
+    }
+    table_offset
+}
+
+// Reads an indexed profile in the format written by `Profdata::to_bytes`, so
+// new profraw files can be folded into an existing profdata file. Other
+// versions, e.g. written by a newer llvm-profdata, are not supported.
+pub fn parse_profdata(data: &[u8]) -> Result<Profdata> {
+    let mut reader = Reader::at(data, 0);
+    if reader.u64()? != MAGIC {
+        return Err(error!("Invalid profdata magic"));
+    }
+    let raw_version = reader.u64()?;
+    if raw_version & VERSION_MASK != VERSION {
+        return Err(error!(
+            "Unsupported profdata version {}",
+            raw_version & VERSION_MASK
+        ));
+    }
+    let variant = raw_version & !VERSION_MASK;
+    reader.u64()?;
+    if reader.u64()? != HASH_TYPE_MD5 {
+        return Err(error!("Unsupported profdata hash type"));
+    }
+    let hash_offset = reader.u64()? as usize;
+    if reader.u64()? != 0 {
+        return Err(error!("MemProf profiles are not supported"));
+    }
+    let binary_id_offset = reader.u64()? as usize;
+
+    let mut profdata = Profdata {
+        variant: Some(variant),
+        ..Profdata::default()
+    };
+
+    let mut table = Reader::at(data, hash_offset);
+    let num_buckets = table.u64()?;
+    table.u64()?;
+    for _ in 0..num_buckets {
+        let bucket_offset = table.u64()? as usize;
+        if bucket_offset == 0 {
+            continue;
+        }
+        let mut bucket = Reader::at(data, bucket_offset);
+        for _ in 0..bucket.u16()? {
+            bucket.u64()?;
+            let name_len = bucket.u64()? as usize;
+            let data_len = bucket.u64()? as usize;
+            let name = String::from_utf8(bucket.bytes(name_len)?.to_vec())?;
+            let mut records = Reader::at(bucket.bytes(data_len)?, 0);
+            while !records.at_end() {
+                let function_hash = records.u64()?;
+                let num_counters = records.u64()? as usize;
+                let counters = (0..num_counters)
+                    .map(|_| records.u64())
+                    .collect::<Result<Vec<u64>>>()?;
+                // Value profile data, starting with its total size.
+                let value_data_size = records.u32()? as usize;
+                records.bytes(value_data_size.saturating_sub(4))?;
+                profdata.add_counters(name.clone(), function_hash, &counters)?;
+            }
+        }
+    }
+
+    let mut binary_ids = Reader::at(data, binary_id_offset);
+    let binary_ids_size = binary_ids.u64()? as usize;
+    let mut binary_ids = Reader::at(binary_ids.bytes(binary_ids_size)?, 0);
+    while !binary_ids.at_end() {
+        let len = binary_ids.u64()? as usize;
+        profdata.binary_ids.insert(binary_ids.bytes(len)?.to_vec());
+        binary_ids.bytes(align8(len) - len)?;
+    }
+
+    Ok(profdata)
+}
+
+fn align8(value: usize) -> usize {
+    (value + 7) & !7
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn patch_u64(out: &mut [u8], position: usize, value: u64) {
+    out[position..position + 8].copy_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,348 @@
+use crate::attribution;
+use crate::dir::WasmcovDir;
+use crate::error::{error, Result};
+use flate2::read::ZlibDecoder;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+pub const MAGIC_64: u64 = 0xff6c70726f667281;
+pub const MAGIC_32: u64 = 0xff6c70726f665281;
+
+// Raw profile versions written by the LLVM 15 and newer profiling runtimes.
+pub const SUPPORTED_VERSIONS: [u64; 3] = [8, 9, 10];
+
+const VERSION_MASK: u64 = 0x00ff_ffff_ffff_ffff;
+const VARIANT_MASK_IR_PROF: u64 = 1 << 56;
+const VARIANT_MASK_INSTR_ENTRY: u64 = 1 << 58;
+// IPVK_Last, the value kinds of LLVM are indirect call targets, memory
+// operation sizes and vtable targets.
+const VALUE_KIND_LAST: usize = 2;
+
+// A single instrumented function read from a profraw file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfrawRecord {
+    pub name: String,
+    pub name_hash: u64,
+    pub function_hash: u64,
+    pub counters: Vec<u64>,
+}
+
+// Contents of a profraw file produced by minicov.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profraw {
+    pub version: u64,
+    // Variant flags (IR instrumentation, entry first) carried over to the indexed profile.
+    pub variant: u64,
+    pub binary_ids: Vec<Vec<u8>>,
+    pub records: Vec<ProfrawRecord>,
+}
+
+pub fn read_profraw(path: &Path) -> Result<Profraw> {
+    let data = fs::read(path)?;
+    parse_profraw(&data).map_err(|err| error!("Failed to parse {:?}: {}", path, err))
+}
+
+// Saves coverage captured from a guest to `profraw/<binary_name>/<hash>.profraw`
+// in the wasmcov directory, the layout used by the patched NEAR sandbox.
+// Identical coverage is stored once. When a test name is given the file is
+// also recorded as produced by that test.
+pub fn save_profraw(
+    wasmcov_dir: &WasmcovDir,
+    binary_name: &str,
+    coverage: &[u8],
+    test_name: Option<&str>,
+) -> Result<PathBuf> {
+    let profraw_dir = wasmcov_dir.profraw()?.join(binary_name);
+    // it may fail if multiple threads are trying to create the same directory
+    let _ = fs::create_dir_all(&profraw_dir);
+
+    let hash = format!("{:x}", md5::compute(coverage));
+    let path = profraw_dir.join(format!("{hash}.profraw"));
+    fs::write(&path, coverage)?;
+    attribution::record_test(&profraw_dir, &hash, test_name)?;
+    Ok(path)
+}
+
+// Little endian reader shared by the profraw, profdata and wasm parsers.
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn at(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    pub(crate) fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| error!("Unexpected end of data at offset {}", self.pos))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    // The data after the current position.
+    pub(crate) fn remaining(&self) -> &'a [u8] {
+        &self.data[self.pos.min(self.data.len())..]
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    // Reads a pointer sized value and sign extends it, pointers in the data
+    // records are relative offsets and can be negative.
+    fn pointer(&mut self, pointer_size: usize) -> Result<i64> {
+        if pointer_size == 4 {
+            Ok(self.u32()? as i32 as i64)
+        } else {
+            Ok(self.u64()? as i64)
+        }
+    }
+
+    pub(crate) fn uleb128(&mut self) -> Result<u64> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift >= 64 {
+                return Err(error!("Invalid LEB128 value at offset {}", self.pos));
+            }
+            result |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+            shift += 7;
+        }
+    }
+}
+
+fn align8(value: usize) -> usize {
+    (value + 7) & !7
+}
+
+// Header fields come from the file, sizes and offsets computed from them are
+// checked so that a malformed header is an error instead of an overflow.
+fn header_field(value: u64) -> Result<usize> {
+    usize::try_from(value).map_err(|_| error!("Invalid profraw header"))
+}
+
+fn checked_sum(values: &[usize]) -> Result<usize> {
+    values
+        .iter()
+        .try_fold(0usize, |sum, value| sum.checked_add(*value))
+        .ok_or_else(|| error!("Invalid profraw header"))
+}
+
+pub fn parse_profraw(data: &[u8]) -> Result<Profraw> {
+    let mut reader = Reader::at(data, 0);
+    let pointer_size = match reader.u64()? {
+        MAGIC_64 => 8,
+        MAGIC_32 => 4,
+        magic => return Err(error!("Unknown profraw magic {:#x}", magic)),
+    };
+
+    let raw_version = reader.u64()?;
+    let version = raw_version & VERSION_MASK;
+    if !SUPPORTED_VERSIONS.contains(&version) {
+        return Err(error!("Unsupported profraw version {}", version));
+    }
+    let variant = raw_version & !VERSION_MASK;
+    if variant & !(VARIANT_MASK_IR_PROF | VARIANT_MASK_INSTR_ENTRY) != 0 {
+        return Err(error!("Unsupported profraw variant {:#x}", variant));
+    }
+
+    let binary_ids_size = header_field(reader.u64()?)?;
+    let num_data = header_field(reader.u64()?)?;
+    let padding_before_counters = header_field(reader.u64()?)?;
+    let num_counters = header_field(reader.u64()?)?;
+    let padding_after_counters = header_field(reader.u64()?)?;
+    let (num_bitmap_bytes, padding_after_bitmap) = if version >= 9 {
+        (header_field(reader.u64()?)?, header_field(reader.u64()?)?)
+    } else {
+        (0, 0)
+    };
+    let names_size = header_field(reader.u64()?)?;
+    let counters_delta = reader.u64()?;
+    // The delta is computed with pointer sized arithmetic by the runtime.
+    let counters_delta = if pointer_size == 4 {
+        counters_delta as u32 as i32 as i64
+    } else {
+        counters_delta as i64
+    };
+    if version >= 9 {
+        let _bitmap_delta = reader.u64()?;
+    }
+    let _names_delta = reader.u64()?;
+    if version >= 10 {
+        let _num_vtables = reader.u64()?;
+        let _vnames_size = reader.u64()?;
+    }
+    let value_kind_last = header_field(reader.u64()?)?;
+    if value_kind_last > VALUE_KIND_LAST {
+        return Err(error!("Invalid profraw header"));
+    }
+
+    if num_bitmap_bytes > 0 {
+        return Err(error!("MC/DC bitmaps are not supported"));
+    }
+
+    let header_size = reader.pos;
+    let binary_ids = parse_binary_ids(reader.bytes(binary_ids_size)?)?;
+
+    // NameRef, FuncHash, CounterPtr, [BitmapPtr], FunctionPointer, Values,
+    // NumCounters, NumValueSites[ValueKindLast + 1], [NumBitmapBytes]
+    let pointers = if version >= 9 { 4 } else { 3 };
+    let mut record_size = 16 + pointers * pointer_size + 4 + 2 * (value_kind_last + 1);
+    if version >= 9 {
+        record_size += 4;
+    }
+    let record_size = align8(record_size);
+
+    let invalid_header = || error!("Invalid profraw header");
+    let data_offset = checked_sum(&[header_size, binary_ids_size])?;
+    let data_size = num_data
+        .checked_mul(record_size)
+        .ok_or_else(invalid_header)?;
+    let counters_offset = checked_sum(&[data_offset, data_size, padding_before_counters])?;
+    let counters_size = num_counters.checked_mul(8).ok_or_else(invalid_header)?;
+    let names_offset = checked_sum(&[
+        counters_offset,
+        counters_size,
+        padding_after_counters,
+        num_bitmap_bytes,
+        padding_after_bitmap,
+    ])?;
+
+    let names = parse_names(Reader::at(data, names_offset).bytes(names_size)?)?;
+
+    let mut records = Vec::with_capacity(num_data);
+    let mut record_reader = Reader::at(data, data_offset);
+    for index in 0..num_data {
+        let name_hash = record_reader.u64()?;
+        let function_hash = record_reader.u64()?;
+        let counter_pointer = record_reader.pointer(pointer_size)?;
+        if version >= 9 {
+            let _bitmap_pointer = record_reader.pointer(pointer_size)?;
+        }
+        let _function_pointer = record_reader.pointer(pointer_size)?;
+        let values = record_reader.pointer(pointer_size)?;
+        let record_counters = record_reader.u32()? as usize;
+        let mut value_sites = 0u32;
+        for _ in 0..=value_kind_last {
+            value_sites += record_reader.u16()? as u32;
+        }
+        if values != 0 || value_sites != 0 {
+            return Err(error!("Value profiling data is not supported"));
+        }
+        record_reader.pos = data_offset + (index + 1) * record_size;
+
+        // Counter pointers are relative to the data record they belong to.
+        let delta = counters_delta.wrapping_sub((index * record_size) as i64);
+        let counter_offset = counter_pointer.wrapping_sub(delta);
+        let counter_offset = usize::try_from(counter_offset)
+            .ok()
+            .filter(|offset| {
+                offset % 8 == 0
+                    && (offset / 8)
+                        .checked_add(record_counters)
+                        .is_some_and(|end| end <= num_counters)
+            })
+            .ok_or_else(|| error!("Invalid counter offset in data record {}", index))?;
+        let mut counter_reader = Reader::at(data, counters_offset + counter_offset);
+        let counters = (0..record_counters)
+            .map(|_| counter_reader.u64())
+            .collect::<Result<Vec<u64>>>()?;
+
+        let name = names
+            .get(&name_hash)
+            .cloned()
+            .ok_or_else(|| error!("Missing function name for hash {:#x}", name_hash))?;
+
+        records.push(ProfrawRecord {
+            name,
+            name_hash,
+            function_hash,
+            counters,
+        });
+    }
+
+    Ok(Profraw {
+        version,
+        variant,
+        binary_ids,
+        records,
+    })
+}
+
+fn parse_binary_ids(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut reader = Reader::at(data, 0);
+    let mut binary_ids = Vec::new();
+    while reader.pos < data.len() {
+        let len = reader.u64()? as usize;
+        binary_ids.push(reader.bytes(len)?.to_vec());
+        reader.pos = align8(reader.pos);
+    }
+    Ok(binary_ids)
+}
+
+// Function names are stored as a sequence of (optionally zlib compressed)
+// blobs, each containing names separated by `\x01`.
+fn parse_names(data: &[u8]) -> Result<HashMap<u64, String>> {
+    let mut reader = Reader::at(data, 0);
+    let mut names = HashMap::new();
+    while reader.pos < data.len() {
+        let uncompressed_size = reader.uleb128()? as usize;
+        let compressed_size = reader.uleb128()? as usize;
+        let blob = if compressed_size == 0 {
+            reader.bytes(uncompressed_size)?.to_vec()
+        } else {
+            let mut blob = Vec::with_capacity(uncompressed_size);
+            ZlibDecoder::new(reader.bytes(compressed_size)?).read_to_end(&mut blob)?;
+            blob
+        };
+        for name in blob.split(|byte| *byte == 1) {
+            let name = String::from_utf8(name.to_vec())?;
+            names.insert(name_hash(&name), name);
+        }
+        // The rest of the section is zero padding.
+        if data[reader.pos..].iter().all(|byte| *byte == 0) {
+            break;
+        }
+    }
+    Ok(names)
+}
+
+// Function names are referenced by the low 64 bits of their MD5 digest.
+pub fn name_hash(name: &str) -> u64 {
+    let digest = md5::compute(name.as_bytes());
+    u64::from_le_bytes(digest.0[..8].try_into().unwrap())
+}
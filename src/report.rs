@@ -1,30 +1,639 @@
+use crate::cobertura;
+use crate::dir::{BinaryKey, WasmcovDir};
+use crate::error::{error, Result};
+use crate::fingerprint::Fingerprint;
+use crate::gc;
 use crate::llvm;
-use crate::utils::run_command;
-
-use anyhow::Result;
+use crate::profdata::Profdata;
+use crate::profraw;
+use crate::utils::{path_to_str, run_command};
 
 use glob::glob;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    Html,
+    Lcov,
+    Cobertura,
+    Json,
+}
+
+impl ReportFormat {
+    pub const ALL: [ReportFormat; 4] = [
+        ReportFormat::Html,
+        ReportFormat::Lcov,
+        ReportFormat::Cobertura,
+        ReportFormat::Json,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReportFormat::Html => "html",
+            ReportFormat::Lcov => "lcov",
+            ReportFormat::Cobertura => "cobertura",
+            ReportFormat::Json => "json",
+        }
+    }
+
+    // Path of the report for the given binary inside the format's subdirectory
+    // of the report directory. HTML reports are directories, other formats
+    // are single files.
+    pub fn output_path(&self, report_dir: &Path, name: &str) -> PathBuf {
+        let format_dir = report_dir.join(self.name());
+        match self {
+            ReportFormat::Html => format_dir.join(name),
+            ReportFormat::Lcov => format_dir.join(format!("{name}.info")),
+            ReportFormat::Cobertura => format_dir.join(format!("{name}.xml")),
+            ReportFormat::Json => format_dir.join(format!("{name}.json")),
+        }
+    }
+}
+
+impl fmt::Display for ReportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ReportFormat {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ReportFormat::ALL
+            .into_iter()
+            .find(|format| format.name() == s.to_lowercase())
+            .ok_or_else(|| {
+                error!("Unknown report format {s:?}, expected one of: html, lcov, cobertura, json")
+            })
+    }
+}
+
+// Formats to generate reports in: none with `--summary-only`, otherwise the
+// formats given on the command line, the configured ones or HTML.
+pub fn report_formats(
+    summary_only: bool,
+    formats: Vec<ReportFormat>,
+    configured: &[ReportFormat],
+) -> Vec<ReportFormat> {
+    if summary_only {
+        Vec::new()
+    } else if !formats.is_empty() {
+        formats
+    } else if !configured.is_empty() {
+        configured.to_vec()
+    } else {
+        vec![ReportFormat::Html]
+    }
+}
+
+// How profraw files are merged into profdata files.
+#[derive(Default)]
+pub struct MergeOptions<'a> {
+    // Additional llvm-profdata arguments, llvm-profdata is used when not empty.
+    pub extra_args: Vec<String>,
+    // Merges all profraw files again instead of folding the new ones into the
+    // existing profdata file.
+    pub full: bool,
+    // Number of merge threads, the available parallelism when 0.
+    pub jobs: usize,
+    // Called with the number of merged and of all profraw files.
+    pub progress: Option<&'a (dyn Fn(usize, usize) + Sync)>,
+    // Moves profraw files written by a different build of the binary to
+    // `profraw/<binary>/stale` instead of leaving them in place.
+    pub quarantine_stale: bool,
+}
+
+// Outcome of merging the profraw files of a binary.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Merged {
+    pub profdata_path: PathBuf,
+    // Number of merged profraw files.
+    pub profraw_files: usize,
+    // No profraw files were added since the last merge.
+    pub up_to_date: bool,
+    // Profraw files written by a different build of the binary, which were
+    // not merged.
+    pub stale_files: Vec<PathBuf>,
+}
+
+// Profraw files merged into a profdata file with their modification times,
+// saved next to it as `<binary>.inputs`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MergeInputs {
+    extra_args: Vec<String>,
+    // Digest of the fingerprint of the build the profraw files were checked against.
+    fingerprint: Option<String>,
+    files: BTreeMap<String, u64>,
+    // Profraw files which were skipped as stale.
+    stale_files: BTreeSet<String>,
+}
 
-pub fn merge_profraw_to_profdata(profraw_dir: &PathBuf, profdata_path: &PathBuf, extra_args: Vec<String>) -> Result<()> {
+impl MergeInputs {
+    fn new(
+        profraw_files: &[PathBuf],
+        extra_args: &[String],
+        fingerprint: Option<&Fingerprint>,
+    ) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        for path in profraw_files {
+            let modified = fs::metadata(path)?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_nanos() as u64)
+                .unwrap_or_default();
+            files.insert(file_name(path), modified);
+        }
+        Ok(Self {
+            extra_args: extra_args.to_vec(),
+            fingerprint: fingerprint.map(Fingerprint::digest),
+            files,
+            stale_files: BTreeSet::new(),
+        })
+    }
+
+    fn path(profdata_path: &Path) -> PathBuf {
+        profdata_path.with_extension("inputs")
+    }
+
+    fn load(profdata_path: &Path) -> Option<Self> {
+        if !profdata_path.exists() {
+            return None;
+        }
+        let inputs = fs::read_to_string(Self::path(profdata_path)).ok()?;
+        serde_json::from_str(&inputs).ok()
+    }
+
+    // The profdata file can only be extended when the merged profraw files
+    // are unchanged and were merged the same way for the same build.
+    fn extends(&self, previous: &MergeInputs) -> bool {
+        self.extra_args == previous.extra_args
+            && self.fingerprint == previous.fingerprint
+            && previous
+                .files
+                .iter()
+                .all(|(name, modified)| self.files.get(name) == Some(modified))
+    }
+
+    // Whether the profraw file is stale, when `previous` checked it unchanged
+    // against the same build.
+    fn checked_stale(&self, previous: &MergeInputs, path: &Path) -> Option<bool> {
+        let name = file_name(path);
+        let unchanged = self.fingerprint.is_some()
+            && self.fingerprint == previous.fingerprint
+            && self.files.contains_key(&name)
+            && self.files.get(&name) == previous.files.get(&name);
+        unchanged.then(|| previous.stale_files.contains(&name))
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Merges profraw files with the native writer, falling back to llvm-profdata
+// when extra llvm-profdata arguments are given or the profraw files use a
+// format the native parser does not support.
+pub fn merge_profraw_to_profdata(profraw_dir: &Path, profdata_path: &Path, extra_args: Vec<String>) -> Result<()> {
+    let options = MergeOptions {
+        extra_args,
+        full: true,
+        ..Default::default()
+    };
+    merge_profraw_dir(profraw_dir, profdata_path, None, &options)?;
+    Ok(())
+}
+
+// Merges the profraw files in the directory into the profdata file. Unless a
+// full merge is requested, profraw files added since the last merge are folded
+// into the existing profdata file. With a fingerprint of the current build,
+// profraw files written by a different build are skipped.
+pub fn merge_profraw_dir(
+    profraw_dir: &Path,
+    profdata_path: &Path,
+    fingerprint: Option<&Fingerprint>,
+    options: &MergeOptions,
+) -> Result<Merged> {
     // find all .profraw files in the profraw directory
-    let profraw_files: Vec<String> = glob(profraw_dir.join("*.profraw").to_str().unwrap())?
+    let mut profraw_files: Vec<PathBuf> = glob(path_to_str(&profraw_dir.join("*.profraw"))?)?
         .filter_map(|entry| entry.ok())
+        .collect();
+    // and the profdata of the profraw files removed by `gc`, unless it was
+    // collected for a different build
+    let collected_profdata = profraw_dir.join(gc::COLLECTED_PROFDATA);
+    let collected_stale =
+        fingerprint.is_some_and(|fingerprint| gc::is_collected_stale(profraw_dir, fingerprint));
+    if collected_profdata.exists() && !collected_stale {
+        profraw_files.push(collected_profdata);
+    }
+
+    let mut inputs = MergeInputs::new(&profraw_files, &options.extra_args, fingerprint)?;
+    let last_inputs = MergeInputs::load(profdata_path);
+    let previous = match options.full {
+        true => None,
+        false => last_inputs
+            .as_ref()
+            .filter(|previous| inputs.extends(previous)),
+    };
+    let (base, profraw_files) = match previous {
+        Some(previous) => {
+            inputs.stale_files = previous.stale_files.clone();
+            let new_files = profraw_files
+                .into_iter()
+                .filter(|path| !previous.files.contains_key(&file_name(path)))
+                .collect();
+            (Some(profdata_path), new_files)
+        }
+        None => (None, profraw_files),
+    };
+
+    let (profraw_files, stale_files) = match fingerprint {
+        Some(fingerprint) => {
+            // Files checked by the last merge are not parsed again, e.g. when
+            // a full merge is needed because another file changed.
+            let mut fresh = Vec::new();
+            let mut stale = Vec::new();
+            let mut unchecked = Vec::new();
+            for path in profraw_files {
+                let checked = last_inputs
+                    .as_ref()
+                    .and_then(|last_inputs| inputs.checked_stale(last_inputs, &path));
+                match checked {
+                    Some(false) => fresh.push(path),
+                    Some(true) => stale.push(path),
+                    None => unchecked.push(path),
+                }
+            }
+            let (checked_fresh, checked_stale) =
+                partition_stale(unchecked, fingerprint, options.jobs)?;
+            fresh.extend(checked_fresh);
+            stale.extend(checked_stale);
+            fresh.sort();
+            stale.sort();
+            (fresh, stale)
+        }
+        None => (profraw_files, Vec::new()),
+    };
+    inputs
+        .stale_files
+        .extend(stale_files.iter().map(|path| file_name(path)));
+    inputs.stale_files.remove(gc::COLLECTED_PROFDATA);
+    if collected_stale {
+        inputs
+            .stale_files
+            .insert(gc::COLLECTED_PROFDATA.to_string());
+    }
+    let mut stale_files: Vec<PathBuf> = inputs
+        .stale_files
+        .iter()
+        .map(|name| profraw_dir.join(name))
+        .collect();
+    if options.quarantine_stale && !stale_files.is_empty() {
+        let stale_dir = profraw_dir.join("stale");
+        fs::create_dir_all(&stale_dir)?;
+        for path in &mut stale_files {
+            *path = match file_name(path) == gc::COLLECTED_PROFDATA {
+                true => gc::quarantine_collected(profraw_dir)?,
+                false => {
+                    let quarantined = stale_dir.join(file_name(path));
+                    fs::rename(&path, &quarantined)?;
+                    quarantined
+                }
+            };
+        }
+        for name in std::mem::take(&mut inputs.stale_files) {
+            inputs.files.remove(&name);
+        }
+    }
+
+    let merged = Merged {
+        profdata_path: profdata_path.to_path_buf(),
+        profraw_files: profraw_files.len(),
+        up_to_date: false,
+        stale_files,
+    };
+    let inputs_path = MergeInputs::path(profdata_path);
+    if base.is_some() && profraw_files.is_empty() {
+        fs::write(&inputs_path, serde_json::to_string(&inputs)?)?;
+        return Ok(Merged {
+            up_to_date: true,
+            ..merged
+        });
+    }
+
+    // Without the inputs file an interrupted merge is followed by a full one.
+    if inputs_path.exists() {
+        fs::remove_file(&inputs_path)?;
+    }
+    merge_profraw_files_into(base, &profraw_files, profdata_path, options)?;
+    fs::write(&inputs_path, serde_json::to_string(&inputs)?)?;
+    Ok(merged)
+}
+
+// Splits the profraw files into the ones written by the fingerprinted build
+// and stale ones, checking them on `jobs` threads. Profdata inputs are kept,
+// the build of the collected profdata is checked by `gc::is_collected_stale`.
+pub fn partition_stale(
+    profraw_files: Vec<PathBuf>,
+    fingerprint: &Fingerprint,
+    jobs: usize,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let batch_size = profraw_files.len().div_ceil(merge_jobs(jobs)).max(1);
+    let stale = thread::scope(|scope| {
+        let handles: Vec<_> = profraw_files
+            .chunks(batch_size)
+            .map(|batch| {
+                scope.spawn(move || {
+                    batch
+                        .iter()
+                        .map(|path| {
+                            path.extension().and_then(|extension| extension.to_str())
+                                == Some("profraw")
+                                && fingerprint.is_stale(path)
+                        })
+                        .collect::<Vec<bool>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .map_err(|_| error!("Profraw check thread panicked"))
+            })
+            .collect::<Result<Vec<Vec<bool>>>>()
+    })?;
+
+    let (stale, fresh): (Vec<_>, Vec<_>) = profraw_files
+        .into_iter()
+        .zip(stale.into_iter().flatten())
+        .partition(|(_, stale)| *stale);
+    Ok((
+        fresh.into_iter().map(|(path, _)| path).collect(),
+        stale.into_iter().map(|(path, _)| path).collect(),
+    ))
+}
+
+// Merges the profraw files of every binary in the wasmcov directory into
+// `profdata/<binary>.profdata`, skipping the profraw files which do not match
+// the fingerprint of the binary's last build.
+pub fn merge_all(wasmcov_dir: &WasmcovDir, options: &MergeOptions) -> Result<Vec<Merged>> {
+    let mut merged = Vec::new();
+    for entry in fs::read_dir(wasmcov_dir.profraw()?)? {
+        let dir_path = entry?.path();
+        if !dir_path.is_dir() || fs::read_dir(&dir_path)?.next().is_none() {
+            continue;
+        }
+
+        let dir_name = dir_path.file_name().unwrap().to_string_lossy();
+        println!("Merging profraw files for {}", dir_name);
+
+        let profdata_path = wasmcov_dir.profdata_file_of(&BinaryKey::parse(&dir_name))?;
+        let fingerprint = load_fingerprint(wasmcov_dir, &dir_name)?;
+        let binary_merged =
+            merge_profraw_dir(&dir_path, &profdata_path, fingerprint.as_ref(), options)?;
+        if binary_merged.up_to_date {
+            println!("Profdata file {:?} is up to date", profdata_path);
+        } else {
+            println!("Profdata file has been saved to {:?}", profdata_path);
+        }
+        merged.push(binary_merged);
+    }
+    Ok(merged)
+}
+
+// Names and paths of the profdata files in the wasmcov directory.
+pub fn profdata_files(wasmcov_dir: &WasmcovDir) -> Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(wasmcov_dir.profdata()?)? {
+        let file_path = entry?.path();
+        if file_path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            != Some("profdata")
+        {
+            continue;
+        }
+        let name = file_path
+            .file_stem()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        files.push((name, file_path));
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Name of the report covering all binaries of the wasmcov directory, also used
+// for its thresholds, e.g. `--fail-under-lines combined=80`.
+pub const COMBINED: &str = "combined";
+
+// Merges the profdata files of all binaries into `report/combined.profdata`,
+// so a single llvm-cov invocation can report on all of them.
+pub fn merge_combined(
+    wasmcov_dir: &WasmcovDir,
+    profdata_files: &[PathBuf],
+    options: &MergeOptions,
+) -> Result<PathBuf> {
+    let profdata_path = wasmcov_dir.report()?.join(format!("{COMBINED}.profdata"));
+    merge_profraw_files_into(None, profdata_files, &profdata_path, options)?;
+    Ok(profdata_path)
+}
+
+// llvm-cov takes the first object file as a positional argument, every other
+// one has to be passed with `-object`.
+pub fn object_args(object_files: &[PathBuf]) -> Result<Vec<String>> {
+    object_files
+        .iter()
+        .skip(1)
+        .map(|object_file| Ok(format!("-object={}", path_to_str(object_file)?)))
+        .collect()
+}
+
+// Object file generated by the build for the binary, the crate name in its
+// file name may use either dashes or underscores.
+pub fn find_object_file(wasmcov_dir: &WasmcovDir, name: &str) -> Result<Option<PathBuf>> {
+    let target_dir = wasmcov_dir.target()?;
+    Ok([
+        wasmcov_dir.object_file_of(&BinaryKey::parse(name))?,
+        target_dir.join(format!("{}.o", name)),
+    ]
+    .into_iter()
+    .find(|path| path.exists()))
+}
+
+// Fingerprint of the last build of the binary, saved when its object file was
+// extracted from the wasm file.
+pub fn load_fingerprint(wasmcov_dir: &WasmcovDir, name: &str) -> Result<Option<Fingerprint>> {
+    match find_object_file(wasmcov_dir, name)? {
+        Some(object_file) if Fingerprint::path(&object_file).exists() => {
+            Ok(Some(Fingerprint::read(&Fingerprint::path(&object_file))?))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn merge_profraw_files(
+    profraw_files: &[PathBuf],
+    profdata_path: &Path,
+    extra_args: Vec<String>,
+) -> Result<()> {
+    let options = MergeOptions {
+        extra_args,
+        ..Default::default()
+    };
+    merge_profraw_files_into(None, profraw_files, profdata_path, &options)
+}
+
+// Merges the profraw files, and the `base` profdata file when given, into the
+// profdata file. Profdata files can also be passed as inputs.
+pub fn merge_profraw_files_into(
+    base: Option<&Path>,
+    profraw_files: &[PathBuf],
+    profdata_path: &Path,
+    options: &MergeOptions,
+) -> Result<()> {
+    if options.extra_args.is_empty() {
+        match merge_natively(base, profraw_files, profdata_path, options) {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                eprintln!("Warning: {}, falling back to llvm-profdata", err);
+            }
+        }
+    }
+
+    merge_with_llvm_profdata(base, profraw_files, profdata_path, options)?;
+    if let Some(progress) = options.progress {
+        progress(profraw_files.len(), profraw_files.len());
+    }
+    Ok(())
+}
+
+pub fn merge_profraw_natively(profraw_files: &[PathBuf], profdata_path: &Path) -> Result<()> {
+    merge_natively(None, profraw_files, profdata_path, &MergeOptions::default())
+}
+
+// Splits the profraw files into one batch per thread. Each thread reads its
+// files one at a time into its own profile, the profiles are combined at the end.
+fn merge_natively(
+    base: Option<&Path>,
+    profraw_files: &[PathBuf],
+    profdata_path: &Path,
+    options: &MergeOptions,
+) -> Result<()> {
+    let mut profdata = match base {
+        Some(base) => Profdata::read(base)?,
+        None => Profdata::new(),
+    };
+
+    let jobs = merge_jobs(options.jobs);
+    let batch_size = profraw_files.len().div_ceil(jobs).max(1);
+    let merged = &Mutex::new(0);
+    let batches = thread::scope(|scope| {
+        let handles: Vec<_> = profraw_files
+            .chunks(batch_size)
+            .map(|batch| {
+                scope.spawn(move || -> Result<Profdata> {
+                    let mut profdata = Profdata::new();
+                    for input in batch {
+                        if input.extension().and_then(|extension| extension.to_str())
+                            == Some("profdata")
+                        {
+                            profdata.combine(Profdata::read(input)?)?;
+                        } else {
+                            profdata.merge(profraw::read_profraw(input)?)?;
+                        }
+                        if let Some(progress) = options.progress {
+                            if let Ok(mut merged) = merged.lock() {
+                                *merged += 1;
+                                progress(*merged, profraw_files.len());
+                            }
+                        }
+                    }
+                    Ok(profdata)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .map_err(|_| error!("Profraw merge thread panicked"))?
+            })
+            .collect::<Result<Vec<Profdata>>>()
+    })?;
+
+    for batch in batches {
+        profdata.combine(batch)?;
+    }
+    profdata.write(profdata_path)
+}
+
+fn merge_jobs(jobs: usize) -> usize {
+    match jobs {
+        0 => thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+        jobs => jobs,
+    }
+}
+
+pub fn merge_profraw_with_llvm_profdata(
+    profraw_files: &[PathBuf],
+    profdata_path: &Path,
+    extra_args: Vec<String>,
+) -> Result<()> {
+    let options = MergeOptions {
+        extra_args,
+        ..Default::default()
+    };
+    merge_with_llvm_profdata(None, profraw_files, profdata_path, &options)
+}
+
+// The input files are passed in a file list, a command line with tens of
+// thousands of profraw files exceeds ARG_MAX.
+fn merge_with_llvm_profdata(
+    base: Option<&Path>,
+    profraw_files: &[PathBuf],
+    profdata_path: &Path,
+    options: &MergeOptions,
+) -> Result<()> {
+    let input_list_path = profdata_path.with_extension("input-list");
+    let output_path = profdata_path.with_extension("profdata.tmp");
+    let input_list: Vec<String> = base
+        .into_iter()
+        .chain(profraw_files.iter().map(PathBuf::as_path))
         .map(|path| path.to_string_lossy().into_owned())
         .collect();
+    fs::write(&input_list_path, input_list.join("\n"))?;
 
     // Prepare the command arguments
     let mut args = vec![
         "merge".to_string(),
         "-sparse".to_string(),
+        format!("--num-threads={}", merge_jobs(options.jobs)),
         "-o".to_string(),
-        profdata_path.to_str().unwrap().to_string(),
+        path_to_str(&output_path)?.to_string(),
     ];
-    args.extend(extra_args);
-    args.extend(profraw_files);
+    args.extend(options.extra_args.iter().cloned());
+    args.push("-f".to_string());
+    args.push(path_to_str(&input_list_path)?.to_string());
 
     // Run the command
-    run_command(
+    let result = run_command(
         &llvm::get_tooling()?.llvm_profdata,
         args.as_slice()
             .iter()
@@ -32,36 +641,639 @@ pub fn merge_profraw_to_profdata(profraw_dir: &PathBuf, profdata_path: &PathBuf,
             .collect::<Vec<&str>>()
             .as_slice(),
         None,
-    )?;
+    );
+    fs::remove_file(&input_list_path)?;
+    result?;
 
+    // The base profdata file is only replaced once the merge succeeded.
+    fs::rename(&output_path, profdata_path)?;
     Ok(())
 }
 
 pub fn generate_report(
-    profdata_path: &PathBuf,
-    object_file: &PathBuf,
-    report_dir: &PathBuf,
-    llvm_cov_args: &Vec<String>,
+    profdata_path: &Path,
+    object_file: &Path,
+    report_dir: &Path,
+    llvm_cov_args: &[String],
 ) -> Result<()> {
     let mut cov_args = vec![
         "show",
         "--instr-profile",
-        profdata_path.to_str().unwrap(),
-        object_file.to_str().unwrap(),
+        path_to_str(profdata_path)?,
+        path_to_str(object_file)?,
         "--output-dir",
-        report_dir.to_str().unwrap(),
+        path_to_str(report_dir)?,
         "--show-instantiations=false",
         "--format=html",
         "-show-directory-coverage",
     ];
-    cov_args.extend(
-        llvm_cov_args
-            .as_slice()
-            .iter()
-            .map(AsRef::as_ref)
-            .collect::<Vec<&str>>()
-            .as_slice(),
-    );
+    cov_args.extend(llvm_cov_args.iter().map(String::as_str));
     run_command(&llvm::get_tooling()?.llvm_cov, cov_args.as_slice(), None)?;
     Ok(())
 }
+
+// `llvm-cov show` options which `llvm-cov export` rejects, or which change
+// its output: the format and output file are set by wasmcov, and the exported
+// names are demangled by wasmcov instead of an external demangler.
+const SHOW_ONLY_FLAGS: &[&str] = &[
+    "binary-counters",
+    "show-branches",
+    "show-created-time",
+    "show-directory-coverage",
+    "show-expansions",
+    "show-instantiations",
+    "show-line-counts",
+    "show-line-counts-or-regions",
+    "show-mcdc",
+    "show-regions",
+];
+// Like `SHOW_ONLY_FLAGS`, for options whose value may be the next argument.
+const SHOW_ONLY_OPTIONS: &[&str] = &[
+    "Xdemangler",
+    "coverage-watermark",
+    "format",
+    "o",
+    "output-dir",
+    "project-title",
+    "tab-size",
+];
+
+// The additional llvm-cov arguments are `llvm-cov show` arguments, the ones
+// `llvm-cov export` does not accept are left out when exporting.
+pub fn export_args(llvm_cov_args: &[String]) -> Vec<String> {
+    let mut export_args = Vec::new();
+    let mut args = llvm_cov_args.iter();
+    while let Some(arg) = args.next() {
+        let Some(option) = arg.strip_prefix('-') else {
+            export_args.push(arg.clone());
+            continue;
+        };
+        let option = option.strip_prefix('-').unwrap_or(option);
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+        if SHOW_ONLY_FLAGS.contains(&name) {
+            continue;
+        }
+        if SHOW_ONLY_OPTIONS.contains(&name) {
+            if value.is_none() {
+                args.next();
+            }
+            continue;
+        }
+        export_args.push(arg.clone());
+    }
+    export_args
+}
+
+pub fn export(
+    profdata_path: &Path,
+    object_file: &Path,
+    format: &str,
+    extra_args: &[&str],
+    llvm_cov_args: &[String],
+) -> Result<String> {
+    let format_arg = format!("-format={format}");
+    let mut cov_args = vec![
+        "export",
+        "--instr-profile",
+        path_to_str(profdata_path)?,
+        path_to_str(object_file)?,
+        &format_arg,
+    ];
+    cov_args.extend(extra_args);
+    let llvm_cov_args = export_args(llvm_cov_args);
+    cov_args.extend(llvm_cov_args.iter().map(String::as_str));
+    run_command(&llvm::get_tooling()?.llvm_cov, cov_args.as_slice(), None)
+}
+
+// Generates a report in the given format at `output_path`, as returned by
+// `ReportFormat::output_path`.
+pub fn generate_report_in_format(
+    format: ReportFormat,
+    name: &str,
+    profdata_path: &Path,
+    object_file: &Path,
+    output_path: &Path,
+    llvm_cov_args: &[String],
+) -> Result<()> {
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match format {
+        ReportFormat::Html => {
+            generate_report(profdata_path, object_file, output_path, llvm_cov_args)?
+        }
+        ReportFormat::Lcov => {
+            let lcov = export(profdata_path, object_file, "lcov", &[], llvm_cov_args)?;
+            fs::write(output_path, lcov)?;
+        }
+        ReportFormat::Cobertura => {
+            let lcov = export(profdata_path, object_file, "lcov", &[], llvm_cov_args)?;
+            let files = cobertura::parse_lcov(&lcov)?;
+            fs::write(output_path, cobertura::to_cobertura(name, &files))?;
+        }
+        ReportFormat::Json => {
+            let json = export(
+                profdata_path,
+                object_file,
+                "text",
+                &["-summary-only"],
+                llvm_cov_args,
+            )?;
+            fs::write(output_path, json)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SummaryEntry {
+    pub count: u64,
+    pub covered: u64,
+}
+
+impl SummaryEntry {
+    // Percentage of covered items, 100% when there is nothing to cover.
+    pub fn percent(&self) -> f64 {
+        if self.count == 0 {
+            100.0
+        } else {
+            self.covered as f64 * 100.0 / self.count as f64
+        }
+    }
+
+    pub(crate) fn from_json(json: &serde_json::Value) -> Self {
+        SummaryEntry {
+            count: json["count"].as_u64().unwrap_or_default(),
+            covered: json["covered"].as_u64().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CoverageSummary {
+    pub functions: SummaryEntry,
+    pub lines: SummaryEntry,
+    pub regions: SummaryEntry,
+    pub branches: SummaryEntry,
+}
+
+impl CoverageSummary {
+    fn from_json(json: &serde_json::Value) -> Self {
+        CoverageSummary {
+            functions: SummaryEntry::from_json(&json["functions"]),
+            lines: SummaryEntry::from_json(&json["lines"]),
+            regions: SummaryEntry::from_json(&json["regions"]),
+            branches: SummaryEntry::from_json(&json["branches"]),
+        }
+    }
+}
+
+// Runs `llvm-cov export -format=text` and parses its JSON output.
+pub fn export_json(
+    profdata_path: &Path,
+    object_file: &Path,
+    llvm_cov_args: &[String],
+) -> Result<serde_json::Value> {
+    let json = export(profdata_path, object_file, "text", &[], llvm_cov_args)?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+// Computes the coverage totals of a binary with `llvm-cov export -summary-only`.
+pub fn summary(
+    profdata_path: &Path,
+    object_file: &Path,
+    llvm_cov_args: &[String],
+) -> Result<CoverageSummary> {
+    let json = export(
+        profdata_path,
+        object_file,
+        "text",
+        &["-summary-only"],
+        llvm_cov_args,
+    )?;
+    parse_summary(&json)
+}
+
+// Parses the coverage totals of the first binary from `llvm-cov export` output.
+pub fn parse_summary(json: &str) -> Result<CoverageSummary> {
+    let json: serde_json::Value = serde_json::from_str(json)?;
+    let totals = &json["data"][0]["totals"];
+    if totals.is_null() {
+        return Err(error!("llvm-cov export output has no coverage totals"));
+    }
+    Ok(CoverageSummary::from_json(totals))
+}
+
+impl std::ops::AddAssign for SummaryEntry {
+    fn add_assign(&mut self, other: SummaryEntry) {
+        self.count += other.count;
+        self.covered += other.covered;
+    }
+}
+
+impl std::ops::AddAssign for CoverageSummary {
+    fn add_assign(&mut self, other: CoverageSummary) {
+        self.functions += other.functions;
+        self.lines += other.lines;
+        self.regions += other.regions;
+        self.branches += other.branches;
+    }
+}
+
+// Whether the summary table should use ANSI colours: stdout is a terminal and
+// NO_COLOR is not set.
+pub fn use_color() -> bool {
+    use std::io::IsTerminal;
+    std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal()
+}
+
+// Renders a table with the covered/total/percent of every metric per
+// contract and a total row. Percentages are red below 50%, yellow below 80%
+// and green otherwise when `color` is set.
+pub fn summary_table(summaries: &[(String, CoverageSummary)], color: bool) -> String {
+    summary_table_with_total(summaries, None, color)
+}
+
+// Same as `summary_table`, with the given total instead of the sum of the
+// contracts, e.g. from a combined report where sources shared by several
+// contracts are only counted once.
+pub fn summary_table_with_total(
+    summaries: &[(String, CoverageSummary)],
+    total: Option<CoverageSummary>,
+    color: bool,
+) -> String {
+    let has_total = total.is_some() || summaries.len() > 1;
+    let total = total.unwrap_or_else(|| {
+        let mut total = CoverageSummary::default();
+        for (_, summary) in summaries {
+            total += *summary;
+        }
+        total
+    });
+    let total_row = ("TOTAL".to_string(), total);
+    let rows: Vec<&(String, CoverageSummary)> = summaries
+        .iter()
+        .chain(has_total.then_some(&total_row))
+        .collect();
+
+    let cells = |summary: &CoverageSummary| {
+        [
+            summary.functions,
+            summary.lines,
+            summary.regions,
+            summary.branches,
+        ]
+        .map(|entry| {
+            (
+                format!("{}/{}", entry.covered, entry.count),
+                format!("{:.2}%", entry.percent()),
+                entry.percent(),
+            )
+        })
+    };
+    let mut name_width = "Contract".len();
+    let mut count_width = 0;
+    for (name, summary) in &rows {
+        name_width = name_width.max(name.len());
+        for (count, _, _) in cells(summary) {
+            count_width = count_width.max(count.len());
+        }
+    }
+    // covered/total, a space and the percentage (up to "100.00%")
+    let column_width = count_width + 8;
+
+    let mut out = String::new();
+    let _ = write!(out, "{:<name_width$}", "Contract");
+    for header in ["Functions", "Lines", "Regions", "Branches"] {
+        let _ = write!(out, "  {:>column_width$}", header);
+    }
+    out.push('\n');
+    let _ = writeln!(out, "{}", "-".repeat(name_width + 4 * (column_width + 2)));
+    for (index, (name, summary)) in rows.iter().enumerate() {
+        if has_total && index == rows.len() - 1 {
+            let _ = writeln!(out, "{}", "-".repeat(name_width + 4 * (column_width + 2)));
+        }
+        let _ = write!(out, "{:<name_width$}", name);
+        for (count, percent, value) in cells(summary) {
+            let percent = format!("{:>7}", percent);
+            let percent = if !color {
+                percent
+            } else if value < 50.0 {
+                format!("\x1b[31m{percent}\x1b[0m")
+            } else if value < 80.0 {
+                format!("\x1b[33m{percent}\x1b[0m")
+            } else {
+                format!("\x1b[32m{percent}\x1b[0m")
+            };
+            let _ = write!(out, "  {:>count_width$} {}", count, percent);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// Index page of a combined HTML report, saved as `report/html/index.html`,
+// with the totals of every contract and of the workspace. It links to the
+// report generated by `llvm-cov show` in `report/html/combined`.
+pub fn combined_index_html(
+    summaries: &[(String, CoverageSummary)],
+    total: &CoverageSummary,
+) -> String {
+    let row = |name: &str, summary: &CoverageSummary| {
+        let mut row = format!("<tr><td>{name}</td>");
+        for entry in [
+            summary.functions,
+            summary.lines,
+            summary.regions,
+            summary.branches,
+        ] {
+            let _ = write!(
+                row,
+                "<td>{}/{} ({:.2}%)</td>",
+                entry.covered,
+                entry.count,
+                entry.percent()
+            );
+        }
+        row.push_str("</tr>\n");
+        row
+    };
+
+    let mut out = String::from(
+        "<!doctype html>\n<html>\n<head><meta charset=\"utf-8\"><title>Coverage report</title></head>\n<body>\n",
+    );
+    let _ = writeln!(
+        out,
+        "<h1>Coverage report</h1>\n<p><a href=\"{COMBINED}/index.html\">Source coverage of all contracts</a></p>"
+    );
+    out.push_str("<table>\n<tr><th>Contract</th><th>Functions</th><th>Lines</th><th>Regions</th><th>Branches</th></tr>\n");
+    for (name, summary) in summaries {
+        out.push_str(&row(name, summary));
+    }
+    out.push_str(&row("TOTAL", total));
+    out.push_str("</table>\n</body>\n</html>\n");
+    out
+}
+
+// Kind of a mapping region as reported by `llvm-cov export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionKind {
+    Code,
+    Expansion,
+    Skipped,
+    Gap,
+    Branch,
+    Other(u64),
+}
+
+impl From<u64> for RegionKind {
+    fn from(kind: u64) -> Self {
+        match kind {
+            0 => RegionKind::Code,
+            1 => RegionKind::Expansion,
+            2 => RegionKind::Skipped,
+            3 => RegionKind::Gap,
+            4 => RegionKind::Branch,
+            other => RegionKind::Other(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RegionCoverage {
+    pub filename: String,
+    pub line_start: u64,
+    pub column_start: u64,
+    pub line_end: u64,
+    pub column_end: u64,
+    pub count: u64,
+    pub kind: RegionKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BranchCoverage {
+    pub line_start: u64,
+    pub column_start: u64,
+    pub line_end: u64,
+    pub column_end: u64,
+    pub true_count: u64,
+    pub false_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FunctionCoverage {
+    // Symbol name as stored in the coverage mapping.
+    pub name: String,
+    pub demangled_name: String,
+    // Number of times the function was executed.
+    pub count: u64,
+    pub filenames: Vec<String>,
+    pub regions: Vec<RegionCoverage>,
+}
+
+impl FunctionCoverage {
+    // Covered code regions of the function.
+    pub fn region_summary(&self) -> SummaryEntry {
+        let mut summary = SummaryEntry::default();
+        for region in &self.regions {
+            if region.kind == RegionKind::Code {
+                summary.count += 1;
+                if region.count > 0 {
+                    summary.covered += 1;
+                }
+            }
+        }
+        summary
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileCoverage {
+    pub filename: String,
+    pub summary: CoverageSummary,
+    // line -> execution count, only lines with code are present
+    pub lines: BTreeMap<u64, u64>,
+    pub branches: Vec<BranchCoverage>,
+}
+
+// Coverage of a single binary, as exported by `llvm-cov export -format=text`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CoverageReport {
+    pub files: Vec<FileCoverage>,
+    pub functions: Vec<FunctionCoverage>,
+    pub totals: CoverageSummary,
+}
+
+impl CoverageReport {
+    pub fn from_json(json: &serde_json::Value) -> Result<Self> {
+        let data = &json["data"][0];
+        if data["totals"].is_null() {
+            return Err(error!("llvm-cov export output has no coverage totals"));
+        }
+        let u64_at =
+            |array: &serde_json::Value, index: usize| array[index].as_u64().unwrap_or_default();
+
+        let files = data["files"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|file| FileCoverage {
+                filename: file["filename"].as_str().unwrap_or_default().to_string(),
+                summary: CoverageSummary::from_json(&file["summary"]),
+                lines: line_counts(&file["segments"]),
+                // [line_start, column_start, line_end, column_end, true_count, false_count, ...]
+                branches: file["branches"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|branch| BranchCoverage {
+                        line_start: u64_at(branch, 0),
+                        column_start: u64_at(branch, 1),
+                        line_end: u64_at(branch, 2),
+                        column_end: u64_at(branch, 3),
+                        true_count: u64_at(branch, 4),
+                        false_count: u64_at(branch, 5),
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let functions = data["functions"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|function| {
+                let name = function["name"].as_str().unwrap_or_default().to_string();
+                let filenames: Vec<String> = function["filenames"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|filename| filename.as_str().map(String::from))
+                    .collect();
+                // [line_start, column_start, line_end, column_end, count, file_id, expanded_file_id, kind]
+                let regions = function["regions"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|region| RegionCoverage {
+                        filename: filenames
+                            .get(u64_at(region, 5) as usize)
+                            .cloned()
+                            .unwrap_or_default(),
+                        line_start: u64_at(region, 0),
+                        column_start: u64_at(region, 1),
+                        line_end: u64_at(region, 2),
+                        column_end: u64_at(region, 3),
+                        count: u64_at(region, 4),
+                        kind: RegionKind::from(u64_at(region, 7)),
+                    })
+                    .collect();
+                FunctionCoverage {
+                    demangled_name: format!("{:#}", rustc_demangle::demangle(&name)),
+                    name,
+                    count: function["count"].as_u64().unwrap_or_default(),
+                    filenames,
+                    regions,
+                }
+            })
+            .collect();
+
+        Ok(CoverageReport {
+            files,
+            functions,
+            totals: CoverageSummary::from_json(&data["totals"]),
+        })
+    }
+
+    pub fn file(&self, filename: &str) -> Option<&FileCoverage> {
+        self.files.iter().find(|file| file.filename == filename)
+    }
+}
+
+// Computes line execution counts from the coverage segments of a file, the
+// same way llvm-cov does for its line based reports.
+fn line_counts(segments: &serde_json::Value) -> BTreeMap<u64, u64> {
+    // [line, column, count, has_count, is_region_entry, is_gap_region]
+    struct Segment {
+        line: u64,
+        count: u64,
+        has_count: bool,
+        is_region_entry: bool,
+        is_gap_region: bool,
+    }
+    let segments: Vec<Segment> = segments
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|segment| Segment {
+            line: segment[0].as_u64().unwrap_or_default(),
+            count: segment[2].as_u64().unwrap_or_default(),
+            has_count: segment[3].as_bool().unwrap_or_default(),
+            is_region_entry: segment[4].as_bool().unwrap_or_default(),
+            is_gap_region: segment[5].as_bool().unwrap_or_default(),
+        })
+        .collect();
+    let is_start_of_region =
+        |segment: &Segment| !segment.is_gap_region && segment.has_count && segment.is_region_entry;
+
+    let mut lines = BTreeMap::new();
+    let (Some(first), Some(last)) = (segments.first(), segments.last()) else {
+        return lines;
+    };
+    let mut wrapped: Option<&Segment> = None;
+    let mut next = 0;
+    for line in first.line..=last.line {
+        let start = next;
+        while next < segments.len() && segments[next].line == line {
+            next += 1;
+        }
+        let line_segments = &segments[start..next];
+
+        let region_starts = line_segments
+            .iter()
+            .filter(|segment| is_start_of_region(segment))
+            .count();
+        let start_of_skipped_region = line_segments
+            .first()
+            .is_some_and(|segment| !segment.has_count && segment.is_region_entry);
+        // A line with a counted region entry is mapped even when it starts
+        // with a skipped region or the entry is a gap region.
+        let mapped = (!start_of_skipped_region
+            && (wrapped.is_some_and(|segment| segment.has_count) || region_starts > 0))
+            || line_segments
+                .iter()
+                .any(|segment| segment.is_region_entry && segment.has_count);
+        if mapped {
+            let mut count = wrapped.map(|segment| segment.count).unwrap_or_default();
+            for segment in line_segments {
+                if is_start_of_region(segment) {
+                    count = count.max(segment.count);
+                }
+            }
+            lines.insert(line, count);
+        }
+
+        if let Some(segment) = line_segments.last() {
+            wrapped = Some(segment);
+        }
+    }
+    lines
+}
+
+// Loads the coverage of a binary as typed data.
+pub fn load(profdata_path: &Path, object_file: &Path) -> Result<CoverageReport> {
+    load_with_args(profdata_path, object_file, &[])
+}
+
+// Same as `load`, with additional llvm-cov arguments, e.g. from `SourceFilter::llvm_cov_args`.
+pub fn load_with_args(
+    profdata_path: &Path,
+    object_file: &Path,
+    llvm_cov_args: &[String],
+) -> Result<CoverageReport> {
+    CoverageReport::from_json(&export_json(profdata_path, object_file, llvm_cov_args)?)
+}
@@ -0,0 +1,98 @@
+use crate::error::{error, Result};
+use crate::report::{CoverageSummary, SummaryEntry};
+use std::str::FromStr;
+
+// Minimum coverage percentage, either for all contracts or for a single one
+// when given as `<contract>=<percent>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Threshold {
+    pub contract: Option<String>,
+    pub percent: f64,
+}
+
+impl FromStr for Threshold {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (contract, percent) = match s.rsplit_once('=') {
+            Some((contract, percent)) => (Some(contract.to_string()), percent),
+            None => (None, s),
+        };
+        let percent: f64 = percent
+            .trim_end_matches('%')
+            .parse()
+            .map_err(|_| error!("Invalid coverage threshold {s:?}"))?;
+        Threshold::new(contract, percent)
+    }
+}
+
+impl Threshold {
+    pub fn new(contract: Option<String>, percent: f64) -> Result<Self> {
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(error!(
+                "Coverage threshold {percent} is not between 0 and 100"
+            ));
+        }
+        Ok(Threshold { contract, percent })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Thresholds {
+    pub lines: Vec<Threshold>,
+    pub functions: Vec<Threshold>,
+    pub regions: Vec<Threshold>,
+}
+
+fn same_contract(a: &str, b: &str) -> bool {
+    a.replace('-', "_") == b.replace('-', "_")
+}
+
+// Contract specific thresholds take precedence over global ones, and later
+// thresholds over earlier ones.
+fn threshold_for(thresholds: &[Threshold], contract: &str) -> Option<f64> {
+    thresholds
+        .iter()
+        .rev()
+        .find(|threshold| {
+            threshold
+                .contract
+                .as_deref()
+                .is_some_and(|name| same_contract(name, contract))
+        })
+        .or_else(|| {
+            thresholds
+                .iter()
+                .rev()
+                .find(|threshold| threshold.contract.is_none())
+        })
+        .map(|threshold| threshold.percent)
+}
+
+impl Thresholds {
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty() && self.functions.is_empty() && self.regions.is_empty()
+    }
+
+    // Returns a description of every threshold the contract does not meet.
+    pub fn check(&self, contract: &str, summary: &CoverageSummary) -> Vec<String> {
+        let metrics: [(&str, &[Threshold], SummaryEntry); 3] = [
+            ("lines", &self.lines, summary.lines),
+            ("functions", &self.functions, summary.functions),
+            ("regions", &self.regions, summary.regions),
+        ];
+        metrics
+            .into_iter()
+            .filter_map(|(metric, thresholds, entry)| {
+                let minimum = threshold_for(thresholds, contract)?;
+                (entry.percent() < minimum).then(|| {
+                    format!(
+                        "{contract}: {metric} coverage {:.2}% is below {:.2}%",
+                        entry.percent(),
+                        minimum
+                    )
+                })
+            })
+            .collect()
+    }
+}
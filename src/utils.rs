@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Result};
+use crate::error::{error, Error, Result};
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::BufRead;
@@ -14,18 +14,28 @@ pub fn run_command(command: &str, args: &[&str], dir: Option<&Path>) -> Result<S
         cmd.current_dir(dir);
     }
     cmd.args(args);
-    let output = cmd
-        .output()
-        .map_err(|_| anyhow!("Failed to execute command \"{command} {args:?}\""))?;
+    let output = cmd.output().map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => Error::ToolingMissing {
+            tool: command.to_string(),
+            message: err.to_string(),
+        },
+        _ => error!("Failed to execute command \"{command} {args:?}\": {err}"),
+    })?;
     if !output.status.success() {
-        return Err(anyhow!(
-            "Command \"{command} {args:?}\" failed with status code {}: {}",
-            output.status.code().unwrap_or(-1),
-            String::from_utf8(output.stderr)?
-        ));
+        return Err(Error::CommandFailed {
+            command: format!("{command} {args:?}"),
+            status: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
     }
     String::from_utf8(output.stdout)
-        .map_err(|_| anyhow!("Failed to read command \"{command} {args:?}\" output"))
+        .map_err(|_| error!("Failed to read command \"{command} {args:?}\" output"))
+}
+
+// Paths passed as command arguments or glob patterns must be valid UTF-8.
+pub fn path_to_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| error!("Path {:?} is not valid UTF-8", path))
 }
 
 pub fn find_file(dir: &Path, alternatives: &[&str]) -> Result<PathBuf> {
@@ -33,11 +43,12 @@ pub fn find_file(dir: &Path, alternatives: &[&str]) -> Result<PathBuf> {
         .iter()
         .map(|path| dir.join(path))
         .find(|path| path.exists())
-        .ok_or_else(|| {
-            anyhow!(
+        .ok_or_else(|| match alternatives {
+            [path] => Error::FileNotFound(dir.join(path)),
+            _ => error!(
                 "Could not find any of the alternative paths: {:?}",
                 alternatives
-            )
+            ),
         })
 }
 
@@ -56,6 +67,25 @@ pub enum FileOperation {
     },
 }
 
+impl FileOperation {
+    pub fn pattern(&self) -> &str {
+        match self {
+            FileOperation::ReplaceText { pattern, .. }
+            | FileOperation::AddBefore { pattern, .. }
+            | FileOperation::AddAfter { pattern, .. } => pattern,
+        }
+    }
+
+    // The replacement or the added line.
+    pub fn text(&self) -> &str {
+        match self {
+            FileOperation::ReplaceText { replacement, .. } => replacement,
+            FileOperation::AddBefore { new_line, .. }
+            | FileOperation::AddAfter { new_line, .. } => new_line,
+        }
+    }
+}
+
 pub fn modify_file(file_path: PathBuf, operation: FileOperation) -> std::io::Result<()> {
     let file = File::open(&file_path)?;
     let reader = BufReader::new(file);
@@ -103,9 +133,8 @@ pub fn modify_file(file_path: PathBuf, operation: FileOperation) -> std::io::Res
         }
         Ok(())
     } else {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("No modifications were made in {file_path:?}"),
-        ))
+        Err(std::io::Error::other(format!(
+            "No modifications were made in {file_path:?}"
+        )))
     }
 }
@@ -0,0 +1,75 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+// Writes the coverage collected so far to
+// `$WASMCOV_DIR/profraw/<binary_name>/<hash>.profraw`, named like the files
+// of `wasmcov::profraw::save_profraw`, and resets the counters so the next
+// call only writes new coverage. The wasmcov directory
+// must be preopened by the runtime, e.g. `wasmtime run --dir <dir> --env WASMCOV_DIR=<dir>`,
+// which is what `cargo wasmcov run` and `test` do for `.wasm` binaries.
+pub fn write_coverage(binary_name: &str) -> io::Result<PathBuf> {
+    let wasmcov_dir = std::env::var("WASMCOV_DIR")
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, "WASMCOV_DIR is not set"))?;
+    let profraw_dir = PathBuf::from(wasmcov_dir).join("profraw").join(binary_name);
+    fs::create_dir_all(&profraw_dir)?;
+
+    let mut coverage = vec![];
+    unsafe { minicov::capture_coverage(&mut coverage) }
+        .map_err(|err| io::Error::other(err.to_string()))?;
+
+    let path = profraw_dir.join(format!("{:x}.profraw", md5::compute(&coverage)));
+    fs::write(&path, coverage)?;
+    minicov::reset_coverage();
+    Ok(path)
+}
+
+static AT_EXIT_BINARY_NAME: OnceLock<String> = OnceLock::new();
+
+extern "C" {
+    // Provided by wasi-libc, handlers run when `_start` returns or `exit` is called.
+    fn atexit(handler: extern "C" fn()) -> i32;
+}
+
+extern "C" fn write_coverage_at_exit() {
+    if let Some(binary_name) = AT_EXIT_BINARY_NAME.get() {
+        if let Err(err) = write_coverage(binary_name) {
+            eprintln!("wasmcov: failed to write coverage: {err}");
+        }
+    }
+}
+
+// Writes the coverage when the program exits, for `_start` programs which
+// do not export `capture_coverage`. Call it at the beginning of `main`.
+pub fn capture_at_exit(binary_name: impl Into<String>) {
+    if AT_EXIT_BINARY_NAME.set(binary_name.into()).is_ok() {
+        unsafe {
+            atexit(write_coverage_at_exit);
+        }
+    }
+}
+
+// Exports `capture_coverage` for hosts which call it explicitly, writing the
+// coverage of the current package to the wasmcov directory.
+#[macro_export]
+macro_rules! add_wasi_coverage {
+    ($($variant:literal)?) => {
+        #[no_mangle]
+        pub extern "C" fn capture_coverage() {
+            $crate::wasi::write_coverage(&$crate::binary_name!($($variant)?))
+                .expect("Failed to write coverage");
+        }
+    };
+}
+
+// Writes the coverage of the current package when the program exits.
+#[macro_export]
+macro_rules! capture_coverage_at_exit {
+    ($($variant:literal)?) => {
+        $crate::wasi::capture_at_exit($crate::binary_name!($($variant)?))
+    };
+}
+
+pub use add_wasi_coverage as add_coverage;
+pub use capture_coverage_at_exit;
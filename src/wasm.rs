@@ -0,0 +1,150 @@
+use crate::error::{error, Result};
+use crate::profraw::Reader;
+use std::collections::HashMap;
+
+const WASM_MAGIC: &[u8; 4] = b"\0asm";
+const CUSTOM_SECTION_ID: u8 = 0;
+const DATA_SECTION_ID: u8 = 11;
+const DATA_SEGMENT_NAMES_SUBSECTION_ID: u8 = 9;
+
+pub const COVMAP_SECTION: &str = "__llvm_covmap";
+pub const COVFUN_SECTION: &str = "__llvm_covfun";
+pub const PRF_NAMES_SECTION: &str = "__llvm_prf_names";
+
+// Coverage mapping sections of an instrumented wasm module.
+pub struct CoverageSections {
+    pub covmap: Vec<u8>,
+    pub covfun: Vec<u8>,
+    pub prf_names: Vec<u8>,
+}
+
+// Custom sections and named data segments of a wasm module.
+#[derive(Default)]
+pub struct WasmSections<'a> {
+    pub custom_sections: HashMap<String, &'a [u8]>,
+    pub data_segments: Vec<&'a [u8]>,
+    pub data_segment_names: HashMap<String, usize>,
+}
+
+impl<'a> WasmSections<'a> {
+    // Looks up a section by name, first in custom sections and then in data
+    // segments named in the "name" custom section.
+    pub fn get(&self, name: &str) -> Option<&'a [u8]> {
+        self.custom_sections.get(name).copied().or_else(|| {
+            self.data_segment_names
+                .get(name)
+                .and_then(|index| self.data_segments.get(*index).copied())
+        })
+    }
+}
+
+fn uleb128(reader: &mut Reader) -> Result<usize> {
+    Ok(reader.uleb128()? as usize)
+}
+
+fn read_name(reader: &mut Reader) -> Result<String> {
+    let len = uleb128(reader)?;
+    Ok(String::from_utf8(reader.bytes(len)?.to_vec())?)
+}
+
+// Skips a constant expression such as the offset of an active data segment.
+fn skip_const_expr(reader: &mut Reader) -> Result<()> {
+    loop {
+        match reader.u8()? {
+            0x0b => return Ok(()),
+            // i32.const, i64.const, global.get
+            0x41 | 0x42 | 0x23 => {
+                reader.uleb128()?;
+            }
+            // extended constant expressions: add, sub, mul
+            0x6a | 0x6b | 0x6c | 0x7c | 0x7d | 0x7e => {}
+            opcode => {
+                return Err(error!(
+                    "Unsupported opcode {:#x} in constant expression",
+                    opcode
+                ))
+            }
+        }
+    }
+}
+
+pub fn parse_sections(data: &[u8]) -> Result<WasmSections<'_>> {
+    let mut reader = Reader::at(data, 0);
+    if reader.bytes(4)? != WASM_MAGIC {
+        return Err(error!("Not a wasm module"));
+    }
+    reader.bytes(4)?;
+
+    let mut sections = WasmSections::default();
+    let mut names_section = None;
+    while !reader.at_end() {
+        let id = reader.u8()?;
+        let size = uleb128(&mut reader)?;
+        let mut section = Reader::at(reader.bytes(size)?, 0);
+        match id {
+            CUSTOM_SECTION_ID => {
+                let name = read_name(&mut section)?;
+                let payload = section.remaining();
+                if name == "name" {
+                    names_section = Some(payload);
+                }
+                sections.custom_sections.insert(name, payload);
+            }
+            DATA_SECTION_ID => {
+                for _ in 0..section.uleb128()? {
+                    match section.uleb128()? {
+                        0 => skip_const_expr(&mut section)?,
+                        1 => {}
+                        2 => {
+                            section.uleb128()?;
+                            skip_const_expr(&mut section)?;
+                        }
+                        flags => return Err(error!("Unsupported data segment flags {}", flags)),
+                    }
+                    let len = uleb128(&mut section)?;
+                    sections.data_segments.push(section.bytes(len)?);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(names_section) = names_section {
+        sections.data_segment_names = parse_data_segment_names(names_section)?;
+    }
+
+    Ok(sections)
+}
+
+fn parse_data_segment_names(data: &[u8]) -> Result<HashMap<String, usize>> {
+    let mut reader = Reader::at(data, 0);
+    let mut names = HashMap::new();
+    while !reader.at_end() {
+        let id = reader.u8()?;
+        let size = uleb128(&mut reader)?;
+        let mut subsection = Reader::at(reader.bytes(size)?, 0);
+        if id != DATA_SEGMENT_NAMES_SUBSECTION_ID {
+            continue;
+        }
+        for _ in 0..subsection.uleb128()? {
+            let index = uleb128(&mut subsection)?;
+            names.insert(read_name(&mut subsection)?, index);
+        }
+    }
+    Ok(names)
+}
+
+pub fn read_coverage_sections(data: &[u8]) -> Result<CoverageSections> {
+    let sections = parse_sections(data)?;
+    let find = |name: &str| {
+        sections
+            .get(name)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| error!("Section {} not found in wasm module", name))
+    };
+    Ok(CoverageSections {
+        covmap: find(COVMAP_SECTION)?,
+        covfun: find(COVFUN_SECTION)?,
+        prf_names: find(PRF_NAMES_SECTION)?,
+    })
+}
@@ -0,0 +1,76 @@
+use crate::dir::WasmcovDir;
+use crate::error::{error, Result};
+use crate::profraw;
+use anyhow::anyhow;
+use wasmtime::{AsContextMut, Caller, Instance, Linker};
+
+// Import module and name of the host function guests call with their
+// coverage, the same import the patched NEAR sandbox provides to
+// `near::add_coverage!`.
+pub const IMPORT_MODULE: &str = "env";
+pub const IMPORT_NAME: &str = "sandbox_capture_coverage";
+
+// Export called by the host to make the guest hand over its coverage.
+pub const CAPTURE_COVERAGE_EXPORT: &str = "capture_coverage";
+
+// Defines `env.sandbox_capture_coverage(name_len, name_ptr, coverage_len, coverage_ptr)`,
+// which reads the binary name and the profraw data from the guest memory and
+// saves them to the profraw directory of `wasmcov_dir`.
+pub fn add_to_linker<T: 'static>(linker: &mut Linker<T>, wasmcov_dir: &WasmcovDir) -> Result<()> {
+    add_to_linker_with_test_name(linker, wasmcov_dir, |_| None)
+}
+
+// Like `add_to_linker`, also recording the coverage as produced by the test
+// `test_name` returns for the store data of the call, see wasmcov::attribution.
+pub fn add_to_linker_with_test_name<T: 'static>(
+    linker: &mut Linker<T>,
+    wasmcov_dir: &WasmcovDir,
+    test_name: fn(&T) -> Option<&str>,
+) -> Result<()> {
+    let wasmcov_dir = wasmcov_dir.clone();
+    linker.func_wrap(
+        IMPORT_MODULE,
+        IMPORT_NAME,
+        move |mut caller: Caller<'_, T>,
+              name_len: u64,
+              name_ptr: u64,
+              coverage_len: u64,
+              coverage_ptr: u64|
+              -> wasmtime::Result<()> {
+            let memory = caller
+                .get_export("memory")
+                .and_then(|export| export.into_memory())
+                .ok_or_else(|| anyhow!("Guest does not export its memory"))?;
+            let data = memory.data(&caller);
+            let read = |ptr: u64, len: u64| {
+                usize::try_from(ptr)
+                    .ok()
+                    .zip(usize::try_from(len).ok())
+                    .and_then(|(ptr, len)| data.get(ptr..ptr.checked_add(len)?))
+                    .ok_or_else(|| anyhow!("Coverage data is out of guest memory bounds"))
+            };
+            let name = String::from_utf8(read(name_ptr, name_len)?.to_vec())
+                .map_err(|_| anyhow!("Invalid coverage binary name"))?;
+            let coverage = read(coverage_ptr, coverage_len)?.to_vec();
+            let test_name = test_name(caller.data());
+            profraw::save_profraw(&wasmcov_dir, &name, &coverage, test_name)?;
+            Ok(())
+        },
+    )?;
+    Ok(())
+}
+
+// Calls the guest's `capture_coverage` export, if it has one, so the coverage
+// collected so far is passed to the import defined by `add_to_linker`.
+// Returns whether coverage was captured. Call it after each invocation of the
+// guest, before the instance is dropped.
+pub fn capture_coverage(mut store: impl AsContextMut, instance: &Instance) -> Result<bool> {
+    let Some(function) = instance.get_func(&mut store, CAPTURE_COVERAGE_EXPORT) else {
+        return Ok(false);
+    };
+    function
+        .typed::<(), ()>(&store)?
+        .call(&mut store, ())
+        .map_err(|err| error!("capture_coverage function failed: {}", err))?;
+    Ok(true)
+}
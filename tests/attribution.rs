@@ -0,0 +1,143 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::process::Command;
+use wasmcov::attribution::{self, TestMatrix};
+use wasmcov::dir::WasmcovDir;
+use wasmcov::profraw;
+
+fn matrix(tests: &[(&str, &[&str])]) -> TestMatrix {
+    TestMatrix {
+        tests: tests
+            .iter()
+            .map(|(test, functions)| {
+                let functions = functions.iter().map(|f| f.to_string()).collect();
+                (test.to_string(), functions)
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_sanitize_test_name() {
+    assert_eq!(
+        attribution::sanitize_test_name("tests::transfer::test_ok"),
+        "tests::transfer::test_ok"
+    );
+    assert_eq!(
+        attribution::sanitize_test_name("test a/b\\c-1.2"),
+        "test_a_b_c-1.2"
+    );
+}
+
+#[test]
+fn test_patched_hosts_share_the_sanitizer() {
+    let source = attribution::record_test_source("None::<String>");
+    assert!(source.contains(attribution::SANITIZE_TEST_NAME_SOURCE));
+    assert!(attribution::SANITIZE_TEST_NAME_SOURCE.starts_with("fn sanitize_test_name"));
+}
+
+#[test]
+fn test_profraw_files_by_test() {
+    let dir = tempfile::tempdir().unwrap();
+    let wasmcov_dir = WasmcovDir::new(dir.path()).unwrap();
+    let first = profraw::save_profraw(&wasmcov_dir, "demo", b"first", Some("test a")).unwrap();
+    let second = profraw::save_profraw(&wasmcov_dir, "demo", b"second", Some("test_b")).unwrap();
+    // Coverage shared by two tests is stored once and recorded for both.
+    profraw::save_profraw(&wasmcov_dir, "demo", b"first", Some("test_b")).unwrap();
+    profraw::save_profraw(&wasmcov_dir, "demo", b"third", None).unwrap();
+
+    let crate_profraw_dir = dir.path().join("profraw/demo");
+    let mut by_test = attribution::profraw_files_by_test(&crate_profraw_dir).unwrap();
+    by_test.values_mut().for_each(|files| files.sort());
+    let mut both = vec![first.clone(), second.clone()];
+    both.sort();
+    assert_eq!(
+        by_test,
+        BTreeMap::from([
+            ("test_a".to_string(), vec![first.clone()]),
+            ("test_b".to_string(), both),
+        ])
+    );
+
+    // Removing the records of a profraw file drops tests left without records.
+    let hash = first.file_stem().unwrap().to_string_lossy().into_owned();
+    attribution::remove_test_records(&crate_profraw_dir, &BTreeSet::from([hash])).unwrap();
+    let by_test = attribution::profraw_files_by_test(&crate_profraw_dir).unwrap();
+    assert_eq!(
+        by_test,
+        BTreeMap::from([("test_b".to_string(), vec![second])])
+    );
+    assert!(!crate_profraw_dir.join("tests/test_a").exists());
+}
+
+#[test]
+fn test_profraw_files_by_test_skips_removed_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let wasmcov_dir = WasmcovDir::new(dir.path()).unwrap();
+    let path = profraw::save_profraw(&wasmcov_dir, "demo", b"first", Some("test_a")).unwrap();
+    fs::remove_file(path).unwrap();
+
+    let crate_profraw_dir = dir.path().join("profraw/demo");
+    assert!(attribution::profraw_files_by_test(&crate_profraw_dir)
+        .unwrap()
+        .is_empty());
+    assert!(
+        attribution::profraw_files_by_test(&dir.path().join("profraw/other"))
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_unique_functions() {
+    let matrix = matrix(&[
+        ("test_a", &["demo::add", "demo::new"]),
+        ("test_b", &["demo::new", "demo::sub"]),
+        ("test_c", &["demo::new"]),
+    ]);
+    assert_eq!(matrix.functions().len(), 3);
+    assert_eq!(
+        matrix.unique_functions("test_a"),
+        BTreeSet::from([&"demo::add".to_string()])
+    );
+    assert!(matrix.unique_functions("test_c").is_empty());
+    assert!(matrix.unique_functions("missing").is_empty());
+}
+
+#[test]
+fn test_matrix_csv_and_json() {
+    let matrix = matrix(&[
+        (
+            "test_a",
+            &["demo::add", "<demo::Token as \"Display\">::fmt"],
+        ),
+        ("test_b", &["demo::add"]),
+    ]);
+    assert_eq!(
+        matrix.to_csv(),
+        "function,\"test_a\",\"test_b\"\n\
+         \"<demo::Token as \"\"Display\"\">::fmt\",1,0\n\
+         \"demo::add\",1,1\n"
+    );
+    assert_eq!(
+        matrix.to_json(),
+        serde_json::json!({
+            "test_a": ["<demo::Token as \"Display\">::fmt", "demo::add"],
+            "test_b": ["demo::add"],
+        })
+    );
+}
+
+#[test]
+fn test_sandbox_test_name_env() {
+    let (key, value) = attribution::sandbox_test_name_env("test_transfer");
+    assert_eq!(key, attribution::TEST_NAME_ENV);
+    let output = Command::new("sh")
+        .args(["-c", "printf %s \"$WASMCOV_TEST_NAME\""])
+        .env(key, value)
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "test_transfer");
+    // Only the spawned command gets the name.
+    assert!(std::env::var_os(attribution::TEST_NAME_ENV).is_none());
+}
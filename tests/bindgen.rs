@@ -0,0 +1,58 @@
+use std::fs;
+use std::path::Path;
+use wasmcov::build::{self, WASM_BINDGEN_TARGET};
+use wasmcov::dir::{BinaryKey, WasmcovDir};
+use wasmcov::report::{self, MergeOptions};
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn test_wasm_bindgen_tests_default_to_wasm32_unknown_unknown() {
+    assert_eq!(
+        build::with_default_target(args(&["--lib"]), WASM_BINDGEN_TARGET),
+        args(&["--lib", "--target", "wasm32-unknown-unknown"])
+    );
+    for cargo_args in [
+        args(&["--target", "wasm32-wasip1"]),
+        args(&["--target=wasm32-wasip1", "--lib"]),
+    ] {
+        assert_eq!(
+            build::with_default_target(cargo_args.clone(), WASM_BINDGEN_TARGET),
+            cargo_args
+        );
+    }
+}
+
+#[test]
+fn test_wasm_bindgen_profraw_pairs_with_object_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let wasmcov_dir = WasmcovDir::new(dir.path()).unwrap();
+    let binary = Path::new("target/wasm32-unknown-unknown/debug/deps/my_crate-0123abcd.wasm");
+
+    let profile_file =
+        build::wasm_bindgen_profile_file(&wasmcov_dir, binary, Some("browser")).unwrap();
+    let profraw_dir = wasmcov_dir
+        .profraw()
+        .unwrap()
+        .join("my_crate-0123abcd@browser");
+    assert_eq!(profile_file, profraw_dir.join("%m_%p.profraw"));
+    assert!(profraw_dir.is_dir());
+
+    // The profraw files written by the runner are merged and reported with
+    // the object file process_wasm_files generates from the test binary.
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/profraw");
+    fs::copy(fixtures.join("0.profraw"), profraw_dir.join("0.profraw")).unwrap();
+    let key = BinaryKey::new("my_crate-0123abcd", Some("browser")).unwrap();
+    fs::write(wasmcov_dir.object_file_of(&key).unwrap(), []).unwrap();
+
+    report::merge_all(&wasmcov_dir, &MergeOptions::default()).unwrap();
+    let profdata_files = report::profdata_files(&wasmcov_dir).unwrap();
+    assert_eq!(profdata_files.len(), 1);
+    assert_eq!(profdata_files[0].0, "my_crate-0123abcd@browser");
+    assert_eq!(
+        report::find_object_file(&wasmcov_dir, &profdata_files[0].0).unwrap(),
+        Some(wasmcov_dir.object_file_of(&key).unwrap())
+    );
+}
@@ -0,0 +1,82 @@
+use wasmcov::cobertura::{parse_lcov, to_cobertura};
+
+// `llvm-cov export -format=lcov` output for two files.
+const LCOV: &str = "\
+SF:/project/src/lib.rs
+FN:1,_ZN4demo3add17h0123456789abcdefE
+FN:7,demo::<impl Ord>::cmp
+FNDA:3,_ZN4demo3add17h0123456789abcdefE
+FNDA:0,demo::<impl Ord>::cmp
+FNF:2
+FNH:1
+BRDA:2,0,0,3
+BRDA:2,0,1,-
+LF:4
+LH:3
+DA:1,3
+DA:2,3
+DA:3,0
+DA:7,1
+end_of_record
+SF:/project/src/a&b.rs
+DA:1,0
+DA:1,2
+end_of_record
+";
+
+#[test]
+fn test_parse_lcov() {
+    let files = parse_lcov(LCOV).unwrap();
+    assert_eq!(files.len(), 2);
+
+    let lib = &files[0];
+    assert_eq!(lib.path, "/project/src/lib.rs");
+    assert_eq!(
+        lib.lines.iter().map(|(l, h)| (*l, *h)).collect::<Vec<_>>(),
+        [(1, 3), (2, 3), (3, 0), (7, 1)]
+    );
+    assert_eq!(lib.functions["_ZN4demo3add17h0123456789abcdefE"], (1, 3));
+    // Names may contain commas.
+    assert_eq!(lib.functions["demo::<impl Ord>::cmp"], (7, 0));
+    // A branch of a block which was never executed has no hits.
+    assert_eq!(lib.branches[&2], [3, 0]);
+
+    // Hits of the same line are summed.
+    assert_eq!(files[1].lines[&1], 2);
+}
+
+#[test]
+fn test_parse_invalid_lcov() {
+    let err = parse_lcov("SF:/project/src/lib.rs\nDA:one,1\nend_of_record\n").unwrap_err();
+    assert_eq!(err.to_string(), "Invalid lcov line: DA:one,1");
+}
+
+#[test]
+fn test_to_cobertura() {
+    let files = parse_lcov(LCOV).unwrap();
+    let xml = to_cobertura("demo", &files);
+
+    assert!(xml.starts_with("<?xml version=\"1.0\" ?>\n"));
+    assert!(xml.contains(
+        "<coverage line-rate=\"0.8000\" branch-rate=\"0.5000\" lines-covered=\"4\" lines-valid=\"5\" branches-covered=\"1\" branches-valid=\"2\""
+    ));
+    assert!(xml.contains(
+        "<package name=\"demo\" line-rate=\"0.8000\" branch-rate=\"0.5000\" complexity=\"0\">"
+    ));
+    assert!(xml.contains(
+        "<class name=\"/project/src/lib.rs\" filename=\"/project/src/lib.rs\" line-rate=\"0.7500\" branch-rate=\"0.5000\" complexity=\"0\">"
+    ));
+    assert!(xml.contains(
+        "<method name=\"demo::&lt;impl Ord&gt;::cmp\" signature=\"\" line-rate=\"0\" branch-rate=\"1\" complexity=\"0\">"
+    ));
+    assert!(xml.contains("<lines><line number=\"1\" hits=\"3\"/></lines>"));
+    assert!(xml.contains(
+        "<line number=\"2\" hits=\"3\" branch=\"true\" condition-coverage=\"50% (1/2)\"/>"
+    ));
+    assert!(xml.contains("<line number=\"3\" hits=\"0\" branch=\"false\"/>"));
+    // File without branches
+    assert!(xml.contains(
+        "<class name=\"/project/src/a&amp;b.rs\" filename=\"/project/src/a&amp;b.rs\" line-rate=\"1.0000\" branch-rate=\"1\" complexity=\"0\">"
+    ));
+    assert!(xml.ends_with("</packages>\n</coverage>\n"));
+}
@@ -0,0 +1,161 @@
+use std::fs;
+use std::path::PathBuf;
+use wasmcov::config::{Config, MetricThresholds, ThresholdsConfig};
+use wasmcov::threshold::Threshold;
+
+#[test]
+fn test_from_cargo_toml() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("Cargo.toml");
+
+    fs::write(
+        &path,
+        r#"
+[package]
+name = "demo"
+
+[package.metadata.wasmcov]
+wasmcov-dir = "target/wasmcov"
+near-binary = "/opt/neard"
+formats = ["html", "lcov"]
+
+[package.metadata.wasmcov.thresholds]
+lines = 80
+"#,
+    )
+    .unwrap();
+    let config = Config::from_cargo_toml(&path).unwrap().unwrap();
+    // Relative paths are relative to the manifest.
+    assert_eq!(
+        config.wasmcov_dir,
+        Some(tempdir.path().join("target/wasmcov"))
+    );
+    assert_eq!(config.near_binary, Some(PathBuf::from("/opt/neard")));
+    assert_eq!(config.formats.len(), 2);
+    assert_eq!(config.thresholds.lines, Some(80.0));
+
+    fs::write(
+        &path,
+        "[workspace]\nmembers = [\"demo\"]\n\n[workspace.metadata.wasmcov]\ncosmwasm = \"2.2.2\"\n",
+    )
+    .unwrap();
+    let config = Config::from_cargo_toml(&path).unwrap().unwrap();
+    assert_eq!(config.cosmwasm.as_deref(), Some("2.2.2"));
+
+    fs::write(
+        &path,
+        "[package]\nname = \"demo\"\n\n[package.metadata.other]\nkey = 1\n",
+    )
+    .unwrap();
+    assert_eq!(Config::from_cargo_toml(&path).unwrap(), None);
+
+    fs::write(
+        &path,
+        "[package]\nname = \"demo\"\n\n[package.metadata.wasmcov]\nnear-version = \"1.40.0\"\n",
+    )
+    .unwrap();
+    let err = Config::from_cargo_toml(&path).unwrap_err();
+    assert!(
+        err.to_string().starts_with("Invalid wasmcov metadata in"),
+        "{err}"
+    );
+    assert!(err.to_string().contains("near-version"), "{err}");
+}
+
+#[test]
+fn test_find_nearest_config() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let root = tempdir.path();
+    let member = root.join("contracts/demo");
+    let other = root.join("contracts/other");
+    fs::create_dir_all(member.join("src")).unwrap();
+    fs::create_dir_all(&other).unwrap();
+    fs::write(root.join("wasmcov.toml"), "near = \"1.40.0\"\n").unwrap();
+    fs::write(
+        root.join("Cargo.toml"),
+        "[workspace.metadata.wasmcov]\nnear = \"2.0.0\"\n",
+    )
+    .unwrap();
+    fs::write(
+        member.join("Cargo.toml"),
+        "[package]\nname = \"demo\"\n\n[package.metadata.wasmcov]\nnear = \"1.39.0\"\n",
+    )
+    .unwrap();
+    fs::write(other.join("Cargo.toml"), "[package]\nname = \"other\"\n").unwrap();
+
+    // The package metadata is nearer than the workspace wasmcov.toml.
+    let (config, path) = Config::find(&member.join("src")).unwrap();
+    assert_eq!(config.near.as_deref(), Some("1.39.0"));
+    assert_eq!(path, Some(member.join("Cargo.toml")));
+
+    // A Cargo.toml without wasmcov metadata is skipped, and wasmcov.toml is
+    // preferred over Cargo.toml in the same directory.
+    let (config, path) = Config::find(&other).unwrap();
+    assert_eq!(config.near.as_deref(), Some("1.40.0"));
+    assert_eq!(path, Some(root.join("wasmcov.toml")));
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let (config, path) = Config::find(tempdir.path()).unwrap();
+    assert_eq!(config, Config::default());
+    assert_eq!(path, None);
+}
+
+#[test]
+fn test_to_thresholds() {
+    let config = ThresholdsConfig {
+        lines: Some(80.0),
+        functions: None,
+        regions: Some(60.0),
+        contracts: [
+            (
+                "helper_contract".to_string(),
+                MetricThresholds {
+                    lines: Some(50.0),
+                    functions: Some(40.0),
+                    regions: None,
+                },
+            ),
+            (
+                "token".to_string(),
+                MetricThresholds {
+                    lines: Some(90.0),
+                    ..Default::default()
+                },
+            ),
+        ]
+        .into_iter()
+        .collect(),
+    };
+    let thresholds = config.to_thresholds().unwrap();
+    let threshold = |contract: Option<&str>, percent| Threshold {
+        contract: contract.map(str::to_string),
+        percent,
+    };
+    assert_eq!(
+        thresholds.lines,
+        [
+            threshold(None, 80.0),
+            threshold(Some("helper_contract"), 50.0),
+            threshold(Some("token"), 90.0)
+        ]
+    );
+    assert_eq!(
+        thresholds.functions,
+        [threshold(Some("helper_contract"), 40.0)]
+    );
+    assert_eq!(thresholds.regions, [threshold(None, 60.0)]);
+
+    assert!(ThresholdsConfig::default()
+        .to_thresholds()
+        .unwrap()
+        .is_empty());
+
+    let config = ThresholdsConfig {
+        regions: Some(120.0),
+        ..Default::default()
+    };
+    assert_eq!(
+        config.to_thresholds().unwrap_err().to_string(),
+        "Coverage threshold 120 is not between 0 and 100"
+    );
+}
@@ -0,0 +1,27 @@
+// call_raw of cosmwasm-vm 2.2.2 src/calls.rs, the function patched by wasmcov.
+/// Calls a function with the given arguments.
+/// The exported function must return exactly one result (an offset to the result Region).
+pub(crate) fn call_raw<A, S, Q>(
+    instance: &mut Instance<A, S, Q>,
+    name: &str,
+    args: &[&[u8]],
+    result_max_length: usize,
+) -> VmResult<Vec<u8>>
+where
+    A: BackendApi + 'static,
+    S: Storage + 'static,
+    Q: Querier + 'static,
+{
+    let mut arg_region_ptrs = Vec::<Value>::with_capacity(args.len());
+    for arg in args {
+        let region_ptr = instance.allocate(arg.len())?;
+        instance.write_memory(region_ptr, arg)?;
+        arg_region_ptrs.push(region_ptr.into());
+    }
+    let result = instance.call_function1(name, &arg_region_ptrs)?;
+    let res_region_ptr = ref_to_u32(&result)?;
+    let data = instance.read_memory(res_region_ptr, result_max_length)?;
+    // free return value in wasm (arguments were freed in wasm code)
+    instance.deallocate(res_region_ptr)?;
+    Ok(data)
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "cosmwasm")]
+
+use std::fs;
+use std::path::Path;
+use wasmcov::cosmwasm_vm;
+
+const ANCHOR: &str = "    instance.deallocate(res_region_ptr)?;";
+
+fn fixture() -> String {
+    fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/cosmwasm/calls.rs"))
+        .unwrap()
+}
+
+#[test]
+fn test_modify_calls_captures_coverage_after_the_call() {
+    let dir = tempfile::tempdir().unwrap();
+    let calls_path = dir.path().join("calls.rs");
+    fs::write(&calls_path, fixture()).unwrap();
+
+    cosmwasm_vm::modify_calls(calls_path.clone()).unwrap();
+    let patched = fs::read_to_string(&calls_path).unwrap();
+    let (before, after) = patched.split_once(ANCHOR).unwrap();
+    assert!(fixture().starts_with(before));
+    // The coverage is captured once the result has been read, before returning it.
+    let (added, rest) = after.split_once("\n    Ok(data)\n").unwrap();
+    assert!(added.contains("instance.call_function1(\"capture_coverage\", &[])"));
+    assert!(added.contains("VmError::generic_err"));
+    assert!(!added.contains(".unwrap()"));
+    assert_eq!(rest, "}\n");
+
+    // Patching again leaves the file unchanged
+    cosmwasm_vm::modify_calls(calls_path.clone()).unwrap();
+    assert_eq!(fs::read_to_string(&calls_path).unwrap(), patched);
+}
+
+#[test]
+fn test_modify_calls_fails_without_anchor() {
+    let dir = tempfile::tempdir().unwrap();
+    let calls_path = dir.path().join("calls.rs");
+    fs::write(&calls_path, fixture().replace(ANCHOR, "")).unwrap();
+
+    assert!(cosmwasm_vm::modify_calls(calls_path.clone()).is_err());
+    assert_eq!(
+        fs::read_to_string(&calls_path).unwrap(),
+        fixture().replace(ANCHOR, "")
+    );
+}
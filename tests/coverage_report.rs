@@ -0,0 +1,255 @@
+use serde_json::json;
+use std::collections::BTreeMap;
+use wasmcov::report::{
+    export_args, parse_summary, report_formats, summary_table, summary_table_with_total,
+    CoverageReport, CoverageSummary, RegionKind, ReportFormat, SummaryEntry,
+};
+
+// `llvm-cov export -format=text` output for a single file. Segments are
+// [line, column, count, has_count, is_region_entry, is_gap_region].
+fn export_json() -> serde_json::Value {
+    let summary = json!({
+        "functions": {"count": 2, "covered": 1},
+        "lines": {"count": 7, "covered": 5},
+        "regions": {"count": 5, "covered": 3},
+        "branches": {"count": 2, "covered": 1}
+    });
+    json!({
+        "data": [{
+            "files": [{
+                "filename": "/project/src/lib.rs",
+                "summary": summary,
+                "segments": [
+                    // code region with count 3
+                    [1, 1, 3, true, true, false],
+                    // gap region, e.g. after a closing brace
+                    [2, 5, 0, true, true, true],
+                    [4, 1, 2, true, true, false],
+                    // two regions starting mid-line
+                    [5, 10, 7, true, true, false],
+                    [5, 20, 0, true, true, false],
+                    // skipped region, e.g. a disabled `#[cfg]`
+                    [6, 1, 0, false, true, false],
+                    // a line starting with a skipped region and entering a counted one
+                    [8, 1, 0, false, true, false],
+                    [8, 5, 4, true, true, false],
+                    [9, 2, 0, false, false, false]
+                ],
+                "branches": [[5, 10, 5, 18, 7, 0, 0, 0, 4]]
+            }],
+            "functions": [
+                {
+                    "name": "_ZN4demo3add17h0123456789abcdefE",
+                    "count": 3,
+                    "filenames": ["/project/src/lib.rs", "/project/src/macros.rs"],
+                    // [line_start, column_start, line_end, column_end, count, file_id, expanded_file_id, kind]
+                    "regions": [
+                        [1, 1, 4, 2, 3, 0, 0, 0],
+                        [2, 5, 3, 1, 0, 0, 0, 3],
+                        [5, 10, 5, 18, 7, 1, 0, 0],
+                        [5, 20, 5, 30, 0, 0, 0, 0],
+                        [6, 1, 7, 1, 0, 0, 0, 2]
+                    ]
+                },
+                {
+                    "name": "unused",
+                    "count": 0,
+                    "filenames": ["/project/src/lib.rs"],
+                    "regions": [[8, 5, 9, 2, 0, 0, 0, 0]]
+                }
+            ],
+            "totals": summary
+        }]
+    })
+}
+
+#[test]
+fn test_line_counts_follow_llvm_cov() {
+    let report = CoverageReport::from_json(&export_json()).unwrap();
+    let file = report.file("/project/src/lib.rs").unwrap();
+
+    let expected = BTreeMap::from([
+        (1, 3),
+        // inside the region started on line 1, gap regions do not start a line
+        (2, 3),
+        // wrapped by the gap region
+        (3, 0),
+        (4, 2),
+        // the highest count of the wrapped region and the regions starting on the line
+        (5, 7),
+        // lines 6 and 7 are skipped
+        (8, 4),
+        (9, 4),
+    ]);
+    assert_eq!(file.lines, expected);
+    assert_eq!(file.summary.lines.count, 7);
+    assert_eq!(file.branches.len(), 1);
+    assert_eq!(file.branches[0].true_count, 7);
+    assert_eq!(file.branches[0].false_count, 0);
+}
+
+#[test]
+fn test_functions_and_regions() {
+    let report = CoverageReport::from_json(&export_json()).unwrap();
+    assert_eq!(report.totals.functions.covered, 1);
+
+    let add = &report.functions[0];
+    assert_eq!(add.demangled_name, "demo::add");
+    assert_eq!(add.count, 3);
+    let kinds: Vec<_> = add.regions.iter().map(|region| region.kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            RegionKind::Code,
+            RegionKind::Gap,
+            RegionKind::Code,
+            RegionKind::Code,
+            RegionKind::Skipped
+        ]
+    );
+    assert_eq!(add.regions[2].filename, "/project/src/macros.rs");
+    // Only code regions are counted.
+    let summary = add.region_summary();
+    assert_eq!((summary.covered, summary.count), (2, 3));
+
+    assert_eq!(report.functions[1].region_summary().covered, 0);
+}
+
+#[test]
+fn test_export_without_totals() {
+    let err = CoverageReport::from_json(&json!({"data": []})).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "llvm-cov export output has no coverage totals"
+    );
+}
+
+#[test]
+fn test_export_args_leave_out_show_options() {
+    let args: Vec<String> = [
+        "-show-line-counts-or-regions",
+        "--show-instantiations=false",
+        "-Xdemangler=rustfilt",
+        "-Xdemangler",
+        "rustfilt",
+        "-format=html",
+        "-o",
+        "report.txt",
+        "-show-branches=count",
+        "-ignore-filename-regex=/rustc/",
+        "--instr-profile-path",
+        "-path-equivalence=/build,/src",
+        "src/lib.rs",
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .collect();
+    assert_eq!(
+        export_args(&args),
+        [
+            "-ignore-filename-regex=/rustc/",
+            "--instr-profile-path",
+            "-path-equivalence=/build,/src",
+            "src/lib.rs"
+        ]
+    );
+}
+
+fn entry(covered: u64, count: u64) -> SummaryEntry {
+    SummaryEntry { count, covered }
+}
+
+#[test]
+fn test_parse_summary() {
+    let summary = parse_summary(&export_json().to_string()).unwrap();
+    assert_eq!(
+        summary,
+        CoverageSummary {
+            functions: entry(1, 2),
+            lines: entry(5, 7),
+            regions: entry(3, 5),
+            branches: entry(1, 2),
+        }
+    );
+    assert_eq!(summary.functions.percent(), 50.0);
+    assert_eq!(summary.regions.percent(), 60.0);
+    // Nothing to cover counts as fully covered.
+    assert_eq!(entry(0, 0).percent(), 100.0);
+
+    let error = parse_summary(r#"{"data": []}"#).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "llvm-cov export output has no coverage totals"
+    );
+}
+
+#[test]
+fn test_summary_table() {
+    let summaries = vec![
+        (
+            "counter".to_string(),
+            CoverageSummary {
+                functions: entry(2, 3),
+                lines: entry(10, 12),
+                regions: entry(1, 4),
+                branches: entry(0, 0),
+            },
+        ),
+        (
+            "status-message".to_string(),
+            CoverageSummary {
+                functions: entry(1, 1),
+                lines: entry(90, 100),
+                regions: entry(3, 4),
+                branches: entry(1, 2),
+            },
+        ),
+    ];
+    assert_eq!(
+        summary_table(&summaries, false),
+        "\
+Contract              Functions            Lines          Regions         Branches
+----------------------------------------------------------------------------------
+counter             2/3  66.67%    10/12  83.33%      1/4  25.00%      0/0 100.00%
+status-message      1/1 100.00%   90/100  90.00%      3/4  75.00%      1/2  50.00%
+----------------------------------------------------------------------------------
+TOTAL               3/4  75.00%  100/112  89.29%      4/8  50.00%      1/2  50.00%
+"
+    );
+
+    // A single contract has no total row, unless the total is given.
+    let table = summary_table(&summaries[..1], false);
+    assert_eq!(table.lines().count(), 3);
+    assert!(!table.contains("TOTAL"));
+    let total = CoverageSummary {
+        lines: entry(5, 10),
+        ..summaries[0].1
+    };
+    let table = summary_table_with_total(&summaries[..1], Some(total), false);
+    assert_eq!(
+        table.lines().last(),
+        Some("TOTAL       2/3  66.67%   5/10  50.00%    1/4  25.00%    0/0 100.00%")
+    );
+
+    // Red below 50%, yellow below 80% and green otherwise.
+    let table = summary_table(&summaries[..1], true);
+    assert!(table.contains("2/3 \x1b[33m 66.67%\x1b[0m"));
+    assert!(table.contains("10/12 \x1b[32m 83.33%\x1b[0m"));
+    assert!(table.contains("1/4 \x1b[31m 25.00%\x1b[0m"));
+    assert!(table.contains("0/0 \x1b[32m100.00%\x1b[0m"));
+}
+
+#[test]
+fn test_summary_only_generates_no_reports() {
+    let configured = [ReportFormat::Json];
+    assert!(report_formats(true, vec![ReportFormat::Lcov], &configured).is_empty());
+    assert_eq!(
+        report_formats(false, vec![ReportFormat::Lcov], &configured),
+        [ReportFormat::Lcov]
+    );
+    assert_eq!(
+        report_formats(false, Vec::new(), &configured),
+        [ReportFormat::Json]
+    );
+    assert_eq!(report_formats(false, Vec::new(), &[]), [ReportFormat::Html]);
+}
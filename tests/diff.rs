@@ -0,0 +1,332 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use wasmcov::diff::{
+    diff, parse_changed_lines, ChangedLines, FileSnapshot, FunctionSnapshot, Snapshot,
+};
+use wasmcov::report::SummaryEntry;
+
+const LIB: &str = "/project/src/lib.rs";
+
+fn entry(covered: u64, count: u64) -> SummaryEntry {
+    SummaryEntry { count, covered }
+}
+
+fn file(regions: SummaryEntry, line_hits: &[(u64, u64)]) -> FileSnapshot {
+    FileSnapshot {
+        lines: entry(
+            line_hits.iter().filter(|(_, hits)| *hits > 0).count() as u64,
+            line_hits.len() as u64,
+        ),
+        regions,
+        line_hits: line_hits.iter().copied().collect(),
+    }
+}
+
+fn function(file: &str, regions: SummaryEntry) -> FunctionSnapshot {
+    FunctionSnapshot {
+        file: file.to_string(),
+        count: regions.covered,
+        regions,
+    }
+}
+
+fn snapshot(
+    files: Vec<(&str, FileSnapshot)>,
+    functions: Vec<(&str, FunctionSnapshot)>,
+) -> Snapshot {
+    let files: BTreeMap<String, FileSnapshot> = files
+        .into_iter()
+        .map(|(path, file)| (path.to_string(), file))
+        .collect();
+    let sum = |entries: Vec<SummaryEntry>| {
+        entries
+            .iter()
+            .fold(SummaryEntry::default(), |total, entry| SummaryEntry {
+                count: total.count + entry.count,
+                covered: total.covered + entry.covered,
+            })
+    };
+    Snapshot {
+        lines: sum(files.values().map(|file| file.lines).collect()),
+        regions: sum(files.values().map(|file| file.regions).collect()),
+        files,
+        functions: functions
+            .into_iter()
+            .map(|(name, function)| (name.to_string(), function))
+            .collect(),
+    }
+}
+
+fn baseline() -> Snapshot {
+    snapshot(
+        vec![
+            (
+                LIB,
+                file(entry(3, 4), &[(1, 1), (2, 1), (3, 0), (4, 1), (5, 2)]),
+            ),
+            ("/project/src/old.rs", file(entry(1, 1), &[(1, 1)])),
+        ],
+        vec![
+            (
+                "_ZN4demo3add17h0123456789abcdefE",
+                function(LIB, entry(2, 2)),
+            ),
+            ("demo::unchanged", function(LIB, entry(1, 2))),
+            ("demo::old", function("/project/src/old.rs", entry(1, 1))),
+        ],
+    )
+}
+
+fn current() -> Snapshot {
+    snapshot(
+        vec![
+            (
+                LIB,
+                file(
+                    entry(2, 4),
+                    &[(1, 1), (2, 0), (3, 0), (4, 1), (5, 0), (6, 0), (7, 1)],
+                ),
+            ),
+            ("/project/src/new.rs", file(entry(0, 1), &[(1, 0), (2, 0)])),
+        ],
+        vec![
+            (
+                "_ZN4demo3add17h0123456789abcdefE",
+                function(LIB, entry(1, 2)),
+            ),
+            ("demo::unchanged", function(LIB, entry(1, 2))),
+            ("demo::new", function("/project/src/new.rs", entry(0, 1))),
+        ],
+    )
+}
+
+#[test]
+fn test_diff() {
+    let diff = diff("demo", Some(&baseline()), Some(&current()), None);
+    assert_eq!(diff.lines.to_string(), "83.33% -> 33.33% (-50.00)");
+    assert_eq!(diff.regions.to_string(), "80.00% -> 40.00% (-40.00)");
+
+    let files: Vec<_> = diff
+        .files
+        .iter()
+        .map(|file| (file.path.as_str(), file.lines.to_string()))
+        .collect();
+    assert_eq!(
+        files,
+        [
+            (LIB, "80.00% -> 42.86% (-37.14)".to_string()),
+            ("/project/src/new.rs", "new, 0.00%".to_string()),
+            ("/project/src/old.rs", "removed, was 100.00%".to_string())
+        ]
+    );
+
+    // Functions with the same coverage are left out.
+    let functions: Vec<_> = diff
+        .functions
+        .iter()
+        .map(|function| {
+            (
+                function.name.as_str(),
+                function.file.as_str(),
+                function.regions.to_string(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        functions,
+        [
+            (
+                "_ZN4demo3add17h0123456789abcdefE",
+                LIB,
+                "100.00% -> 50.00% (-50.00)".to_string()
+            ),
+            ("demo::new", "/project/src/new.rs", "new, 0.00%".to_string()),
+            (
+                "demo::old",
+                "/project/src/old.rs",
+                "removed, was 100.00%".to_string()
+            )
+        ]
+    );
+
+    // Line 3 was not covered in the baseline either.
+    assert_eq!(
+        diff.uncovered_lines,
+        BTreeMap::from([
+            (LIB.to_string(), vec![2, 5, 6]),
+            ("/project/src/new.rs".to_string(), vec![1, 2])
+        ])
+    );
+}
+
+#[test]
+fn test_diff_of_changed_lines() {
+    let changed_lines = ChangedLines::from([(PathBuf::from(LIB), BTreeSet::from([3, 5, 6, 7]))]);
+    let diff = diff(
+        "demo",
+        Some(&baseline()),
+        Some(&current()),
+        Some(&changed_lines),
+    );
+    assert_eq!(
+        diff.uncovered_lines,
+        BTreeMap::from([(LIB.to_string(), vec![3, 5, 6])])
+    );
+}
+
+#[test]
+fn test_to_table() {
+    let diff = diff("demo", Some(&baseline()), Some(&current()), None);
+    // Lines are shown as ranges.
+    assert_eq!(
+        diff.to_table(),
+        "\
+Coverage diff for demo
+
+Total
+Lines                      Regions
+----------------------------------------------------
+83.33% -> 33.33% (-50.00)  80.00% -> 40.00% (-40.00)
+
+Files
+File                 Lines                      Regions
+----------------------------------------------------------------------------------
+/project/src/lib.rs  80.00% -> 42.86% (-37.14)  75.00% -> 50.00% (-25.00)  down
+/project/src/new.rs  new, 0.00%                 new, 0.00%                 new
+/project/src/old.rs  removed, was 100.00%       removed, was 100.00%       removed
+
+Functions
+Function   File                 Regions
+-------------------------------------------------------------------
+demo::add  /project/src/lib.rs  100.00% -> 50.00% (-50.00)  down
+demo::new  /project/src/new.rs  new, 0.00%                  new
+demo::old  /project/src/old.rs  removed, was 100.00%        removed
+
+Newly uncovered lines
+File                 Lines
+---------------------------
+/project/src/lib.rs  2, 5-6
+/project/src/new.rs  1-2
+"
+    );
+}
+
+#[test]
+fn test_to_markdown() {
+    let diff = diff("demo", Some(&baseline()), Some(&current()), None);
+    assert_eq!(
+        diff.to_markdown(),
+        "\
+## Coverage diff for `demo`
+
+### Total
+
+| Lines | Regions |
+|---|---|
+| 83.33% -> 33.33% (-50.00) | 80.00% -> 40.00% (-40.00) |
+
+### Files
+
+| File | Lines | Regions |  |
+|---|---|---|---|
+| /project/src/lib.rs | 80.00% -> 42.86% (-37.14) | 75.00% -> 50.00% (-25.00) | down |
+| /project/src/new.rs | new, 0.00% | new, 0.00% | new |
+| /project/src/old.rs | removed, was 100.00% | removed, was 100.00% | removed |
+
+### Functions
+
+| Function | File | Regions |  |
+|---|---|---|---|
+| demo::add | /project/src/lib.rs | 100.00% -> 50.00% (-50.00) | down |
+| demo::new | /project/src/new.rs | new, 0.00% | new |
+| demo::old | /project/src/old.rs | removed, was 100.00% | removed |
+
+### Newly uncovered lines
+
+| File | Lines |
+|---|---|
+| /project/src/lib.rs | 2, 5-6 |
+| /project/src/new.rs | 1-2 |
+"
+    );
+}
+
+#[test]
+fn test_parse_changed_lines() {
+    let diff = "\
+diff --git a/src/lib.rs b/src/lib.rs
+index 1111111..2222222 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -3 +3 @@ fn add(a: u32, b: u32) -> u32 {
+-    a + b
++    a.wrapping_add(b)
+@@ -10,2 +10,0 @@ fn sub(a: u32, b: u32) -> u32 {
+-    // unused
+-    // comments
+@@ -20,0 +19,3 @@ fn mul(a: u32, b: u32) -> u32 {
++fn div(a: u32, b: u32) -> u32 {
++    a / b
++}
+diff --git a/src/old.rs b/src/renamed.rs
+similarity index 90%
+rename from src/old.rs
+rename to src/renamed.rs
+index 3333333..4444444 100644
+--- a/src/old.rs
++++ b/src/renamed.rs
+@@ -1,2 +1,2 @@
+-use crate::old;
+-use crate::old::Old;
++use crate::renamed;
++use crate::renamed::Renamed;
+diff --git a/src/removed.rs b/src/removed.rs
+deleted file mode 100644
+index 5555555..0000000
+--- a/src/removed.rs
++++ /dev/null
+@@ -1 +0,0 @@
+-pub fn removed() {}
+diff --git a/src/empty.rs b/src/empty.rs
+index 6666666..7777777 100644
+--- a/src/empty.rs
++++ b/src/empty.rs
+@@ -1 +0,0 @@
+-pub fn empty() {}
+";
+    let changed = parse_changed_lines(Path::new("/project"), diff);
+    assert_eq!(
+        changed,
+        ChangedLines::from([
+            (
+                PathBuf::from("/project/src/lib.rs"),
+                BTreeSet::from([3, 19, 20, 21])
+            ),
+            (
+                PathBuf::from("/project/src/renamed.rs"),
+                BTreeSet::from([1, 2])
+            )
+        ])
+    );
+}
+
+#[test]
+fn test_diff_of_new_contract() {
+    let new = diff("demo", None, Some(&current()), None);
+    assert_eq!(new.lines.to_string(), "new, 33.33%");
+    assert_eq!(new.regions.to_string(), "new, 40.00%");
+    assert!(new.files.iter().all(|file| file.lines.baseline.is_none()));
+    assert_eq!(
+        new.uncovered_lines,
+        BTreeMap::from([
+            (LIB.to_string(), vec![2, 3, 5, 6]),
+            ("/project/src/new.rs".to_string(), vec![1, 2])
+        ])
+    );
+    assert!(new.to_markdown().contains("| new, 33.33% | new, 40.00% |"));
+
+    let removed = diff("demo", Some(&baseline()), None, None);
+    assert_eq!(removed.lines.to_string(), "removed, was 83.33%");
+    assert_eq!(removed.regions.to_string(), "removed, was 80.00%");
+    assert!(removed.uncovered_lines.is_empty());
+}
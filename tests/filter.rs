@@ -0,0 +1,72 @@
+use wasmcov::filter::SourceFilter;
+
+fn filter(include: &[&str], exclude: &[&str], default_excludes: bool) -> SourceFilter {
+    let strings = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    SourceFilter::new(&strings(include), &strings(exclude), default_excludes).unwrap()
+}
+
+#[test]
+fn test_default_excludes_hide_dependencies() {
+    let filter = filter(&[], &[], true);
+    assert!(!filter.matches(
+        "/home/u/.cargo/registry/src/index.crates.io-6f17d22bba15001f/near-sdk-5.1.0/src/lib.rs"
+    ));
+    assert!(!filter.matches("/home/u/.cargo/git/checkouts/near-sdk-rs-1234/abcdef/src/lib.rs"));
+    assert!(!filter.matches(
+        "/home/u/.rustup/toolchains/stable-x86_64-unknown-linux-gnu/lib/rustlib/src/rust/library/core/src/lib.rs"
+    ));
+    assert!(!filter
+        .matches("/rustc/90b35a6239c3d8bdabc530a6a0816f7ff89a0aaf/library/core/src/fmt/mod.rs"));
+    assert!(filter.matches("/home/u/project/src/lib.rs"));
+
+    let filter = self::filter(&[], &[], false);
+    assert!(filter.is_empty());
+    assert!(filter.matches(
+        "/home/u/.cargo/registry/src/index.crates.io-6f17d22bba15001f/borsh-1.5.0/src/lib.rs"
+    ));
+}
+
+#[test]
+fn test_single_star_stays_within_a_component() {
+    let filter = filter(&["/home/u/project/*/lib.rs"], &[], false);
+    assert!(filter.matches("/home/u/project/src/lib.rs"));
+    assert!(!filter.matches("/home/u/project/contracts/token/src/lib.rs"));
+
+    let filter = self::filter(&["/home/u/project/**/lib.rs"], &[], false);
+    assert!(filter.matches("/home/u/project/src/lib.rs"));
+    assert!(filter.matches("/home/u/project/contracts/token/src/lib.rs"));
+    assert!(!filter.matches("/home/u/other/src/lib.rs"));
+}
+
+#[test]
+fn test_relative_patterns_match_below_current_dir() {
+    let current_dir = std::env::current_dir().unwrap();
+    let path = |relative: &str| current_dir.join(relative).to_string_lossy().into_owned();
+
+    let filter = filter(&["src/**"], &[], false);
+    assert!(filter.matches(&path("src/report.rs")));
+    assert!(!filter.matches(&path("tests/filter.rs")));
+    assert!(!filter.matches("/elsewhere/src/report.rs"));
+}
+
+#[test]
+fn test_exclude_takes_precedence_over_include() {
+    let filter = filter(&["/home/u/project/**"], &["**/tests/**"], true);
+    assert!(filter.matches("/home/u/project/src/lib.rs"));
+    assert!(!filter.matches("/home/u/project/tests/integration.rs"));
+    assert!(!filter.matches("/home/u/other/src/lib.rs"));
+
+    // Default excludes apply to included files as well.
+    let filter = self::filter(&["**/near-sdk-*/**"], &[], true);
+    assert!(!filter.matches(
+        "/home/u/.cargo/registry/src/index.crates.io-6f17d22bba15001f/near-sdk-5.1.0/src/lib.rs"
+    ));
+}
+
+#[test]
+fn test_invalid_pattern() {
+    let err = SourceFilter::new(&["src/[".to_string()], &[], true).unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("Invalid source filter \"src/[\""));
+}
@@ -0,0 +1,88 @@
+#![cfg(feature = "near_local")]
+
+use std::fs;
+use wasmcov::dir::WasmcovDir;
+use wasmcov::near_local::Contract;
+
+// Stores its input under the "value" key and returns the previous value,
+// or panics when called with an empty input.
+const CONTRACT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "storage_write" (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (import "env" "log_utf8" (func $log_utf8 (param i64 i64)))
+  (import "env" "panic_utf8" (func $panic_utf8 (param i64 i64)))
+  (import "env" "sandbox_capture_coverage" (func $capture (param i64 i64 i64 i64)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "value")
+  (data (i32.const 16) "empty input")
+  (data (i32.const 32) "demo")
+  (data (i32.const 48) "coverage")
+  (func (export "set")
+    (local $len i64)
+    (call $input (i64.const 0))
+    (local.set $len (call $register_len (i64.const 0)))
+    (if (i64.eqz (local.get $len))
+      (then (call $panic_utf8 (i64.const 11) (i64.const 16))))
+    (call $read_register (i64.const 0) (i64.const 1024))
+    (if (i64.eq
+          (call $storage_write (i64.const 5) (i64.const 0) (local.get $len) (i64.const 1024) (i64.const 1))
+          (i64.const 1))
+      (then
+        (call $read_register (i64.const 1) (i64.const 2048))
+        (call $value_return (call $register_len (i64.const 1)) (i64.const 2048))))
+    (call $log_utf8 (i64.const 5) (i64.const 0)))
+  (func (export "capture_coverage")
+    (call $capture (i64.const 4) (i64.const 32) (i64.const 8) (i64.const 48))))
+"#;
+
+#[test]
+fn test_call_updates_storage_and_captures_coverage() {
+    let dir = tempfile::tempdir().unwrap();
+    let wasmcov_dir = WasmcovDir::new(dir.path()).unwrap();
+
+    let mut contract = Contract::new(&wasmcov_dir, &wat::parse_str(CONTRACT).unwrap()).unwrap();
+    let outcome = contract.call("set", b"first").unwrap();
+    assert_eq!(outcome.return_data, None);
+    assert_eq!(outcome.logs, vec!["value".to_string()]);
+
+    let outcome = contract.call("set", b"second").unwrap();
+    assert_eq!(outcome.return_data, Some(b"first".to_vec()));
+
+    let err = contract.call("set", b"").unwrap_err();
+    assert_eq!(err.to_string(), "Smart contract panicked: empty input");
+    assert_eq!(
+        contract.storage.get(b"value".as_slice()),
+        Some(&b"second".to_vec())
+    );
+
+    // The same coverage is captured after every call, including the panicking one
+    let profraw_files: Vec<_> = fs::read_dir(dir.path().join("profraw/demo"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(profraw_files.len(), 1);
+}
+
+#[test]
+fn test_coverage_is_recorded_for_the_test_of_each_call() {
+    let dir = tempfile::tempdir().unwrap();
+    let wasmcov_dir = WasmcovDir::new(dir.path()).unwrap();
+
+    let mut contract = Contract::new(&wasmcov_dir, &wat::parse_str(CONTRACT).unwrap()).unwrap();
+    contract.call("set", b"untracked").unwrap();
+    contract.test_name = Some("test_first".to_string());
+    contract.call("set", b"first").unwrap();
+    contract.test_name = Some("test_second".to_string());
+    contract.call("set", b"second").unwrap();
+
+    let by_test =
+        wasmcov::attribution::profraw_files_by_test(&dir.path().join("profraw/demo")).unwrap();
+    assert_eq!(
+        by_test.keys().collect::<Vec<_>>(),
+        ["test_first", "test_second"]
+    );
+}
@@ -0,0 +1,205 @@
+#![cfg(feature = "near_sandbox")]
+
+use base64::Engine;
+use sha2::Digest;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use wasmcov::near_sandbox::{self, NearcoreLayout, DEFAULT_NEARCORE_VERSION};
+use wasmcov::Error;
+
+#[test]
+fn test_nearcore_layout_boundaries() {
+    let layout = |version| near_sandbox::nearcore_layout(version).unwrap();
+    assert_eq!(layout("1.34.0"), NearcoreLayout::V1_34);
+    assert_eq!(layout("2.2.9"), NearcoreLayout::V1_34);
+    assert_eq!(layout("2.3.0"), NearcoreLayout::V2_3);
+    assert_eq!(layout("2.5.1"), NearcoreLayout::V2_3);
+    assert_eq!(layout("2.6.0"), NearcoreLayout::V2_6);
+    assert_eq!(layout("2.9.99"), NearcoreLayout::V2_6);
+    assert_eq!(layout("2.10.0"), NearcoreLayout::V2_10);
+    assert_eq!(layout("2.11.3"), NearcoreLayout::V2_10);
+    assert_eq!(layout("2.12.0"), NearcoreLayout::V2_12);
+    assert_eq!(layout("2.99.0"), NearcoreLayout::V2_12);
+    assert_eq!(layout(DEFAULT_NEARCORE_VERSION), NearcoreLayout::V1_34);
+}
+
+#[test]
+fn test_release_candidates_use_their_release_layout() {
+    let layout = |version| near_sandbox::nearcore_layout(version).unwrap();
+    assert_eq!(layout("v2.6.0"), NearcoreLayout::V2_6);
+    // 2.6.0-rc.1 is below 2.6.0 in semver, but uses the 2.6 sources
+    assert_eq!(layout("2.6.0-rc.1"), NearcoreLayout::V2_6);
+}
+
+#[test]
+fn test_unsupported_nearcore_versions() {
+    for version in ["1.33.9", "3.0.0", "3.0.0-rc.1", "0.1.0"] {
+        match near_sandbox::nearcore_layout(version) {
+            Err(Error::VersionMismatch { tool, found, .. }) => {
+                assert_eq!(tool, "nearcore");
+                assert_eq!(found, version);
+            }
+            other => panic!("{version} should not be supported, got {other:?}"),
+        }
+    }
+    assert!(near_sandbox::parse_version("2.6").is_err());
+    assert_eq!(
+        near_sandbox::nearcore_layout("latest")
+            .unwrap_err()
+            .to_string(),
+        "Invalid nearcore version latest: unexpected character 'l' while parsing major version number"
+    );
+}
+
+#[test]
+fn test_patch_hash_depends_on_the_patch_set() {
+    let hash = |version| near_sandbox::patch_hash(version).unwrap();
+    assert_eq!(hash("2.6.0"), hash("2.6.0"));
+    assert_eq!(hash("2.6.0").len(), 8);
+    // Same layout, but a different release
+    assert_ne!(hash("2.6.0"), hash("2.7.0"));
+    assert_ne!(hash("2.6.0"), hash("2.10.0"));
+    assert!(near_sandbox::patch_hash("3.0.0").is_err());
+}
+
+// A nearcore checkout with one line containing the pattern of every patch.
+fn fake_nearcore(dir: &Path, version: &str) {
+    for patch in near_sandbox::patches(Some(version)).unwrap() {
+        let path = dir.join(patch.files[0]);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, format!("{}\n", patch.operation.pattern())).unwrap();
+    }
+}
+
+#[test]
+fn test_cargo_toml_patch_is_required() {
+    let dir = tempfile::tempdir().unwrap();
+    fake_nearcore(dir.path(), "2.6.0");
+    fs::write(dir.path().join("Cargo.toml"), "[profile.dev-release]\n").unwrap();
+    match near_sandbox::add_wasmcov_to_nearcore(dir.path(), Some("2.6.0")) {
+        Err(Error::PatchFailed { file, message }) => {
+            assert_eq!(file, "Cargo.toml");
+            assert_eq!(
+                message,
+                "`profile.quick-release` not found in nearcore 2.6.0"
+            );
+        }
+        other => panic!("missing Cargo.toml profile should fail, got {other:?}"),
+    }
+    // Nothing was patched
+    assert!(!near_sandbox::is_patched(dir.path()).unwrap());
+
+    fake_nearcore(dir.path(), "2.6.0");
+    near_sandbox::add_wasmcov_to_nearcore(dir.path(), Some("2.6.0")).unwrap();
+    assert!(near_sandbox::is_patched(dir.path()).unwrap());
+    assert_eq!(
+        fs::read_to_string(dir.path().join("Cargo.toml")).unwrap(),
+        "profile.dev-release\n"
+    );
+}
+
+#[test]
+fn test_neard_release() {
+    assert_eq!(
+        near_sandbox::neard_release(
+            "neard (release 2.6.0) (build 2.6.0) (rustc 1.84.0) (protocol 73) (db 42)\n"
+        ),
+        Some("2.6.0".to_string())
+    );
+    assert_eq!(
+        near_sandbox::neard_release("neard (release 2.7.0-rc.2) (build 2.7.0-rc.2-1-gabcdef)"),
+        Some("2.7.0-rc.2".to_string())
+    );
+    assert_eq!(
+        near_sandbox::neard_release("neard (release trunk) (build 2.6.0-123-gabcdef)"),
+        None
+    );
+    assert_eq!(near_sandbox::neard_release("neard 2.6.0"), None);
+}
+
+#[test]
+fn test_verify_neard_checks_the_markers_of_the_version() {
+    let dir = tempfile::tempdir().unwrap();
+    let neard_path = dir.path().join("neard");
+    for version in ["1.35.0", "2.0.0", "2.6.0", "2.10.0", "2.12.0"] {
+        let patches = near_sandbox::patches(Some(version)).unwrap();
+        for patch in &patches {
+            if let Some(marker) = patch.marker {
+                assert!(patch.operation.text().contains(marker), "{marker}");
+            }
+        }
+        let markers: Vec<_> = patches.iter().filter_map(|patch| patch.marker).collect();
+        // Every patch has its own marker, so none of them can be missing.
+        let unique: BTreeSet<_> = markers.iter().collect();
+        assert_eq!(unique.len(), markers.len(), "{version}");
+        fs::write(&neard_path, markers.join("\0")).unwrap();
+        near_sandbox::verify_neard(&neard_path, Some(version)).unwrap();
+
+        for (index, marker) in markers.iter().enumerate() {
+            let mut others = markers.clone();
+            others.remove(index);
+            fs::write(&neard_path, others.join("\0")).unwrap();
+            let err = near_sandbox::verify_neard(&neard_path, Some(version)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "{:?} is not patched by wasmcov: `{}` not found in the binary",
+                    neard_path, marker
+                )
+            );
+        }
+    }
+}
+
+#[test]
+fn test_smoke_contract_encoding() {
+    let wasm = wat::parse_str(near_sandbox::SMOKE_CONTRACT_WAT).unwrap();
+    assert_eq!(
+        base64::engine::general_purpose::STANDARD.encode(&wasm),
+        near_sandbox::SMOKE_CONTRACT_BASE64
+    );
+    assert_eq!(
+        bs58::encode(sha2::Sha256::digest(&wasm)).into_string(),
+        near_sandbox::SMOKE_CONTRACT_HASH
+    );
+}
+
+// Stands in for neard: `init` writes a genesis, `run` saves coverage like the
+// patched sandbox when the smoke contract has been added to the genesis.
+#[cfg(unix)]
+const FAKE_NEARD: &str = r#"#!/bin/sh
+home="$2"
+case "$3" in
+  init)
+    echo '{"records": [{"Account": {"account_id": "test.near", "account": {"code_hash": "11111111111111111111111111111111"}}}]}' > "$home/genesis.json"
+    ;;
+  run)
+    grep -q "CODE_HASH" "$home/genesis.json" || exit 1
+    grep -q "CODE_BASE64" "$home/genesis.json" || exit 1
+    mkdir -p "$WASMCOV_DIR/profraw/wasmcov-smoke"
+    touch "$WASMCOV_DIR/profraw/wasmcov-smoke/0.profraw"
+    exec sleep 30
+    ;;
+esac
+"#;
+
+#[cfg(unix)]
+#[test]
+fn test_smoke_test_deploys_the_contract_and_waits_for_coverage() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let neard_path = dir.path().join("neard");
+    let script = FAKE_NEARD
+        .replace("CODE_HASH", near_sandbox::SMOKE_CONTRACT_HASH)
+        .replace("CODE_BASE64", &near_sandbox::SMOKE_CONTRACT_BASE64[..32]);
+    fs::write(&neard_path, script).unwrap();
+    fs::set_permissions(&neard_path, fs::Permissions::from_mode(0o755)).unwrap();
+    near_sandbox::smoke_test_neard(&neard_path).unwrap();
+
+    // A neard which exits without saving coverage fails the smoke test
+    fs::write(&neard_path, FAKE_NEARD).unwrap();
+    let err = near_sandbox::smoke_test_neard(&neard_path).unwrap_err();
+    assert!(err.to_string().starts_with("neard sandbox exited with"));
+}
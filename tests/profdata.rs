@@ -0,0 +1,341 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use wasmcov::dir::WasmcovDir;
+use wasmcov::fingerprint::Fingerprint;
+use wasmcov::gc;
+use wasmcov::profdata::Profdata;
+use wasmcov::profraw::{self, ProfrawRecord};
+use wasmcov::report::{self, MergeOptions};
+
+// Profraw files of a single `main` function and their merged profdata,
+// written by llvm-profdata.
+fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/profraw")
+}
+
+// The function of the fixtures.
+fn fixture_record() -> ProfrawRecord {
+    profraw::read_profraw(&fixtures().join("0.profraw"))
+        .unwrap()
+        .records
+        .remove(0)
+}
+
+fn expected_profdata() -> Vec<u8> {
+    fs::read(fixtures().join("coverage-expected.profdata")).unwrap()
+}
+
+// A covfun section with a single function record without mapping data.
+fn covfun(name_hash: u64, function_hash: u64) -> Vec<u8> {
+    let mut covfun = Vec::new();
+    covfun.extend_from_slice(&name_hash.to_le_bytes());
+    covfun.extend_from_slice(&0u32.to_le_bytes());
+    covfun.extend_from_slice(&function_hash.to_le_bytes());
+    covfun.extend_from_slice(&0u64.to_le_bytes());
+    covfun.resize(32, 0);
+    covfun
+}
+
+// Temporary wasmcov directory of the `contract` target.
+struct Workspace {
+    dir: TempDir,
+    wasmcov_dir: WasmcovDir,
+    profraw_dir: PathBuf,
+}
+
+impl Workspace {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let wasmcov_dir = WasmcovDir::new(dir.path()).unwrap();
+        let profraw_dir = wasmcov_dir.profraw().unwrap().join("contract");
+        fs::create_dir(&profraw_dir).unwrap();
+        Workspace {
+            dir,
+            wasmcov_dir,
+            profraw_dir,
+        }
+    }
+
+    // Copies a profraw fixture to the profraw directory as `name`.
+    fn add_profraw(&self, fixture: &str, name: &str) {
+        fs::copy(fixtures().join(fixture), self.profraw_dir.join(name)).unwrap();
+    }
+
+    // Extracts an object file of a build in which the function of the
+    // fixtures has the given hash.
+    fn build(&self, function_hash: u64) {
+        let target_dir = self.wasmcov_dir.target().unwrap();
+        fs::write(target_dir.join("contract.o"), []).unwrap();
+        fingerprint(function_hash)
+            .write(&target_dir.join("contract.fingerprint"))
+            .unwrap();
+    }
+}
+
+fn fingerprint(function_hash: u64) -> Fingerprint {
+    Fingerprint::from_covfun(&covfun(fixture_record().name_hash, function_hash)).unwrap()
+}
+
+#[test]
+fn test_merge_profraw_matches_llvm_profdata() {
+    let fixtures = fixtures();
+
+    let mut profdata = Profdata::new();
+    for name in ["0.profraw", "1.profraw"] {
+        let profraw = profraw::read_profraw(&fixtures.join(name)).unwrap();
+        assert_eq!(profraw.records.len(), 1);
+        assert_eq!(profraw.records[0].name, "main");
+        assert_eq!(profraw.records[0].counters, vec![1, 30, 2]);
+        profdata.merge(profraw).unwrap();
+    }
+
+    assert_eq!(profdata.to_bytes(), expected_profdata());
+}
+
+#[test]
+fn test_parse_malformed_profraw() {
+    let data = fs::read(fixtures().join("0.profraw")).unwrap();
+    // Replaces a u64 field of the version 8 header.
+    let with_field = |offset: usize, value: u64| {
+        let mut data = data.clone();
+        data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        data
+    };
+    let error = |data: &[u8]| profraw::parse_profraw(data).unwrap_err().to_string();
+
+    // NumData, NumCounters and the paddings would overflow the offsets.
+    for offset in [24, 32, 40, 48] {
+        assert_eq!(
+            error(&with_field(offset, u64::MAX)),
+            "Invalid profraw header"
+        );
+    }
+    assert_eq!(
+        error(&with_field(24, u64::MAX / 48 + 1)),
+        "Invalid profraw header"
+    );
+    // ValueKindLast
+    assert_eq!(error(&with_field(80, 3)), "Invalid profraw header");
+    assert_eq!(error(&with_field(80, u64::MAX)), "Invalid profraw header");
+    // Offsets past the end of the file
+    assert!(error(&with_field(40, 1 << 40)).starts_with("Unexpected end of data"));
+    assert!(error(&with_field(56, 1 << 40)).starts_with("Unexpected end of data"));
+    assert!(error(&data[..100]).starts_with("Unexpected end of data"));
+    // A counter offset outside of the counters
+    assert_eq!(
+        error(&with_field(64, 0)),
+        "Invalid counter offset in data record 0"
+    );
+}
+
+#[test]
+fn test_incremental_merge_matches_full_merge() {
+    let workspace = Workspace::new();
+    let profraw_dir = &workspace.profraw_dir;
+    let profdata_path = workspace.dir.path().join("coverage.profdata");
+    let options = MergeOptions {
+        jobs: 2,
+        ..Default::default()
+    };
+    let merge = || {
+        report::merge_profraw_dir(profraw_dir, &profdata_path, None, &options)
+            .unwrap()
+            .profraw_files
+    };
+
+    workspace.add_profraw("0.profraw", "0.profraw");
+    assert_eq!(merge(), 1);
+    assert_eq!(merge(), 0);
+    workspace.add_profraw("1.profraw", "1.profraw");
+    assert_eq!(merge(), 1);
+
+    assert_eq!(fs::read(&profdata_path).unwrap(), expected_profdata());
+    assert_eq!(
+        Profdata::read(&profdata_path).unwrap().to_bytes(),
+        expected_profdata()
+    );
+}
+
+#[test]
+fn test_gc_keeps_merged_coverage() {
+    let workspace = Workspace::new();
+    workspace.add_profraw("0.profraw", "0.profraw");
+
+    let collected = gc::collect(&workspace.wasmcov_dir, None, &MergeOptions::default()).unwrap();
+    assert_eq!(collected.len(), 1);
+    assert_eq!(collected[0].profraw_files, 1);
+    assert!(!workspace.profraw_dir.join("0.profraw").exists());
+
+    workspace.add_profraw("1.profraw", "1.profraw");
+    let merged = report::merge_all(&workspace.wasmcov_dir, &MergeOptions::default()).unwrap();
+    assert_eq!(
+        fs::read(&merged[0].profdata_path).unwrap(),
+        expected_profdata()
+    );
+}
+
+#[test]
+fn test_merge_skips_profraw_of_other_builds() {
+    let workspace = Workspace::new();
+    let profraw_dir = &workspace.profraw_dir;
+    workspace.add_profraw("0.profraw", "0.profraw");
+    workspace.add_profraw("1.profraw", "1.profraw");
+    let profdata_path = workspace.dir.path().join("coverage.profdata");
+    let function_hash = fixture_record().function_hash;
+
+    let merged = report::merge_profraw_dir(
+        profraw_dir,
+        &profdata_path,
+        Some(&fingerprint(function_hash)),
+        &MergeOptions::default(),
+    )
+    .unwrap();
+    assert_eq!(merged.profraw_files, 2);
+    assert!(merged.stale_files.is_empty());
+
+    let options = MergeOptions {
+        quarantine_stale: true,
+        ..Default::default()
+    };
+    let merged = report::merge_profraw_dir(
+        profraw_dir,
+        &profdata_path,
+        Some(&fingerprint(function_hash + 1)),
+        &options,
+    )
+    .unwrap();
+    assert_eq!(merged.profraw_files, 0);
+    assert_eq!(merged.stale_files.len(), 2);
+    assert!(profraw_dir.join("stale/0.profraw").exists());
+    assert!(!profraw_dir.join("0.profraw").exists());
+}
+
+#[test]
+fn test_merge_does_not_check_unchanged_profraw_again() {
+    let workspace = Workspace::new();
+    let profraw_dir = &workspace.profraw_dir;
+    workspace.add_profraw("0.profraw", "0.profraw");
+    workspace.add_profraw("1.profraw", "1.profraw");
+    let profdata_path = workspace.dir.path().join("coverage.profdata");
+    let fingerprint = fingerprint(fixture_record().function_hash + 1);
+    let options = MergeOptions {
+        full: true,
+        ..Default::default()
+    };
+    let merge =
+        || report::merge_profraw_dir(profraw_dir, &profdata_path, Some(&fingerprint), &options);
+    assert_eq!(merge().unwrap().stale_files.len(), 2);
+
+    // Unparsable profraw files are not stale, so the file would be merged
+    // and fail if it was checked again.
+    let path = profraw_dir.join("0.profraw");
+    let modified = fs::metadata(&path).unwrap().modified().unwrap();
+    fs::write(&path, b"not a profraw file").unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    let merged = merge().unwrap();
+    assert_eq!(merged.profraw_files, 0);
+    assert_eq!(merged.stale_files.len(), 2);
+
+    // Once modified it is checked and merged.
+    fs::write(&path, b"not a profraw file").unwrap();
+    assert!(merge().is_err());
+}
+
+#[test]
+fn test_rebuild_after_gc_skips_collected_profdata() {
+    let workspace = Workspace::new();
+    let profraw_dir = &workspace.profraw_dir;
+    let function_hash = fixture_record().function_hash;
+
+    workspace.build(function_hash);
+    workspace.add_profraw("0.profraw", "0.profraw");
+    let collected = gc::collect(&workspace.wasmcov_dir, None, &MergeOptions::default()).unwrap();
+    assert_eq!(collected[0].profraw_files, 1);
+    let merged = report::merge_all(&workspace.wasmcov_dir, &MergeOptions::default()).unwrap();
+    assert_eq!(merged[0].profraw_files, 1);
+    assert!(merged[0].stale_files.is_empty());
+
+    // The collected counters do not match the functions of the new build.
+    workspace.build(function_hash + 1);
+    let options = MergeOptions {
+        quarantine_stale: true,
+        ..Default::default()
+    };
+    let merged = report::merge_all(&workspace.wasmcov_dir, &options).unwrap();
+    assert_eq!(merged[0].profraw_files, 0);
+    assert_eq!(
+        merged[0].stale_files,
+        vec![profraw_dir.join("stale/collected.profdata")]
+    );
+    assert!(!profraw_dir.join(gc::COLLECTED_PROFDATA).exists());
+    assert!(profraw_dir.join("stale/collected.fingerprint").exists());
+}
+
+#[test]
+fn test_gc_after_rebuild_starts_new_collected_profdata() {
+    let workspace = Workspace::new();
+    let profraw_dir = &workspace.profraw_dir;
+    let function_hash = fixture_record().function_hash;
+
+    // Collected for the previous build
+    workspace.add_profraw("0.profraw", "old.profraw");
+    report::merge_profraw_files(
+        &[profraw_dir.join("old.profraw")],
+        &profraw_dir.join(gc::COLLECTED_PROFDATA),
+        Vec::new(),
+    )
+    .unwrap();
+    fs::remove_file(profraw_dir.join("old.profraw")).unwrap();
+    fs::write(profraw_dir.join("collected.fingerprint"), "old build").unwrap();
+
+    workspace.build(function_hash);
+    workspace.add_profraw("0.profraw", "0.profraw");
+    let collected = gc::collect(&workspace.wasmcov_dir, None, &MergeOptions::default()).unwrap();
+    assert_eq!(collected[0].profraw_files, 1);
+    assert!(profraw_dir.join("stale/collected.profdata").exists());
+
+    let merged = report::merge_all(&workspace.wasmcov_dir, &MergeOptions::default()).unwrap();
+    assert!(merged[0].stale_files.is_empty());
+    let expected = Profdata::read(&profraw_dir.join("stale/collected.profdata")).unwrap();
+    assert_eq!(
+        Profdata::read(&merged[0].profdata_path).unwrap().to_bytes(),
+        expected.to_bytes()
+    );
+}
+
+#[test]
+fn test_combined_profdata_merges_all_contracts() {
+    let workspace = Workspace::new();
+    let wasmcov_dir = &workspace.wasmcov_dir;
+    // Two targets instead of `contract`
+    fs::remove_dir(&workspace.profraw_dir).unwrap();
+    for (contract, profraw) in [("first", "0.profraw"), ("second", "1.profraw")] {
+        let profraw_dir = wasmcov_dir.profraw().unwrap().join(contract);
+        fs::create_dir(&profraw_dir).unwrap();
+        fs::copy(fixtures().join(profraw), profraw_dir.join(profraw)).unwrap();
+    }
+
+    let merged = report::merge_all(wasmcov_dir, &MergeOptions::default()).unwrap();
+    let profdata_files: Vec<_> = merged
+        .into_iter()
+        .map(|merged| merged.profdata_path)
+        .collect();
+    let profdata_path =
+        report::merge_combined(wasmcov_dir, &profdata_files, &MergeOptions::default()).unwrap();
+    assert_eq!(fs::read(&profdata_path).unwrap(), expected_profdata());
+
+    let object_files = [
+        workspace.dir.path().join("first.o"),
+        workspace.dir.path().join("second.o"),
+    ];
+    assert_eq!(
+        report::object_args(&object_files).unwrap(),
+        vec![format!("-object={}", object_files[1].display())]
+    );
+}
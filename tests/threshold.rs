@@ -0,0 +1,84 @@
+use wasmcov::report::{CoverageSummary, SummaryEntry};
+use wasmcov::threshold::{Threshold, Thresholds};
+
+fn summary(lines: u64, functions: u64, regions: u64) -> CoverageSummary {
+    let entry = |covered| SummaryEntry {
+        count: 100,
+        covered,
+    };
+    CoverageSummary {
+        functions: entry(functions),
+        lines: entry(lines),
+        regions: entry(regions),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_parse_threshold() {
+    assert_eq!(
+        "80".parse::<Threshold>().unwrap(),
+        Threshold {
+            contract: None,
+            percent: 80.0
+        }
+    );
+    assert_eq!(
+        "helper-contract=62.5%".parse::<Threshold>().unwrap(),
+        Threshold {
+            contract: Some("helper-contract".to_string()),
+            percent: 62.5
+        }
+    );
+    assert_eq!(
+        "eighty".parse::<Threshold>().unwrap_err().to_string(),
+        "Invalid coverage threshold \"eighty\""
+    );
+    assert_eq!(
+        "contract=101".parse::<Threshold>().unwrap_err().to_string(),
+        "Coverage threshold 101 is not between 0 and 100"
+    );
+    assert!("-1".parse::<Threshold>().is_err());
+}
+
+#[test]
+fn test_contract_thresholds_take_precedence() {
+    let thresholds = Thresholds {
+        lines: vec!["80".parse().unwrap(), "helper_contract=50".parse().unwrap()],
+        ..Default::default()
+    };
+
+    // Dashes and underscores in contract names are interchangeable.
+    assert!(thresholds
+        .check("helper-contract", &summary(60, 0, 0))
+        .is_empty());
+    assert_eq!(
+        thresholds.check("main", &summary(60, 0, 0)),
+        vec!["main: lines coverage 60.00% is below 80.00%"]
+    );
+    assert!(thresholds.check("main", &summary(80, 0, 0)).is_empty());
+}
+
+#[test]
+fn test_check_reports_failed_metrics() {
+    let thresholds = Thresholds {
+        lines: vec!["50".parse().unwrap()],
+        functions: vec!["70".parse().unwrap(), "70.5".parse().unwrap()],
+        regions: vec!["main=90".parse().unwrap()],
+    };
+
+    assert_eq!(
+        thresholds.check("main", &summary(60, 70, 89)),
+        vec![
+            "main: functions coverage 70.00% is below 70.50%",
+            "main: regions coverage 89.00% is below 90.00%",
+        ]
+    );
+    assert_eq!(
+        thresholds.check("other", &summary(40, 80, 0)),
+        vec!["other: lines coverage 40.00% is below 50.00%"]
+    );
+    assert!(Thresholds::default()
+        .check("main", &summary(0, 0, 0))
+        .is_empty());
+}
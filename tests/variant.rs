@@ -0,0 +1,117 @@
+use std::fs;
+use std::path::Path;
+use wasmcov::build;
+use wasmcov::dir::{BinaryKey, WasmcovDir};
+use wasmcov::profraw;
+use wasmcov::report::{self, MergeOptions};
+
+// An instrumented wasm module with a single function record in its coverage
+// mapping.
+fn instrumented_module(name_hash: u64, function_hash: u64) -> Vec<u8> {
+    let mut covfun = Vec::new();
+    covfun.extend_from_slice(&name_hash.to_le_bytes());
+    covfun.extend_from_slice(&0u32.to_le_bytes());
+    covfun.extend_from_slice(&function_hash.to_le_bytes());
+    covfun.resize(32, 0);
+    let covfun: String = covfun.iter().map(|byte| format!("\\{byte:02x}")).collect();
+    wat::parse_str(format!(
+        r#"(module
+  (@custom "__llvm_covmap" "\00\00\00\00\10\00\00\00covmap")
+  (@custom "__llvm_covfun" "{covfun}")
+  (@custom "__llvm_prf_names" "\01\04main")
+  (func (export "__llvm_profile_init")))"#
+    ))
+    .unwrap()
+}
+
+#[test]
+fn test_binary_key_round_trip() {
+    assert_eq!(wasmcov::binary_name!("test-utils"), "wasmcov@test-utils");
+
+    let key = BinaryKey::parse("my-contract@test-utils");
+    assert_eq!(
+        key,
+        BinaryKey::new("my-contract", Some("test-utils")).unwrap()
+    );
+    assert_eq!(key.to_string(), "my-contract@test-utils");
+    assert_eq!(key.file_stem(), "my_contract@test-utils");
+    assert_eq!(BinaryKey::parse("my-contract").variant, None);
+    assert!(BinaryKey::new("my-contract", Some("a/b")).is_err());
+}
+
+#[test]
+fn test_variants_pair_with_their_object_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let wasmcov_dir = WasmcovDir::new(dir.path()).unwrap();
+    for variant in [None, Some("test-utils")] {
+        let key = BinaryKey::new("my-contract", variant).unwrap();
+        fs::write(wasmcov_dir.object_file_of(&key).unwrap(), []).unwrap();
+    }
+
+    let object_file = |name| {
+        report::find_object_file(&wasmcov_dir, name)
+            .unwrap()
+            .unwrap()
+    };
+    assert!(object_file("my-contract").ends_with("target/my_contract.o"));
+    assert!(object_file("my-contract@test-utils").ends_with("target/my_contract@test-utils.o"));
+    assert!(report::find_object_file(&wasmcov_dir, "my-contract@prod")
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_variants_keep_their_own_builds() {
+    let dir = tempfile::tempdir().unwrap();
+    let wasmcov_dir = WasmcovDir::new(dir.path()).unwrap();
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/profraw/0.profraw");
+    let record = profraw::read_profraw(&fixture).unwrap().records.remove(0);
+
+    // The same crate built without a variant and for the tests, where the
+    // profraw fixture was written by the test build.
+    let deps_dir = wasmcov_dir
+        .target()
+        .unwrap()
+        .join("wasm32-unknown-unknown/release/deps");
+    fs::create_dir_all(&deps_dir).unwrap();
+    for (variant, function_hash) in [
+        (None, record.function_hash + 1),
+        (Some("test-utils"), record.function_hash),
+    ] {
+        let module = instrumented_module(record.name_hash, function_hash);
+        fs::write(deps_dir.join("my_contract.wasm"), module).unwrap();
+        build::process_wasm_files(&wasmcov_dir, variant).unwrap();
+
+        let key = BinaryKey::new("my-contract", variant).unwrap();
+        let profraw_dir = wasmcov_dir.profraw_dir_of(&key).unwrap();
+        fs::create_dir_all(&profraw_dir).unwrap();
+        fs::copy(&fixture, profraw_dir.join("0.profraw")).unwrap();
+    }
+    let target_dir = wasmcov_dir.target().unwrap();
+    for file in [
+        "my_contract.o",
+        "my_contract.wasm",
+        "my_contract@test-utils.o",
+        "my_contract@test-utils.wasm",
+    ] {
+        assert!(target_dir.join(file).exists(), "{file} is missing");
+    }
+
+    // Each variant is checked against its own build.
+    let mut merged: Vec<_> = report::merge_all(&wasmcov_dir, &MergeOptions::default())
+        .unwrap()
+        .into_iter()
+        .map(|merged| {
+            let name = merged.profdata_path.file_name().unwrap().to_owned();
+            (name, merged.profraw_files, merged.stale_files.len())
+        })
+        .collect();
+    merged.sort();
+    assert_eq!(
+        merged,
+        [
+            ("my-contract.profdata".into(), 0, 1),
+            ("my-contract@test-utils.profdata".into(), 1, 0),
+        ]
+    );
+}
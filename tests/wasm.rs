@@ -0,0 +1,137 @@
+use std::fs;
+use wasmcov::object::write_coverage_object;
+use wasmcov::wasm::read_coverage_sections;
+
+// Coverage mapping sections as emitted by rustc for wasm32-unknown-unknown.
+const MODULE: &str = r#"
+(module
+  (@custom "__llvm_covmap" "\00\00\00\00\10\00\00\00covmap")
+  (@custom "__llvm_covfun" "covfun records")
+  (@custom "__llvm_prf_names" "\01\0bdemo::main")
+  (func (export "main")))
+"#;
+
+// Coverage mapping sections placed in named data segments, as done by
+// wasm-ld for wasm32-wasip1.
+const MODULE_WITH_DATA_SEGMENTS: &str = r#"
+(module
+  (memory 1)
+  (data $.rodata (i32.const 0) "other data")
+  (data $__llvm_covmap (i32.const 16) "covmap")
+  (data $__llvm_covfun (i32.const 32) "covfun")
+  (data $__llvm_prf_names (i32.const 48) "names"))
+"#;
+
+// Name, type, offset, size and alignment of every section of an ELF object.
+fn elf_sections(elf: &[u8]) -> Vec<(String, u32, usize, usize, u64)> {
+    let u16_at = |offset: usize| u16::from_le_bytes(elf[offset..offset + 2].try_into().unwrap());
+    let u32_at = |offset: usize| u32::from_le_bytes(elf[offset..offset + 4].try_into().unwrap());
+    let u64_at = |offset: usize| u64::from_le_bytes(elf[offset..offset + 8].try_into().unwrap());
+
+    let headers = u64_at(0x28) as usize;
+    let header_size = u16_at(0x3a) as usize;
+    let count = u16_at(0x3c) as usize;
+    let names_header = headers + u16_at(0x3e) as usize * header_size;
+    let names = &elf[u64_at(names_header + 0x18) as usize..];
+
+    (1..count)
+        .map(|index| {
+            let header = headers + index * header_size;
+            let name = &names[u32_at(header) as usize..];
+            let name = &name[..name.iter().position(|byte| *byte == 0).unwrap()];
+            (
+                String::from_utf8(name.to_vec()).unwrap(),
+                u32_at(header + 4),
+                u64_at(header + 0x18) as usize,
+                u64_at(header + 0x20) as usize,
+                u64_at(header + 0x30),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_read_custom_sections() {
+    let wasm = wat::parse_str(MODULE).unwrap();
+    let sections = read_coverage_sections(&wasm).unwrap();
+    assert_eq!(sections.covmap, b"\0\0\0\0\x10\0\0\0covmap");
+    assert_eq!(sections.covfun, b"covfun records");
+    assert_eq!(sections.prf_names, b"\x01\x0bdemo::main");
+}
+
+#[test]
+fn test_read_named_data_segments() {
+    let wasm = wat::parse_str(MODULE_WITH_DATA_SEGMENTS).unwrap();
+    let sections = read_coverage_sections(&wasm).unwrap();
+    assert_eq!(sections.covmap, b"covmap");
+    assert_eq!(sections.covfun, b"covfun");
+    assert_eq!(sections.prf_names, b"names");
+}
+
+#[test]
+fn test_read_without_coverage_sections() {
+    let wasm = wat::parse_str(r#"(module (@custom "__llvm_covmap" "covmap"))"#).unwrap();
+    let err = read_coverage_sections(&wasm).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Section __llvm_covfun not found in wasm module"
+    );
+
+    let err = read_coverage_sections(b"\x7fELF").err().unwrap();
+    assert_eq!(err.to_string(), "Not a wasm module");
+
+    let err = read_coverage_sections(&wasm[..wasm.len() - 1])
+        .err()
+        .unwrap();
+    assert!(
+        err.to_string().starts_with("Unexpected end of data"),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_write_coverage_object() {
+    let wasm = wat::parse_str(MODULE).unwrap();
+    let sections = read_coverage_sections(&wasm).unwrap();
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("demo.o");
+    write_coverage_object(&sections, &path).unwrap();
+
+    let elf = fs::read(&path).unwrap();
+    // 64-bit little endian relocatable x86_64 object
+    assert_eq!(&elf[..6], b"\x7fELF\x02\x01");
+    assert_eq!(u16::from_le_bytes([elf[0x10], elf[0x11]]), 1);
+    assert_eq!(u16::from_le_bytes([elf[0x12], elf[0x13]]), 62);
+
+    let elf_sections = elf_sections(&elf);
+    let headers: Vec<_> = elf_sections
+        .iter()
+        .map(|(name, kind, _, size, alignment)| (name.as_str(), *kind, *size, *alignment))
+        .collect();
+    assert_eq!(
+        headers,
+        [
+            ("__llvm_prf_names", 1, 12, 8),
+            ("__llvm_covmap", 1, 14, 8),
+            ("__llvm_covfun", 1, 14, 8),
+            (".shstrtab", 3, 56, 1)
+        ]
+    );
+    let contents: Vec<_> = elf_sections
+        .iter()
+        .take(3)
+        .map(|(_, _, offset, size, _)| {
+            // llvm-cov reads the coverage records in place
+            assert_eq!(offset % 8, 0);
+            &elf[*offset..offset + size]
+        })
+        .collect();
+    assert_eq!(
+        contents,
+        [
+            sections.prf_names.as_slice(),
+            sections.covmap.as_slice(),
+            sections.covfun.as_slice()
+        ]
+    );
+}
@@ -0,0 +1,38 @@
+#![cfg(feature = "wasmtime_host")]
+
+use std::fs;
+use wasmcov::dir::WasmcovDir;
+use wasmcov::wasmtime_host;
+use wasmtime::{Engine, Linker, Module, Store};
+
+const GUEST: &str = r#"
+(module
+  (import "env" "sandbox_capture_coverage" (func $capture (param i64 i64 i64 i64)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "demo")
+  (data (i32.const 16) "coverage")
+  (func (export "capture_coverage")
+    (call $capture (i64.const 4) (i64.const 0) (i64.const 8) (i64.const 16))))
+"#;
+
+#[test]
+fn test_capture_coverage_writes_profraw() {
+    let dir = tempfile::tempdir().unwrap();
+    let wasmcov_dir = WasmcovDir::new(dir.path()).unwrap();
+
+    let engine = Engine::default();
+    let module = Module::new(&engine, wat::parse_str(GUEST).unwrap()).unwrap();
+    let mut linker = Linker::new(&engine);
+    wasmtime_host::add_to_linker(&mut linker, &wasmcov_dir).unwrap();
+    let mut store = Store::new(&engine, ());
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+
+    assert!(wasmtime_host::capture_coverage(&mut store, &instance).unwrap());
+
+    let profraw_files: Vec<_> = fs::read_dir(dir.path().join("profraw/demo"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(profraw_files.len(), 1);
+    assert_eq!(fs::read(&profraw_files[0]).unwrap(), b"coverage");
+}